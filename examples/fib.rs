@@ -1,4 +1,4 @@
-#![cfg_attr(feature = "cargo-clippy", allow(print_with_newline))]
+#![allow(clippy::print_with_newline)]
 
 extern crate minrisc;
 
@@ -0,0 +1,38 @@
+extern crate minrisc;
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use minrisc::emu::Machine;
+use minrisc::emu::sparse::{SparseMemory, UnmappedPolicy};
+use minrisc::elf;
+use minrisc::gdb::{self, GdbStub};
+use minrisc::linux::Linux;
+
+// Load a statically linked Linux executable and wait for GDB to connect,
+// as in "target remote :1234".
+fn main() {
+    let mut args = env::args().skip(1);
+    let path = args.next().expect("Usage: ./gdbserver <FILE> [PORT]");
+    let port = args.next().unwrap_or_else(|| "1234".to_owned());
+
+    let mut data = vec![];
+    File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+
+    let mut machine = Machine::new(SparseMemory::new(UnmappedPolicy::Fault));
+    let image = elf::load(&mut machine, &data).unwrap();
+
+    let mut linux = Linux::new(".");
+    linux.set_brk(image.end);
+    linux.init_stack(&mut machine, 0xC000_0000, &image, &[&path], &[]).unwrap();
+
+    // Enough history for reverse-stepi and reverse-continue to be useful.
+    machine.start_recording(1 << 16);
+
+    let mut stub = GdbStub::new();
+    stub.on_syscall(move |m| linux.syscall(m));
+
+    println!("Waiting for GDB on port {}", port);
+    let mut conn = gdb::accept_tcp(("127.0.0.1", port.parse::<u16>().unwrap())).unwrap();
+    stub.serve(&mut machine, &mut conn).unwrap();
+}
@@ -8,6 +8,8 @@ fn main() {
         16
     ).unwrap();
 
-    println!("{:?}",
-        minrisc::decode::decode(num));
+    match minrisc::decode::decode(num) {
+        Ok(inst) => println!("{}", inst),
+        Err(e) => println!("{:?}", e),
+    }
 }
@@ -0,0 +1,47 @@
+extern crate minrisc;
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::process;
+use minrisc::emu::{Machine, StepOutcome};
+use minrisc::emu::bus::Perms;
+use minrisc::emu::sparse::{SparseMemory, UnmappedPolicy};
+use minrisc::elf;
+use minrisc::linux::Linux;
+
+// Run a statically linked Linux executable, with the current directory as
+// its root, and exit with its exit status.
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let path = args.first().expect("Usage: ./run_elf <FILE> [ARGS...]");
+
+    let mut data = vec![];
+    File::open(path).unwrap().read_to_end(&mut data).unwrap();
+
+    // Only the text segment is executable, and only the data segments
+    // (and whatever the program writes outside them) are writable.
+    let mut memory = SparseMemory::new(UnmappedPolicy::Fault);
+    memory.default_perms = Perms::RW;
+
+    let mut machine = Machine::new(memory);
+    let image = elf::load(&mut machine, &data).unwrap();
+
+    let mut linux = Linux::new(".");
+    linux.set_brk(image.end);
+
+    let args: Vec<&str> = args.iter().map(|a| &a[..]).collect();
+    linux.init_stack(&mut machine, 0xC000_0000, &image, &args, &[]).unwrap();
+
+    loop {
+        match machine.step() {
+            Err(e) => panic!("{:?} at pc {:#x}", e, machine.pc),
+            Ok(StepOutcome::Syscall) => {
+                if let Some(status) = linux.syscall(&mut machine) {
+                    process::exit(status);
+                }
+            }
+            _ => (),
+        }
+    }
+}
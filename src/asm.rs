@@ -0,0 +1,1032 @@
+//! A small assembler for GNU-style RISC-V assembly.
+//!
+//! This is meant for building test programs without a cross toolchain, not
+//! for replacing one. There is a single section, and every label is
+//! resolved at assembly time, so the output is a flat image to be loaded
+//! at a fixed base address.
+//!
+//! Supported are the instructions `decode` knows, the usual
+//! pseudo-instructions (`li`, `la`, `mv`, `j`, `call`, `ret`, `beqz`, ...),
+//! labels with forward references, `%hi` / `%lo`, and the directives
+//! `.word`, `.half`, `.byte`, `.ascii`, `.asciz`, `.zero`, `.align`,
+//! `.balign` and `.equ`. Section and symbol-visibility directives are
+//! accepted and ignored.
+
+use std::collections::HashMap;
+
+use decode::{Instruction, Reg, Imm, Csr};
+use decode::formats::{ROperands, AOperands, IOperands, SOperands, BOperands,
+                      UOperands, JOperands, CsrOperands, CsrIOperands, FenceOperands};
+use emu::{csr, Machine};
+use emu::bus::Bus;
+use encode::encode;
+use {Error, Result};
+
+/// The output of `assemble`.
+#[derive(Clone, Debug)]
+pub struct Program {
+    pub base: u32,
+    pub bytes: Vec<u8>,
+    pub symbols: HashMap<String, u32>,
+}
+
+impl Program {
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).cloned()
+    }
+
+    /// Copy the program into memory at its base address.
+    pub fn load<B: Bus>(&self, machine: &mut Machine<B>) -> Result<()> {
+        for (i, &byte) in self.bytes.iter().enumerate() {
+            machine.store8(self.base.wrapping_add(i as u32), byte)?;
+        }
+        Ok(())
+    }
+}
+
+// Errors without a line number yet.
+type Parse<T> = ::std::result::Result<T, String>;
+
+fn fits_signed(val: u32, num_bits: u8) -> bool {
+    let half = 1i64 << (num_bits - 1);
+    let val = val as i32 as i64;
+    -half <= val && val < half
+}
+
+// Split `val` for a LUI/AUIPC + ADDI pair: the upper part already shifted
+// into place, and a sign-extended lower part.
+fn split_hi_lo(val: u32) -> (u32, u32) {
+    let hi = val.wrapping_add(0x800) & !0xFFF;
+    (hi, val.wrapping_sub(hi))
+}
+
+struct ExprParser<'a> {
+    s: &'a [u8],
+    pos: usize,
+    symbols: &'a HashMap<String, u32>,
+    dot: u32,
+}
+
+fn is_ident_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'.' || c == b'$'
+}
+
+impl<'a> ExprParser<'a> {
+    fn skip_space(&mut self) {
+        while self.pos < self.s.len() && self.s[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_space();
+        self.s.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, c: u8) -> Parse<()> {
+        if self.peek() != Some(c) {
+            return Err(format!("expected '{}'", c as char));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn ident(&mut self) -> &'a str {
+        let start = self.pos;
+        while self.pos < self.s.len() && is_ident_char(self.s[self.pos]) {
+            self.pos += 1;
+        }
+        // Only ASCII bytes were consumed.
+        ::std::str::from_utf8(&self.s[start..self.pos]).unwrap()
+    }
+
+    fn number(&mut self) -> Parse<u32> {
+        let text = self.ident();
+        let lower = text.to_lowercase();
+        let parsed = if let Some(hex) = lower.strip_prefix("0x") {
+            u64::from_str_radix(hex, 16)
+        } else if let Some(bin) = lower.strip_prefix("0b") {
+            u64::from_str_radix(bin, 2)
+        } else {
+            lower.parse()
+        };
+
+        match parsed {
+            Ok(n) if n <= u32::MAX as u64 => Ok(n as u32),
+            _ => Err(format!("bad number '{}'", text)),
+        }
+    }
+
+    fn primary(&mut self) -> Parse<u32> {
+        match self.peek() {
+            Some(b'(') => {
+                self.pos += 1;
+                let val = self.expr()?;
+                self.expect(b')')?;
+                Ok(val)
+            }
+
+            Some(b'%') => {
+                self.pos += 1;
+                let func = self.ident();
+                self.expect(b'(')?;
+                let val = self.expr()?;
+                self.expect(b')')?;
+                match func {
+                    "hi" => Ok(split_hi_lo(val).0 >> 12),
+                    "lo" => Ok(split_hi_lo(val).1),
+                    _ => Err(format!("unknown relocation '%{}'", func)),
+                }
+            }
+
+            Some(b'\'') => {
+                let c = self.s.get(self.pos + 1).cloned();
+                if c.is_none() || self.s.get(self.pos + 2) != Some(&b'\'') {
+                    return Err("bad character literal".to_owned());
+                }
+                self.pos += 3;
+                Ok(c.unwrap() as u32)
+            }
+
+            Some(c) if c.is_ascii_digit() => self.number(),
+
+            Some(c) if is_ident_char(c) => {
+                let name = self.ident();
+                if name == "." {
+                    return Ok(self.dot);
+                }
+                match self.symbols.get(name) {
+                    Some(&val) => Ok(val),
+                    None => Err(format!("undefined symbol '{}'", name)),
+                }
+            }
+
+            _ => Err("expected an expression".to_owned()),
+        }
+    }
+
+    fn unary(&mut self) -> Parse<u32> {
+        match self.peek() {
+            Some(b'-') => {
+                self.pos += 1;
+                Ok(self.unary()?.wrapping_neg())
+            }
+            Some(b'~') => {
+                self.pos += 1;
+                Ok(!self.unary()?)
+            }
+            Some(b'+') => {
+                self.pos += 1;
+                self.unary()
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn product(&mut self) -> Parse<u32> {
+        let mut val = self.unary()?;
+        while self.peek() == Some(b'*') {
+            self.pos += 1;
+            val = val.wrapping_mul(self.unary()?);
+        }
+        Ok(val)
+    }
+
+    fn expr(&mut self) -> Parse<u32> {
+        let mut val = self.product()?;
+        loop {
+            match self.peek() {
+                Some(b'+') => {
+                    self.pos += 1;
+                    val = val.wrapping_add(self.product()?);
+                }
+                Some(b'-') => {
+                    self.pos += 1;
+                    val = val.wrapping_sub(self.product()?);
+                }
+                _ => return Ok(val),
+            }
+        }
+    }
+}
+
+fn eval(text: &str, symbols: &HashMap<String, u32>, dot: u32) -> Parse<u32> {
+    let mut p = ExprParser { s: text.as_bytes(), pos: 0, symbols, dot };
+    let val = p.expr()?;
+    if p.peek().is_some() {
+        return Err(format!("junk after expression '{}'", text));
+    }
+    Ok(val)
+}
+
+// Evaluation context for one statement in the second pass.
+struct Ctx<'a> {
+    symbols: &'a HashMap<String, u32>,
+    pc: u32,
+}
+
+impl<'a> Ctx<'a> {
+    fn reg(&self, text: &str) -> Parse<Reg> {
+        Reg::from_name(text).ok_or_else(|| format!("bad register '{}'", text))
+    }
+
+    fn imm(&self, text: &str) -> Parse<u32> {
+        eval(text, self.symbols, self.pc)
+    }
+
+    fn imm12(&self, text: &str) -> Parse<u32> {
+        let val = self.imm(text)?;
+        if !fits_signed(val, 12) {
+            return Err(format!("immediate out of range: {}", text));
+        }
+        Ok(val)
+    }
+
+    // Offset from this instruction to a branch or jump target.
+    fn target(&self, text: &str) -> Parse<u32> {
+        Ok(self.imm(text)?.wrapping_sub(self.pc))
+    }
+
+    // "offset(reg)" or "(reg)".
+    fn mem(&self, text: &str) -> Parse<(Reg, u32)> {
+        let bad = || format!("bad memory operand '{}'", text);
+        let open = text.rfind('(').ok_or_else(bad)?;
+        if !text.ends_with(')') {
+            return Err(bad());
+        }
+        let base = self.reg(text[open + 1..text.len() - 1].trim())?;
+        let offset = text[..open].trim();
+        let offset = if offset.is_empty() { 0 } else { self.imm12(offset)? };
+        Ok((base, offset))
+    }
+
+    fn csr(&self, text: &str) -> Parse<Csr> {
+        if let Some(csr) = csr::from_name(text) {
+            return Ok(csr);
+        }
+        match self.imm(text)? {
+            n if n < 1 << 12 => Ok(n as Csr),
+            _ => Err(format!("bad CSR '{}'", text)),
+        }
+    }
+
+    fn uimm5(&self, text: &str) -> Parse<Imm> {
+        match self.imm(text)? {
+            n if n < 32 => Ok(n),
+            _ => Err(format!("immediate out of range: {}", text)),
+        }
+    }
+}
+
+// A FENCE predecessor or successor set, like "rw", in IORW order.
+fn fence_set(text: &str) -> Parse<u8> {
+    let mut bits = 0;
+    let mut rest = text;
+    for (i, c) in "iorw".chars().enumerate() {
+        if let Some(r) = rest.strip_prefix(c) {
+            bits |= 0b1000 >> i;
+            rest = r;
+        }
+    }
+    if bits == 0 || !rest.is_empty() {
+        return Err(format!("bad fence set '{}'", text));
+    }
+    Ok(bits)
+}
+
+fn check_count(ops: &[&str], n: usize) -> Parse<()> {
+    if ops.len() != n {
+        return Err(format!("expected {} operands, found {}", n, ops.len()));
+    }
+    Ok(())
+}
+
+type RCtor = fn(ROperands) -> Instruction;
+type ICtor = fn(IOperands) -> Instruction;
+type BCtor = fn(BOperands) -> Instruction;
+type CsrCtor = fn(CsrOperands) -> Instruction;
+type CsrICtor = fn(CsrIOperands) -> Instruction;
+
+fn r_op(mnemonic: &str) -> Option<RCtor> {
+    Some(match mnemonic {
+        "add" => Instruction::ADD, "sub" => Instruction::SUB,
+        "sll" => Instruction::SLL, "slt" => Instruction::SLT,
+        "sltu" => Instruction::SLTU, "xor" => Instruction::XOR,
+        "srl" => Instruction::SRL, "sra" => Instruction::SRA,
+        "or" => Instruction::OR, "and" => Instruction::AND,
+        "mul" => Instruction::MUL, "mulh" => Instruction::MULH,
+        "mulhsu" => Instruction::MULHSU, "mulhu" => Instruction::MULHU,
+        "div" => Instruction::DIV, "divu" => Instruction::DIVU,
+        "rem" => Instruction::REM, "remu" => Instruction::REMU,
+        _ => return None,
+    })
+}
+
+fn i_op(mnemonic: &str) -> Option<ICtor> {
+    Some(match mnemonic {
+        "addi" => Instruction::ADDI, "slti" => Instruction::SLTI,
+        "sltiu" => Instruction::SLTIU, "xori" => Instruction::XORI,
+        "ori" => Instruction::ORI, "andi" => Instruction::ANDI,
+        _ => return None,
+    })
+}
+
+fn shift_op(mnemonic: &str) -> Option<(ICtor, u32)> {
+    Some(match mnemonic {
+        "slli" => (Instruction::SLLI as ICtor, 0),
+        "srli" => (Instruction::SRLI as ICtor, 0),
+        "srai" => (Instruction::SRAI as ICtor, 0b0100000_00000),
+        _ => return None,
+    })
+}
+
+fn load_op(mnemonic: &str) -> Option<ICtor> {
+    Some(match mnemonic {
+        "lb" => Instruction::LB, "lh" => Instruction::LH, "lw" => Instruction::LW,
+        "lbu" => Instruction::LBU, "lhu" => Instruction::LHU,
+        _ => return None,
+    })
+}
+
+fn store_op(mnemonic: &str) -> Option<fn(SOperands) -> Instruction> {
+    Some(match mnemonic {
+        "sb" => Instruction::SB, "sh" => Instruction::SH, "sw" => Instruction::SW,
+        _ => return None,
+    })
+}
+
+fn branch_op(mnemonic: &str) -> Option<BCtor> {
+    Some(match mnemonic {
+        "beq" => Instruction::BEQ, "bne" => Instruction::BNE,
+        "blt" => Instruction::BLT, "bge" => Instruction::BGE,
+        "bltu" => Instruction::BLTU, "bgeu" => Instruction::BGEU,
+        _ => return None,
+    })
+}
+
+// Branches against zero, and branches with swapped operands.
+fn branch_alias(mnemonic: &str) -> Option<(BCtor, bool, bool)> {
+    // (instruction, compare with zero, swap operands)
+    Some(match mnemonic {
+        "beqz" => (Instruction::BEQ as BCtor, true, false),
+        "bnez" => (Instruction::BNE as BCtor, true, false),
+        "bltz" => (Instruction::BLT as BCtor, true, false),
+        "bgez" => (Instruction::BGE as BCtor, true, false),
+        "blez" => (Instruction::BGE as BCtor, true, true),
+        "bgtz" => (Instruction::BLT as BCtor, true, true),
+        "bgt" => (Instruction::BLT as BCtor, false, true),
+        "ble" => (Instruction::BGE as BCtor, false, true),
+        "bgtu" => (Instruction::BLTU as BCtor, false, true),
+        "bleu" => (Instruction::BGEU as BCtor, false, true),
+        _ => return None,
+    })
+}
+
+fn amo_op(base: &str) -> Option<fn(AOperands) -> Instruction> {
+    Some(match base {
+        "lr" => Instruction::LR, "sc" => Instruction::SC,
+        "amoswap" => Instruction::AMOSWAP, "amoadd" => Instruction::AMOADD,
+        "amoxor" => Instruction::AMOXOR, "amoand" => Instruction::AMOAND,
+        "amoor" => Instruction::AMOOR, "amomin" => Instruction::AMOMIN,
+        "amomax" => Instruction::AMOMAX, "amominu" => Instruction::AMOMINU,
+        "amomaxu" => Instruction::AMOMAXU,
+        _ => return None,
+    })
+}
+
+fn csr_op(mnemonic: &str) -> Option<(CsrCtor, CsrICtor)> {
+    Some(match mnemonic {
+        "csrrw" | "csrw" => (Instruction::CSRRW as CsrCtor, Instruction::CSRRWI as CsrICtor),
+        "csrrs" | "csrs" => (Instruction::CSRRS as CsrCtor, Instruction::CSRRSI as CsrICtor),
+        "csrrc" | "csrc" => (Instruction::CSRRC as CsrCtor, Instruction::CSRRCI as CsrICtor),
+        _ => return None,
+    })
+}
+
+fn addi(rd: Reg, rs1: Reg, imm: Imm) -> Instruction {
+    Instruction::ADDI(IOperands { rd, rs1, imm })
+}
+
+fn auipc_pair(ctx: &Ctx, rd: Reg, target: &str) -> Parse<(Instruction, Imm)> {
+    let (hi, lo) = split_hi_lo(ctx.target(target)?);
+    Ok((Instruction::AUIPC(UOperands { rd, imm: hi }), lo))
+}
+
+// Size in bytes of an instruction statement at `addr`. Only `li` depends
+// on its operand, and then only when the value is known in the first pass.
+fn inst_size(mnemonic: &str, ops: &[&str], known: &HashMap<String, u32>, addr: u32) -> usize {
+    match mnemonic {
+        "la" | "lla" | "call" | "tail" => 8,
+        "li" if ops.len() == 2 => {
+            match eval(ops[1], known, addr) {
+                Ok(val) if fits_signed(val, 12) || split_hi_lo(val).1 == 0 => 4,
+                _ => 8,
+            }
+        }
+        _ => 4,
+    }
+}
+
+// Translate one (pseudo-)instruction. `size` is what the first pass
+// reserved for it.
+fn build(mnemonic: &str, ops: &[&str], size: usize, ctx: &Ctx) -> Parse<Vec<Instruction>> {
+    let zero = Reg::zero();
+    let ra = Reg::ra();
+
+    if let Some(f) = r_op(mnemonic) {
+        check_count(ops, 3)?;
+        return Ok(vec![f(ROperands { rd: ctx.reg(ops[0])?, rs1: ctx.reg(ops[1])?,
+                                     rs2: ctx.reg(ops[2])? })]);
+    }
+
+    if let Some(f) = i_op(mnemonic) {
+        check_count(ops, 3)?;
+        return Ok(vec![f(IOperands { rd: ctx.reg(ops[0])?, rs1: ctx.reg(ops[1])?,
+                                     imm: ctx.imm12(ops[2])? })]);
+    }
+
+    if let Some((f, funct7)) = shift_op(mnemonic) {
+        check_count(ops, 3)?;
+        let shamt = ctx.uimm5(ops[2])?;
+        return Ok(vec![f(IOperands { rd: ctx.reg(ops[0])?, rs1: ctx.reg(ops[1])?,
+                                     imm: shamt | funct7 })]);
+    }
+
+    if let Some(f) = load_op(mnemonic) {
+        check_count(ops, 2)?;
+        let (rs1, imm) = ctx.mem(ops[1])?;
+        return Ok(vec![f(IOperands { rd: ctx.reg(ops[0])?, rs1, imm })]);
+    }
+
+    if let Some(f) = store_op(mnemonic) {
+        check_count(ops, 2)?;
+        let (rs1, imm) = ctx.mem(ops[1])?;
+        return Ok(vec![f(SOperands { rs1, rs2: ctx.reg(ops[0])?, imm })]);
+    }
+
+    if let Some(f) = branch_op(mnemonic) {
+        check_count(ops, 3)?;
+        return Ok(vec![f(BOperands { rs1: ctx.reg(ops[0])?, rs2: ctx.reg(ops[1])?,
+                                     imm: ctx.target(ops[2])? })]);
+    }
+
+    if let Some((f, with_zero, swap)) = branch_alias(mnemonic) {
+        let (a, b, target) = if with_zero {
+            check_count(ops, 2)?;
+            (ctx.reg(ops[0])?, zero, ops[1])
+        } else {
+            check_count(ops, 3)?;
+            (ctx.reg(ops[0])?, ctx.reg(ops[1])?, ops[2])
+        };
+        let (rs1, rs2) = if swap { (b, a) } else { (a, b) };
+        return Ok(vec![f(BOperands { rs1, rs2, imm: ctx.target(target)? })]);
+    }
+
+    if mnemonic.starts_with("lr.") || mnemonic.starts_with("sc.")
+        || mnemonic.starts_with("amo")
+    {
+        let mut parts = mnemonic.split('.');
+        let f = parts.next().and_then(amo_op)
+            .ok_or_else(|| format!("unknown instruction '{}'", mnemonic))?;
+        if parts.next() != Some("w") {
+            return Err(format!("only .w atomics exist on RV32: '{}'", mnemonic));
+        }
+        let (aq, rl) = match parts.next() {
+            None => (false, false),
+            Some("aq") => (true, false),
+            Some("rl") => (false, true),
+            Some("aqrl") => (true, true),
+            Some(other) => return Err(format!("bad ordering '{}'", other)),
+        };
+
+        let (rd, rs2, addr) = if mnemonic.starts_with("lr.") {
+            check_count(ops, 2)?;
+            (ctx.reg(ops[0])?, zero, ops[1])
+        } else {
+            check_count(ops, 3)?;
+            (ctx.reg(ops[0])?, ctx.reg(ops[1])?, ops[2])
+        };
+        let (rs1, offset) = ctx.mem(addr)?;
+        if offset != 0 {
+            return Err("atomics take no offset".to_owned());
+        }
+        return Ok(vec![f(AOperands { rd, rs1, rs2, aq, rl })]);
+    }
+
+    if let Some((reg_form, imm_form)) = csr_op(mnemonic.trim_end_matches('i')) {
+        let imm = mnemonic.ends_with('i');
+        // csrw etc. are the csrrw forms with rd = zero.
+        let (rd, ops) = if mnemonic.starts_with("csrr") {
+            check_count(ops, 3)?;
+            (ctx.reg(ops[0])?, &ops[1..])
+        } else {
+            check_count(ops, 2)?;
+            (zero, ops)
+        };
+        let csr = ctx.csr(ops[0])?;
+        return Ok(vec![if imm {
+            imm_form(CsrIOperands { rd, uimm: ctx.uimm5(ops[1])?, csr })
+        } else {
+            reg_form(CsrOperands { rd, rs1: ctx.reg(ops[1])?, csr })
+        }]);
+    }
+
+    let counter = match mnemonic {
+        "rdcycle" => Some(csr::CYCLE),
+        "rdcycleh" => Some(csr::CYCLEH),
+        "rdinstret" => Some(csr::INSTRET),
+        "rdinstreth" => Some(csr::INSTRETH),
+        "csrr" => {
+            check_count(ops, 2)?;
+            Some(ctx.csr(ops[1])?)
+        }
+        _ => None,
+    };
+    if let Some(csr) = counter {
+        if mnemonic != "csrr" {
+            check_count(ops, 1)?;
+        }
+        return Ok(vec![Instruction::CSRRS(CsrOperands { rd: ctx.reg(ops[0])?, rs1: zero, csr })]);
+    }
+
+    let insts = match mnemonic {
+        "lui" | "auipc" => {
+            check_count(ops, 2)?;
+            let val = ctx.imm(ops[1])?;
+            if val >= 1 << 20 {
+                return Err(format!("immediate out of range: {}", ops[1]));
+            }
+            let op = UOperands { rd: ctx.reg(ops[0])?, imm: val << 12 };
+            vec![if mnemonic == "lui" { Instruction::LUI(op) } else { Instruction::AUIPC(op) }]
+        }
+
+        "jal" => {
+            let (rd, target) = match ops.len() {
+                1 => (ra, ops[0]),
+                _ => {
+                    check_count(ops, 2)?;
+                    (ctx.reg(ops[0])?, ops[1])
+                }
+            };
+            vec![Instruction::JAL(JOperands { rd, imm: ctx.target(target)? })]
+        }
+
+        "jalr" => {
+            let (rd, rs1, imm) = match ops.len() {
+                1 => (ra, ctx.reg(ops[0])?, 0),
+                2 => {
+                    let (rs1, imm) = ctx.mem(ops[1])?;
+                    (ctx.reg(ops[0])?, rs1, imm)
+                }
+                _ => {
+                    check_count(ops, 3)?;
+                    (ctx.reg(ops[0])?, ctx.reg(ops[1])?, ctx.imm12(ops[2])?)
+                }
+            };
+            vec![Instruction::JALR(IOperands { rd, rs1, imm })]
+        }
+
+        "ecall" => vec![Instruction::ECALL],
+        "ebreak" => vec![Instruction::EBREAK],
+        "mret" => vec![Instruction::MRET],
+        "fence.i" => vec![Instruction::FENCEI],
+
+        "fence" => {
+            let (pred, succ) = match ops.len() {
+                0 => (0b1111, 0b1111),
+                _ => {
+                    check_count(ops, 2)?;
+                    (fence_set(ops[0])?, fence_set(ops[1])?)
+                }
+            };
+            vec![Instruction::FENCE(FenceOperands { pred, succ })]
+        }
+        "nop" => vec![addi(zero, zero, 0)],
+        "ret" => vec![Instruction::JALR(IOperands { rd: zero, rs1: ra, imm: 0 })],
+
+        "j" => {
+            check_count(ops, 1)?;
+            vec![Instruction::JAL(JOperands { rd: zero, imm: ctx.target(ops[0])? })]
+        }
+
+        "jr" => {
+            check_count(ops, 1)?;
+            vec![Instruction::JALR(IOperands { rd: zero, rs1: ctx.reg(ops[0])?, imm: 0 })]
+        }
+
+        "mv" => {
+            check_count(ops, 2)?;
+            vec![addi(ctx.reg(ops[0])?, ctx.reg(ops[1])?, 0)]
+        }
+
+        "not" => {
+            check_count(ops, 2)?;
+            vec![Instruction::XORI(IOperands { rd: ctx.reg(ops[0])?, rs1: ctx.reg(ops[1])?,
+                                               imm: !0 })]
+        }
+
+        "neg" | "snez" | "sltz" | "sgtz" | "seqz" => {
+            check_count(ops, 2)?;
+            let (rd, rs) = (ctx.reg(ops[0])?, ctx.reg(ops[1])?);
+            match mnemonic {
+                "neg" => vec![Instruction::SUB(ROperands { rd, rs1: zero, rs2: rs })],
+                "snez" => vec![Instruction::SLTU(ROperands { rd, rs1: zero, rs2: rs })],
+                "sltz" => vec![Instruction::SLT(ROperands { rd, rs1: rs, rs2: zero })],
+                "sgtz" => vec![Instruction::SLT(ROperands { rd, rs1: zero, rs2: rs })],
+                _ => vec![Instruction::SLTIU(IOperands { rd, rs1: rs, imm: 1 })],
+            }
+        }
+
+        "li" => {
+            check_count(ops, 2)?;
+            let rd = ctx.reg(ops[0])?;
+            let val = ctx.imm(ops[1])?;
+            let (hi, lo) = split_hi_lo(val);
+            if size == 8 {
+                vec![Instruction::LUI(UOperands { rd, imm: hi }), addi(rd, rd, lo)]
+            } else if fits_signed(val, 12) {
+                vec![addi(rd, zero, val)]
+            } else if lo == 0 {
+                vec![Instruction::LUI(UOperands { rd, imm: hi })]
+            } else {
+                // A symbol redefined after this line.
+                return Err(format!("value of '{}' changed after it was laid out", ops[1]));
+            }
+        }
+
+        "la" | "lla" => {
+            check_count(ops, 2)?;
+            let rd = ctx.reg(ops[0])?;
+            let (auipc, lo) = auipc_pair(ctx, rd, ops[1])?;
+            vec![auipc, addi(rd, rd, lo)]
+        }
+
+        "call" | "tail" => {
+            check_count(ops, 1)?;
+            let (rd, link) = if mnemonic == "call" { (ra, ra) } else { (Reg::t1(), zero) };
+            let (auipc, lo) = auipc_pair(ctx, rd, ops[0])?;
+            vec![auipc, Instruction::JALR(IOperands { rd: link, rs1: rd, imm: lo })]
+        }
+
+        _ => return Err(format!("unknown instruction '{}'", mnemonic)),
+    };
+
+    Ok(insts)
+}
+
+// Split operands on commas, except inside quotes or parentheses.
+fn split_operands(text: &str) -> Vec<&str> {
+    let mut ops = vec![];
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                ops.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    let last = text[start..].trim();
+    if !last.is_empty() || !ops.is_empty() {
+        ops.push(last);
+    }
+    ops
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn parse_string(text: &str) -> Parse<Vec<u8>> {
+    let bad = || format!("bad string {}", text);
+    if text.len() < 2 || !text.starts_with('"') || !text.ends_with('"') {
+        return Err(bad());
+    }
+
+    let mut out = vec![];
+    let mut chars = text[1..text.len() - 1].bytes();
+    while let Some(c) = chars.next() {
+        if c != b'\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next().ok_or_else(bad)? {
+            b'n' => b'\n',
+            b't' => b'\t',
+            b'r' => b'\r',
+            b'0' => 0,
+            b'x' => {
+                let hex: Vec<u8> = chars.by_ref().take(2).collect();
+                let hex = String::from_utf8(hex).map_err(|_| bad())?;
+                u8::from_str_radix(&hex, 16).map_err(|_| bad())?
+            }
+            c => c,
+        });
+    }
+    Ok(out)
+}
+
+enum Item {
+    Inst(String, Vec<String>),
+    Data(usize, Vec<String>),
+    Bytes(Vec<u8>),
+
+    // As many zero bytes as the statement's size.
+    Fill,
+}
+
+struct Stmt {
+    line: usize,
+    addr: u32,
+    size: usize,
+    item: Item,
+}
+
+// Collect labels and statements, and lay them out in memory.
+fn first_pass(source: &str, base: u32, symbols: &mut HashMap<String, u32>)
+    -> Result<Vec<Stmt>>
+{
+    let mut stmts = vec![];
+    let mut addr = base;
+
+    for (i, line) in source.lines().enumerate() {
+        let line_no = i + 1;
+        let at_line = |msg: String| Error::Assembly(line_no, msg);
+        let mut rest = strip_comment(line).trim();
+
+        // Labels
+        while let Some(colon) = rest.find(':') {
+            let label = rest[..colon].trim();
+            if label.is_empty() || !label.bytes().all(is_ident_char) {
+                break;
+            }
+            if symbols.insert(label.to_owned(), addr).is_some() {
+                return Err(at_line(format!("duplicate symbol '{}'", label)));
+            }
+            rest = rest[colon + 1..].trim();
+        }
+
+        if rest.is_empty() {
+            continue;
+        }
+
+        let (head, tail) = match rest.find(char::is_whitespace) {
+            Some(n) => (&rest[..n], rest[n..].trim()),
+            None => (rest, ""),
+        };
+        let head = head.to_lowercase();
+        let ops = split_operands(tail);
+        let owned = || ops.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let (size, item) = match &head[..] {
+            ".word" | ".long" | ".4byte" => (4 * ops.len(), Item::Data(4, owned())),
+            ".half" | ".short" | ".2byte" => (2 * ops.len(), Item::Data(2, owned())),
+            ".byte" => (ops.len(), Item::Data(1, owned())),
+
+            ".ascii" | ".asciz" | ".string" => {
+                let mut bytes = vec![];
+                for op in &ops {
+                    bytes.extend(parse_string(op).map_err(&at_line)?);
+                    if head != ".ascii" {
+                        bytes.push(0);
+                    }
+                }
+                (bytes.len(), Item::Bytes(bytes))
+            }
+
+            ".zero" | ".space" | ".skip" => {
+                check_count(&ops, 1).map_err(&at_line)?;
+                let n = eval(ops[0], symbols, addr).map_err(&at_line)?;
+                // The fill may reach the top of the address space, but not
+                // wrap around it.
+                if (addr as u64) + (n as u64) > 1 << 32 {
+                    return Err(at_line(format!("bad size {}", n)));
+                }
+                (n as usize, Item::Fill)
+            }
+
+            ".align" | ".p2align" | ".balign" => {
+                let n = eval(ops.first().cloned().unwrap_or(""), symbols, addr)
+                    .map_err(&at_line)?;
+                let align = if head == ".balign" { n } else { 1u32.checked_shl(n).unwrap_or(0) };
+                if align == 0 || !align.is_power_of_two() {
+                    return Err(at_line(format!("bad alignment {}", n)));
+                }
+                let pad = addr.wrapping_neg() & (align - 1);
+                (pad as usize, Item::Fill)
+            }
+
+            ".equ" | ".set" => {
+                check_count(&ops, 2).map_err(&at_line)?;
+                let val = eval(ops[1], symbols, addr).map_err(&at_line)?;
+                symbols.insert(ops[0].to_owned(), val);
+                continue;
+            }
+
+            ".text" | ".data" | ".bss" | ".rodata" | ".section" | ".globl" | ".global"
+                | ".local" | ".type" | ".size" | ".option" | ".file" | ".ident" => continue,
+
+            _ if head.starts_with('.') => {
+                return Err(at_line(format!("unknown directive '{}'", head)));
+            }
+
+            _ => (inst_size(&head, &ops, symbols, addr), Item::Inst(head.clone(), owned())),
+        };
+
+        stmts.push(Stmt { line: line_no, addr, size, item });
+        addr = addr.wrapping_add(size as u32);
+    }
+
+    Ok(stmts)
+}
+
+fn emit(stmt: &Stmt, symbols: &HashMap<String, u32>, out: &mut Vec<u8>) -> Parse<()> {
+    let ctx = Ctx { symbols, pc: stmt.addr };
+    match stmt.item {
+        Item::Inst(ref mnemonic, ref ops) => {
+            let ops: Vec<&str> = ops.iter().map(|s| &s[..]).collect();
+            let insts = build(mnemonic, &ops, stmt.size, &ctx)?;
+            for inst in insts {
+                let word = encode(&inst).map_err(|e| format!("can't encode: {:?}", e))?;
+                out.extend_from_slice(&[word as u8, (word >> 8) as u8,
+                                        (word >> 16) as u8, (word >> 24) as u8]);
+            }
+        }
+
+        Item::Data(width, ref exprs) => {
+            for (i, expr) in exprs.iter().enumerate() {
+                let pc = stmt.addr.wrapping_add((i * width) as u32);
+                let val = eval(expr, symbols, pc)?;
+                for b in 0..width {
+                    out.push((val >> (8 * b)) as u8);
+                }
+            }
+        }
+
+        Item::Bytes(ref bytes) => out.extend_from_slice(bytes),
+
+        Item::Fill => out.resize(out.len() + stmt.size, 0),
+    }
+    Ok(())
+}
+
+/// Assemble `source` into an image to be loaded at `base`.
+///
+/// Errors are reported as `Error::Assembly`, with the line number and a
+/// description.
+pub fn assemble(source: &str, base: u32) -> Result<Program> {
+    let mut symbols = HashMap::new();
+    let stmts = first_pass(source, base, &mut symbols)?;
+
+    let mut bytes = vec![];
+    for stmt in &stmts {
+        emit(stmt, &symbols, &mut bytes).map_err(|msg| Error::Assembly(stmt.line, msg))?;
+        debug_assert_eq!(bytes.len() as u32, stmt.addr.wrapping_sub(base) + stmt.size as u32);
+    }
+
+    Ok(Program { base, bytes, symbols })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::assemble;
+    use decode::Reg;
+    use emu::{Machine, StepOutcome};
+    use Error;
+
+    fn words(bytes: &[u8]) -> Vec<u32> {
+        bytes.chunks(4).map(|b| {
+            b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+        }).collect()
+    }
+
+    #[test]
+    fn test_fib() {
+        // The program from examples/fib.rs
+        let program = assemble("
+            fib:    beqz    a0, .L4
+                    addi    a5, a0, -1
+                    beqz    a5, .L5
+                    li      a4, 1
+                    li      a3, 0
+            .L3:    add     a0, a3, a4
+                    addi    a5, a5, -1
+                    mv      a3, a4
+                    mv      a4, a0
+                    bnez    a5, .L3
+                    ecall
+            .L4:    li      a0, 0
+                    ecall
+            .L5:    li      a0, 1   # fib(1)
+                    ecall
+        ", 0).unwrap();
+
+        assert_eq!(vec![
+            0x02050663, 0xfff50793, 0x02078663, 0x00100713, 0x00000693,
+            0x00e68533, 0xfff78793, 0x00070693, 0x00050713, 0xfe0798e3,
+            0x00000073, 0x00000513, 0x00000073, 0x00100513, 0x00000073,
+        ], words(&program.bytes));
+        assert_eq!(Some(0x14), program.symbol(".L3"));
+    }
+
+    #[test]
+    fn test_pseudo_and_relocations() {
+        let program = assemble("
+            .equ    UART, 0x10000000
+                    li      a0, 0x12345678
+                    li      a1, -2048
+                    lui     a2, %hi(UART)
+                    sw      a0, %lo(UART)(a2)
+                    la      a3, msg
+                    call    done
+            msg:    .asciz  \"hi\\n\"
+                    .align  2
+            done:   ret
+        ", 0x1000).unwrap();
+
+        assert_eq!(vec![
+            0x12345537,  // lui     a0,0x12345
+            0x67850513,  // addi    a0,a0,1656
+            0x80000593,  // li      a1,-2048
+            0x10000637,  // lui     a2,0x10000
+            0x00a62023,  // sw      a0,0(a2)
+            0x00000697,  // auipc   a3,0x0
+            0x01068693,  // addi    a3,a3,16
+            0x00000097,  // auipc   ra,0x0
+            0x00c080e7,  // jalr    12(ra)
+            0x000a6968,  // "hi\n\0"
+            0x00008067,  // ret
+        ], words(&program.bytes));
+    }
+
+    #[test]
+    fn test_li_size() {
+        let program = assemble("
+                    nop
+                    li      a0, . + 0x1000
+        ", 0).unwrap();
+        assert_eq!(vec![
+            0x00000013,  // nop
+            0x00001537,  // lui     a0,0x1
+            0x00450513,  // addi    a0,a0,4
+        ], words(&program.bytes));
+
+        match assemble(".equ X, 0x1000\n li a0, X\n .equ X, 0x1004\n", 0) {
+            Err(Error::Assembly(2, _)) => (),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let program = assemble("
+                    la      t0, data
+                    lw      a0, 4(t0)
+                    lbu     a1, 8(t0)
+                    add     a0, a0, a1
+                    ecall
+            data:   .word   1, 41
+                    .byte   'x' - 'w'
+        ", 0).unwrap();
+
+        let mut m = Machine::with_memory(256);
+        program.load(&mut m).unwrap();
+        while m.step().unwrap() != StepOutcome::Syscall {}
+        assert_eq!(42, m.get_reg(Reg::a0()));
+    }
+
+    #[test]
+    fn test_errors() {
+        match assemble("nop\n  bogus a0\n", 0) {
+            Err(Error::Assembly(2, _)) => (),
+            r => panic!("{:?}", r),
+        }
+        match assemble("j nowhere", 0) {
+            Err(Error::Assembly(1, _)) => (),
+            r => panic!("{:?}", r),
+        }
+        match assemble("addi a0, a0, 4096", 0) {
+            Err(Error::Assembly(1, _)) => (),
+            r => panic!("{:?}", r),
+        }
+        match assemble("nop\n  .zero -1\n", 0) {
+            Err(Error::Assembly(2, _)) => (),
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(vec![0; 4], assemble(".zero 4", 0xFFFF_FFFC).unwrap().bytes);
+    }
+}
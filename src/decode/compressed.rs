@@ -0,0 +1,272 @@
+//! The "C" extension: 16-bit encodings of common instructions.
+//!
+//! Every compressed instruction is equivalent to some 32-bit instruction,
+//! so instead of new variants we expand straight into `Instruction`.
+
+use {Error, Result};
+use super::{Reg, Imm, Instruction};
+use super::formats::{sign_extend, ROperands, IOperands, SOperands,
+                     BOperands, UOperands, JOperands};
+
+// Full 5-bit register field.
+fn reg(bits: u16) -> Reg {
+    Reg((bits & 0b11111) as u8)
+}
+
+// 3-bit register field, which only names x8 through x15.
+fn creg(bits: u16) -> Reg {
+    Reg(8 + (bits & 0b111) as u8)
+}
+
+fn funct3(bits: u16) -> u16 {
+    bits >> 13
+}
+
+// The 6-bit immediate used by C.ADDI, C.LI, C.ANDI and the shifts.
+fn imm6(bits: u16) -> Imm {
+    sign_extend((((bits >> 7) & 0b100000) | ((bits >> 2) & 0b11111)) as u32, 6)
+}
+
+fn shamt(bits: u16) -> Result<Imm> {
+    // shamt[5] must be zero on RV32.
+    if bits & (1 << 12) != 0 {
+        return Err(Error::BadFunct);
+    }
+    Ok(((bits >> 2) & 0b11111) as Imm)
+}
+
+fn j_imm(bits: u16) -> Imm {
+    let b = bits as u32;
+    sign_extend(((b >> 1) & 0b1000_0000_0000)
+              | ((b >> 7) & 0b0000_0001_0000)
+              | ((b >> 1) & 0b0011_0000_0000)
+              | ((b << 2) & 0b0100_0000_0000)
+              | ((b >> 1) & 0b0000_0100_0000)
+              | ((b << 1) & 0b0000_1000_0000)
+              | ((b >> 2) & 0b0000_0000_1110)
+              | ((b << 3) & 0b0000_0010_0000),
+              12)
+}
+
+fn b_imm(bits: u16) -> Imm {
+    let b = bits as u32;
+    sign_extend(((b >> 4) & 0b1_0000_0000)
+              | ((b >> 7) & 0b0_0001_1000)
+              | ((b << 1) & 0b0_1100_0000)
+              | ((b >> 2) & 0b0_0000_0110)
+              | ((b << 3) & 0b0_0010_0000),
+              9)
+}
+
+// Offset for C.LW and C.SW.
+fn lw_imm(bits: u16) -> Imm {
+    (((bits >> 7) & 0b0111000)
+   | ((bits >> 4) & 0b0000100)
+   | ((bits << 1) & 0b1000000)) as Imm
+}
+
+fn i(rd: Reg, rs1: Reg, imm: Imm) -> IOperands {
+    IOperands { rd, rs1, imm }
+}
+
+fn r(rd: Reg, rs1: Reg, rs2: Reg) -> ROperands {
+    ROperands { rd, rs1, rs2 }
+}
+
+/// Decode a 16-bit instruction into its 32-bit equivalent.
+pub fn decode(bits: u16) -> Result<Instruction> {
+    let x0 = Reg::x0();
+    let ra = Reg::ra();
+    let sp = Reg::sp();
+
+    match (bits & 0b11, funct3(bits)) {
+        // C.ADDI4SPN
+        (0b00, 0b000) => {
+            let imm = ((bits >> 7) & 0b0000110000)
+                    | ((bits >> 1) & 0b1111000000)
+                    | ((bits >> 4) & 0b0000000100)
+                    | ((bits >> 2) & 0b0000001000);
+            if imm == 0 {
+                return Err(Error::BadFunct);
+            }
+            Ok(Instruction::ADDI(i(creg(bits >> 2), sp, imm as Imm)))
+        }
+
+        // C.LW
+        (0b00, 0b010)
+            => Ok(Instruction::LW(i(creg(bits >> 2), creg(bits >> 7), lw_imm(bits)))),
+
+        // C.SW
+        (0b00, 0b110) => Ok(Instruction::SW(SOperands {
+            rs1: creg(bits >> 7),
+            rs2: creg(bits >> 2),
+            imm: lw_imm(bits),
+        })),
+
+        // C.ADDI, C.NOP
+        (0b01, 0b000) => {
+            let rd = reg(bits >> 7);
+            Ok(Instruction::ADDI(i(rd, rd, imm6(bits))))
+        }
+
+        // C.JAL
+        (0b01, 0b001) => Ok(Instruction::JAL(JOperands { rd: ra, imm: j_imm(bits) })),
+
+        // C.LI
+        (0b01, 0b010) => Ok(Instruction::ADDI(i(reg(bits >> 7), x0, imm6(bits)))),
+
+        // C.ADDI16SP
+        (0b01, 0b011) if reg(bits >> 7) == sp => {
+            let b = bits as u32;
+            let imm = ((b >> 3) & 0b10_0000_0000)
+                    | ((b >> 2) & 0b00_0001_0000)
+                    | ((b << 1) & 0b00_0100_0000)
+                    | ((b << 4) & 0b01_1000_0000)
+                    | ((b << 3) & 0b00_0010_0000);
+            if imm == 0 {
+                return Err(Error::BadFunct);
+            }
+            Ok(Instruction::ADDI(i(sp, sp, sign_extend(imm, 10))))
+        }
+
+        // C.LUI
+        (0b01, 0b011) => {
+            let imm = imm6(bits);
+            if imm == 0 {
+                return Err(Error::BadFunct);
+            }
+            Ok(Instruction::LUI(UOperands { rd: reg(bits >> 7), imm: imm << 12 }))
+        }
+
+        (0b01, 0b100) => {
+            let rd = creg(bits >> 7);
+            match (bits >> 10) & 0b11 {
+                0b00 => Ok(Instruction::SRLI(i(rd, rd, shamt(bits)?))),
+                0b01 => Ok(Instruction::SRAI(i(rd, rd, shamt(bits)? | 0b0100000_00000))),
+                0b10 => Ok(Instruction::ANDI(i(rd, rd, imm6(bits)))),
+                _ => {
+                    if bits & (1 << 12) != 0 {
+                        return Err(Error::BadFunct);
+                    }
+                    let op = r(rd, rd, creg(bits >> 2));
+                    match (bits >> 5) & 0b11 {
+                        0b00 => Ok(Instruction::SUB(op)),
+                        0b01 => Ok(Instruction::XOR(op)),
+                        0b10 => Ok(Instruction::OR(op)),
+                        _    => Ok(Instruction::AND(op)),
+                    }
+                }
+            }
+        }
+
+        // C.J
+        (0b01, 0b101) => Ok(Instruction::JAL(JOperands { rd: x0, imm: j_imm(bits) })),
+
+        // C.BEQZ, C.BNEZ
+        (0b01, 0b110) | (0b01, 0b111) => {
+            let op = BOperands { rs1: creg(bits >> 7), rs2: x0, imm: b_imm(bits) };
+            if funct3(bits) == 0b110 {
+                Ok(Instruction::BEQ(op))
+            } else {
+                Ok(Instruction::BNE(op))
+            }
+        }
+
+        // C.SLLI
+        (0b10, 0b000) => {
+            let rd = reg(bits >> 7);
+            Ok(Instruction::SLLI(i(rd, rd, shamt(bits)?)))
+        }
+
+        // C.LWSP
+        (0b10, 0b010) => {
+            let rd = reg(bits >> 7);
+            if rd == x0 {
+                return Err(Error::BadFunct);
+            }
+            let imm = ((bits >> 7) & 0b00100000)
+                    | ((bits >> 2) & 0b00011100)
+                    | ((bits << 4) & 0b11000000);
+            Ok(Instruction::LW(i(rd, sp, imm as Imm)))
+        }
+
+        (0b10, 0b100) => {
+            let rs1 = reg(bits >> 7);
+            let rs2 = reg(bits >> 2);
+            match (bits & (1 << 12) != 0, rs1 == x0, rs2 == x0) {
+                // C.JR
+                (false, false, true) => Ok(Instruction::JALR(i(x0, rs1, 0))),
+                // C.MV
+                (false, _, false) => Ok(Instruction::ADD(r(rs1, x0, rs2))),
+                // C.EBREAK
+                (true, true, true) => Ok(Instruction::EBREAK),
+                // C.JALR
+                (true, false, true) => Ok(Instruction::JALR(i(ra, rs1, 0))),
+                // C.ADD
+                (true, _, false) => Ok(Instruction::ADD(r(rs1, rs1, rs2))),
+
+                _ => Err(Error::BadFunct),
+            }
+        }
+
+        // C.SWSP
+        (0b10, 0b110) => Ok(Instruction::SW(SOperands {
+            rs1: sp,
+            rs2: reg(bits >> 2),
+            imm: (((bits >> 7) & 0b00111100)
+                | ((bits >> 1) & 0b11000000)) as Imm,
+        })),
+
+        // Floating point loads and stores, and reserved encodings.
+        (0b00, _) | (0b01, _) | (0b10, _) => Err(Error::BadFunct),
+
+        _ => Err(Error::BadOpcode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode;
+    use decode::Instruction::*;
+    use decode::Reg;
+    use decode::formats::{IOperands, ROperands, SOperands, BOperands, JOperands};
+
+    #[test]
+    fn test_decode_compressed() {
+        // c.addi4spn a0,sp,16
+        assert_eq!(ADDI(IOperands { rd: Reg::a0(), rs1: Reg::sp(), imm: 16 }),
+                   decode(0x0808).unwrap());
+        // c.li a0,-1
+        assert_eq!(ADDI(IOperands { rd: Reg::a0(), rs1: Reg::zero(), imm: !0 }),
+                   decode(0x557d).unwrap());
+        // c.addi16sp sp,-32
+        assert_eq!(ADDI(IOperands { rd: Reg::sp(), rs1: Reg::sp(), imm: -32i32 as u32 }),
+                   decode(0x713d).unwrap());
+        // c.lw a5,4(a0)
+        assert_eq!(LW(IOperands { rd: Reg::a5(), rs1: Reg::a0(), imm: 4 }),
+                   decode(0x415c).unwrap());
+        // c.swsp ra,28(sp)
+        assert_eq!(SW(SOperands { rs1: Reg::sp(), rs2: Reg::ra(), imm: 28 }),
+                   decode(0xce06).unwrap());
+        // c.mv a3,a4
+        assert_eq!(ADD(ROperands { rd: Reg::a3(), rs1: Reg::zero(), rs2: Reg::a4() }),
+                   decode(0x86ba).unwrap());
+        // c.jr ra
+        assert_eq!(JALR(IOperands { rd: Reg::zero(), rs1: Reg::ra(), imm: 0 }),
+                   decode(0x8082).unwrap());
+        // c.j -20
+        assert_eq!(JAL(JOperands { rd: Reg::zero(), imm: -20i32 as u32 }),
+                   decode(0xb7f5).unwrap());
+        // c.bnez a5,-6
+        assert_eq!(BNE(BOperands { rs1: Reg::a5(), rs2: Reg::zero(), imm: -6i32 as u32 }),
+                   decode(0xffed).unwrap());
+        assert_eq!(EBREAK, decode(0x9002).unwrap());
+    }
+
+    #[test]
+    fn test_decode_compressed_illegal() {
+        assert!(decode(0x0000).is_err());
+        // c.lwsp with rd = x0
+        assert!(decode(0x4002).is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use num::FromPrimitive;
 
 use {Error, Result};
-use super::{Reg, Funct, Imm};
+use super::{Reg, Funct, Imm, Csr};
 
 /// Halfway-decoded instruction.
 #[derive(Clone, Debug)]
@@ -19,11 +19,12 @@ enum_from_primitive! {
     #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
     pub enum Opcode {
         Load    = 0b00_000_11,
-     // MiscMem = 0b00_011_11,
+        MiscMem = 0b00_011_11,
         OpImm   = 0b00_100_11,
         Auipc   = 0b00_101_11,
      // OpImm32 = 0b00_110_11,
         Store   = 0b01_000_11,
+        Amo     = 0b01_011_11,
         Op      = 0b01_100_11,
         Lui     = 0b01_101_11,
      // Op32    = 0b01_110_11,
@@ -50,6 +51,16 @@ pub struct ROperands {
     pub rs2: Reg,
 }
 
+/// R-type operands of an atomic memory operation, plus its ordering bits.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AOperands {
+    pub rd: Reg,
+    pub rs1: Reg,
+    pub rs2: Reg,
+    pub aq: bool,
+    pub rl: bool,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct IOperands {
     pub rd: Reg,
@@ -83,18 +94,50 @@ pub struct JOperands {
     pub imm: Imm,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CsrOperands {
+    pub rd: Reg,
+    pub rs1: Reg,
+    pub csr: Csr,
+}
+
+/// The predecessor and successor sets of a FENCE, each a mask of
+/// `FENCE_I`, `FENCE_O`, `FENCE_R` and `FENCE_W`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FenceOperands {
+    pub pred: u8,
+    pub succ: u8,
+}
+
+pub const FENCE_I: u8 = 0b1000;
+pub const FENCE_O: u8 = 0b0100;
+pub const FENCE_R: u8 = 0b0010;
+pub const FENCE_W: u8 = 0b0001;
+
+/// Operands of CSRRWI, CSRRSI and CSRRCI, where the rs1 field holds a
+/// 5-bit unsigned immediate instead.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CsrIOperands {
+    pub rd: Reg,
+    pub uimm: Imm,
+    pub csr: Csr,
+}
+
 pub type RInstruction = Instruction<ROperands>;
+pub type AInstruction = Instruction<AOperands>;
 pub type IInstruction = Instruction<IOperands>;
 pub type SInstruction = Instruction<SOperands>;
 pub type BInstruction = Instruction<BOperands>;
 pub type UInstruction = Instruction<UOperands>;
 pub type JInstruction = Instruction<JOperands>;
+pub type CsrInstruction = Instruction<CsrOperands>;
+pub type CsrIInstruction = Instruction<CsrIOperands>;
 
 fn inst<T>(bits: u32, funct: Funct, operands: T) -> Result<Instruction<T>> {
     Ok(Instruction {
         opcode: Opcode::from_inst(bits)?,
-        funct: funct,
-        operands: operands,
+        funct,
+        operands,
     })
 }
 
@@ -110,11 +153,15 @@ fn funct7(bits: u32) -> Funct {
     ((bits >> 22) & 0b1111111_000) as Funct
 }
 
+fn funct5(bits: u32) -> Funct {
+    ((bits >> 24) & 0b11111_000) as Funct
+}
+
 // go from
 //   00..00snn..nn
 // to
 //   ss..sssnn..nn
-fn sign_extend(mut bits: u32, num_bits: u8) -> u32 {
+pub(super) fn sign_extend(mut bits: u32, num_bits: u8) -> u32 {
     assert!(num_bits > 0);
     if 0 != bits & (1 << (num_bits - 1)) {
         for i in num_bits..32 {
@@ -132,6 +179,16 @@ pub fn decode_r(bits: u32) -> Result<RInstruction> {
     })
 }
 
+pub fn decode_a(bits: u32) -> Result<AInstruction> {
+    inst(bits, funct3(bits) | funct5(bits), AOperands {
+        rd: reg(bits >> 7)?,
+        rs1: reg(bits >> 15)?,
+        rs2: reg(bits >> 20)?,
+        aq: 0 != bits & (1 << 26),
+        rl: 0 != bits & (1 << 25),
+    })
+}
+
 pub fn decode_i(bits: u32) -> Result<IInstruction> {
     inst(bits, funct3(bits), IOperands {
         rd: reg(bits >> 7)?,
@@ -180,6 +237,154 @@ pub fn decode_j(bits: u32) -> Result<JInstruction> {
     })
 }
 
+pub fn decode_csr(bits: u32) -> Result<CsrInstruction> {
+    inst(bits, funct3(bits), CsrOperands {
+        rd: reg(bits >> 7)?,
+        rs1: reg(bits >> 15)?,
+        csr: (bits >> 20) as Csr,
+    })
+}
+
+pub fn decode_csri(bits: u32) -> Result<CsrIInstruction> {
+    inst(bits, funct3(bits), CsrIOperands {
+        rd: reg(bits >> 7)?,
+        uimm: (bits >> 15) & 0b11111,
+        csr: (bits >> 20) as Csr,
+    })
+}
+
+fn check_signed(imm: Imm, num_bits: u8) -> Result<()> {
+    if sign_extend(imm & ((1 << num_bits) - 1), num_bits) != imm {
+        return Err(Error::ImmediateOutOfRange);
+    }
+    Ok(())
+}
+
+fn check_even(imm: Imm) -> Result<()> {
+    if imm & 1 != 0 {
+        return Err(Error::MisalignedImmediate);
+    }
+    Ok(())
+}
+
+fn funct3_bits(funct: Funct) -> u32 {
+    ((funct & 0b111) as u32) << 12
+}
+
+// The inverse of funct7() and funct5(), which leave bit 25 (or 26) of the
+// instruction at bit 3 of the funct.
+fn upper_funct_bits(funct: Funct) -> u32 {
+    ((funct as u32) >> 3) << 25
+}
+
+fn reg_bits(reg: Reg, shift: u8) -> u32 {
+    (reg.num() as u32) << shift
+}
+
+pub fn encode_r(inst: &RInstruction) -> Result<u32> {
+    let op = &inst.operands;
+    Ok(inst.opcode as u32
+       | funct3_bits(inst.funct)
+       | upper_funct_bits(inst.funct)
+       | reg_bits(op.rd, 7)
+       | reg_bits(op.rs1, 15)
+       | reg_bits(op.rs2, 20))
+}
+
+pub fn encode_a(inst: &AInstruction) -> Result<u32> {
+    let op = &inst.operands;
+    Ok(inst.opcode as u32
+       | funct3_bits(inst.funct)
+       | (upper_funct_bits(inst.funct) << 2)
+       | reg_bits(op.rd, 7)
+       | reg_bits(op.rs1, 15)
+       | reg_bits(op.rs2, 20)
+       | if op.aq { 1 << 26 } else { 0 }
+       | if op.rl { 1 << 25 } else { 0 })
+}
+
+pub fn encode_i(inst: &IInstruction) -> Result<u32> {
+    let op = &inst.operands;
+    check_signed(op.imm, 12)?;
+    Ok(inst.opcode as u32
+       | funct3_bits(inst.funct)
+       | reg_bits(op.rd, 7)
+       | reg_bits(op.rs1, 15)
+       | (op.imm << 20))
+}
+
+pub fn encode_s(inst: &SInstruction) -> Result<u32> {
+    let op = &inst.operands;
+    check_signed(op.imm, 12)?;
+    Ok(inst.opcode as u32
+       | funct3_bits(inst.funct)
+       | reg_bits(op.rs1, 15)
+       | reg_bits(op.rs2, 20)
+       | ((op.imm & 0b11111) << 7)
+       | ((op.imm & 0b1111111_00000) << 20))
+}
+
+pub fn encode_b(inst: &BInstruction) -> Result<u32> {
+    let op = &inst.operands;
+    check_signed(op.imm, 13)?;
+    check_even(op.imm)?;
+    Ok(inst.opcode as u32
+       | funct3_bits(inst.funct)
+       | reg_bits(op.rs1, 15)
+       | reg_bits(op.rs2, 20)
+       | ((op.imm & 0b1111_0) << 7)
+       | ((op.imm & 0b111111_0000_0) << 20)
+       | ((op.imm & 0b1_000000_0000_0) >> 4)
+       | ((op.imm & 0b1_0_000000_0000_0) << 19))
+}
+
+pub fn encode_u(inst: &UInstruction) -> Result<u32> {
+    let op = &inst.operands;
+    if op.imm & 0b111111111111 != 0 {
+        return Err(Error::MisalignedImmediate);
+    }
+    Ok(inst.opcode as u32 | reg_bits(op.rd, 7) | op.imm)
+}
+
+pub fn encode_j(inst: &JInstruction) -> Result<u32> {
+    let op = &inst.operands;
+    check_signed(op.imm, 21)?;
+    check_even(op.imm)?;
+    Ok(inst.opcode as u32
+       | reg_bits(op.rd, 7)
+       | (op.imm & 0b11111111_000000000000)
+       | ((op.imm & 0b100000000000) << 9)
+       | ((op.imm & 0b11111111110) << 20)
+       | ((op.imm & 0b1_00000000000000000000) << 11))
+}
+
+pub fn encode_csr(inst: &CsrInstruction) -> Result<u32> {
+    let op = &inst.operands;
+    if op.csr >= 1 << 12 {
+        return Err(Error::BadCsr);
+    }
+    Ok(inst.opcode as u32
+       | funct3_bits(inst.funct)
+       | reg_bits(op.rd, 7)
+       | reg_bits(op.rs1, 15)
+       | ((op.csr as u32) << 20))
+}
+
+pub fn encode_csri(inst: &CsrIInstruction) -> Result<u32> {
+    let op = &inst.operands;
+    if op.csr >= 1 << 12 {
+        return Err(Error::BadCsr);
+    }
+    if op.uimm >= 1 << 5 {
+        return Err(Error::ImmediateOutOfRange);
+    }
+    Ok(inst.opcode as u32
+       | funct3_bits(inst.funct)
+       | reg_bits(op.rd, 7)
+       | (op.uimm << 15)
+       | ((op.csr as u32) << 20))
+}
+
 #[cfg(test)]
 mod tests {
     use super::sign_extend;
@@ -1,7 +1,11 @@
+use std::fmt;
+
 use {Error, Result};
-use self::formats::{ROperands, IOperands, SOperands, BOperands, UOperands, JOperands};
+use self::formats::{ROperands, AOperands, IOperands, SOperands, BOperands, UOperands, JOperands,
+                    CsrOperands, CsrIOperands, FenceOperands};
 
 pub mod formats;
+pub mod compressed;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Reg(u8);
@@ -29,6 +33,26 @@ impl Reg {
         self.0
     }
 
+    /// The calling convention's name for this register, as used by
+    /// disassemblers.
+    pub fn abi_name(&self) -> &'static str {
+        ABI_NAMES[self.0 as usize]
+    }
+
+    /// Look up a register by ABI name (including `fp`) or by `xN` name.
+    pub fn from_name(name: &str) -> Option<Reg> {
+        if name == "fp" {
+            return Some(Reg::fp());
+        }
+        if let Some(n) = ABI_NAMES.iter().position(|&abi| abi == name) {
+            return Some(Reg(n as u8));
+        }
+        if name.starts_with('x') && (name.len() == 2 || !name[1..].starts_with('0')) {
+            return name[1..].parse().ok().and_then(|n| Reg::new(n).ok());
+        }
+        None
+    }
+
     reg_names! {
         // Machine register names
         x0  => 0,   x1 => 1,   x2 => 2,   x3 => 3,
@@ -57,8 +81,22 @@ impl Reg {
     }
 }
 
+static ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.abi_name())
+    }
+}
+
 pub type Funct = u16;
 pub type Imm   = u32;
+pub type Csr   = u16;
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Instruction {
@@ -86,6 +124,15 @@ pub enum Instruction {
     SRA(ROperands),
     SUB(ROperands),
 
+    MUL(ROperands),
+    MULH(ROperands),
+    MULHSU(ROperands),
+    MULHU(ROperands),
+    DIV(ROperands),
+    DIVU(ROperands),
+    REM(ROperands),
+    REMU(ROperands),
+
     JAL(JOperands),
     JALR(IOperands),
 
@@ -105,13 +152,34 @@ pub enum Instruction {
     SH(SOperands),
     SB(SOperands),
 
+    LR(AOperands),
+    SC(AOperands),
+    AMOSWAP(AOperands),
+    AMOADD(AOperands),
+    AMOXOR(AOperands),
+    AMOAND(AOperands),
+    AMOOR(AOperands),
+    AMOMIN(AOperands),
+    AMOMAX(AOperands),
+    AMOMINU(AOperands),
+    AMOMAXU(AOperands),
+
+    CSRRW(CsrOperands),
+    CSRRS(CsrOperands),
+    CSRRC(CsrOperands),
+    CSRRWI(CsrIOperands),
+    CSRRSI(CsrIOperands),
+    CSRRCI(CsrIOperands),
+
+    FENCE(FenceOperands),
+    FENCEI,
+
     ECALL,
     EBREAK,
+    MRET,
 
     // Not implemented:
-    //     FENCE FENCE.I
-    //     CSRRW CSRRS CSRRC CSRRWI CSRRSI CSRRCI
-    //     RDCYCLE RDCYCLEH RDTIME RDTIMEH RDINSTRET RDINSTRETH
+    //     RDTIME RDTIMEH
 }
 
 macro_rules! instruction {
@@ -120,7 +188,20 @@ macro_rules! instruction {
     };
 }
 
+/// Length in bytes of the instruction whose low halfword is `bits`.
+pub fn instruction_length(bits: u16) -> u32 {
+    if bits & 0b11 == 0b11 { 4 } else { 2 }
+}
+
+/// Decode one instruction.
+///
+/// If the low two bits mark a compressed instruction, only the low
+/// halfword of `bits` is used.
 pub fn decode(bits: u32) -> Result<Instruction> {
+    if instruction_length(bits as u16) == 2 {
+        return compressed::decode(bits as u16);
+    }
+
     match formats::Opcode::from_inst(bits)? {
         formats::Opcode::OpImm => {
             let inst = formats::decode_i(bits)?;
@@ -158,6 +239,15 @@ pub fn decode(bits: u32) -> Result<Instruction> {
                 0b_0100000_000 => instruction!(SUB, inst),
                 0b_0100000_101 => instruction!(SRA, inst),
 
+                0b_0000001_000 => instruction!(MUL,    inst),
+                0b_0000001_001 => instruction!(MULH,   inst),
+                0b_0000001_010 => instruction!(MULHSU, inst),
+                0b_0000001_011 => instruction!(MULHU,  inst),
+                0b_0000001_100 => instruction!(DIV,    inst),
+                0b_0000001_101 => instruction!(DIVU,   inst),
+                0b_0000001_110 => instruction!(REM,    inst),
+                0b_0000001_111 => instruction!(REMU,   inst),
+
                 _ => Err(Error::BadFunct),
             }
         }
@@ -212,14 +302,55 @@ pub fn decode(bits: u32) -> Result<Instruction> {
             }
         }
 
+        formats::Opcode::Amo => {
+            let inst = formats::decode_a(bits)?;
+            match inst.funct {
+                0b_00010_010 if inst.operands.rs2.num() == 0
+                    => instruction!(LR, inst),
+                0b_00011_010 => instruction!(SC,      inst),
+                0b_00001_010 => instruction!(AMOSWAP, inst),
+                0b_00000_010 => instruction!(AMOADD,  inst),
+                0b_00100_010 => instruction!(AMOXOR,  inst),
+                0b_01100_010 => instruction!(AMOAND,  inst),
+                0b_01000_010 => instruction!(AMOOR,   inst),
+                0b_10000_010 => instruction!(AMOMIN,  inst),
+                0b_10100_010 => instruction!(AMOMAX,  inst),
+                0b_11000_010 => instruction!(AMOMINU, inst),
+                0b_11100_010 => instruction!(AMOMAXU, inst),
+
+                _ => Err(Error::BadFunct),
+            }
+        }
+
+        // The fm field and the register fields are ignored, as the spec
+        // allows; FENCE.TSO is an ordinary FENCE here.
+        formats::Opcode::MiscMem => {
+            match (bits >> 12) & 0b111 {
+                0b_000 => Ok(Instruction::FENCE(FenceOperands {
+                    pred: (bits >> 24) as u8 & 0b1111,
+                    succ: (bits >> 20) as u8 & 0b1111,
+                })),
+                0b_001 => Ok(Instruction::FENCEI),
+                _ => Err(Error::BadFunct),
+            }
+        }
+
         formats::Opcode::System => {
             let inst = formats::decode_i(bits)?;
             match inst.funct {
+                0b_001 => instruction!(CSRRW,  formats::decode_csr(bits)?),
+                0b_010 => instruction!(CSRRS,  formats::decode_csr(bits)?),
+                0b_011 => instruction!(CSRRC,  formats::decode_csr(bits)?),
+                0b_101 => instruction!(CSRRWI, formats::decode_csri(bits)?),
+                0b_110 => instruction!(CSRRSI, formats::decode_csri(bits)?),
+                0b_111 => instruction!(CSRRCI, formats::decode_csri(bits)?),
+
                 0b_000 if inst.operands.rs1.num() == 0
                          && inst.operands.rd.num() == 0 => {
                     match inst.operands.imm {
                         0 => Ok(Instruction::ECALL),
                         1 => Ok(Instruction::EBREAK),
+                        0b_0011000_00010 => Ok(Instruction::MRET),
                         _ => Err(Error::BadFunct),
                     }
                 }
@@ -0,0 +1,371 @@
+//! Printing instructions in assembler syntax.
+
+use std::fmt;
+
+use decode::{Instruction, Reg, Imm, Csr};
+use decode::Instruction::*;
+use decode::formats::{ROperands, AOperands, IOperands, SOperands, BOperands};
+use emu::csr;
+
+/// Choices that objdump also offers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Syntax {
+    /// Use `a0`, `sp` etc. instead of `x10`, `x2`.
+    pub abi_names: bool,
+
+    /// Print aliases like `li`, `mv`, `beqz`, `j` and `ret` where they
+    /// apply.
+    pub pseudo: bool,
+}
+
+impl Default for Syntax {
+    fn default() -> Syntax {
+        Syntax {
+            abi_names: true,
+            pseudo: true,
+        }
+    }
+}
+
+// A FENCE predecessor or successor set, like "rw".
+fn fence_set(bits: u8) -> String {
+    let s: String = "iorw".chars().enumerate()
+        .filter(|&(i, _)| bits & (0b1000 >> i) != 0)
+        .map(|(_, c)| c)
+        .collect();
+    if s.is_empty() { "0".to_owned() } else { s }
+}
+
+struct Printer {
+    syntax: Syntax,
+    pc: Option<u32>,
+}
+
+// Mnemonic and operand list.
+type Parts = (String, Vec<String>);
+
+fn parts(mnemonic: &str, operands: Vec<String>) -> Parts {
+    (mnemonic.to_owned(), operands)
+}
+
+impl Printer {
+    fn reg(&self, reg: Reg) -> String {
+        if self.syntax.abi_names {
+            reg.abi_name().to_owned()
+        } else {
+            format!("x{}", reg.num())
+        }
+    }
+
+    fn is_zero(&self, reg: Reg) -> bool {
+        reg.num() == 0
+    }
+
+    // A branch or jump target: absolute if we know the pc, otherwise a
+    // signed offset.
+    fn target(&self, imm: Imm) -> String {
+        match self.pc {
+            Some(pc) => format!("0x{:x}", pc.wrapping_add(imm)),
+            None => format!("{}", imm as i32),
+        }
+    }
+
+    fn csr(&self, csr: Csr) -> String {
+        match csr::name(csr) {
+            Some(name) => name.to_owned(),
+            None => format!("0x{:x}", csr),
+        }
+    }
+
+    fn r(&self, mnemonic: &str, op: &ROperands) -> Parts {
+        parts(mnemonic, vec![self.reg(op.rd), self.reg(op.rs1), self.reg(op.rs2)])
+    }
+
+    fn i(&self, mnemonic: &str, op: &IOperands) -> Parts {
+        parts(mnemonic, vec![self.reg(op.rd), self.reg(op.rs1), format!("{}", op.imm as i32)])
+    }
+
+    fn shift(&self, mnemonic: &str, op: &IOperands) -> Parts {
+        parts(mnemonic, vec![self.reg(op.rd), self.reg(op.rs1),
+                             format!("0x{:x}", op.imm & 0b11111)])
+    }
+
+    fn load(&self, mnemonic: &str, op: &IOperands) -> Parts {
+        parts(mnemonic, vec![self.reg(op.rd),
+                             format!("{}({})", op.imm as i32, self.reg(op.rs1))])
+    }
+
+    fn store(&self, mnemonic: &str, op: &SOperands) -> Parts {
+        parts(mnemonic, vec![self.reg(op.rs2),
+                             format!("{}({})", op.imm as i32, self.reg(op.rs1))])
+    }
+
+    fn branch(&self, mnemonic: &str, op: &BOperands) -> Parts {
+        let (rs1, rs2) = (op.rs1, op.rs2);
+        if self.syntax.pseudo {
+            let zero_form = match mnemonic {
+                "beq" if self.is_zero(rs2) => Some(("beqz", rs1)),
+                "bne" if self.is_zero(rs2) => Some(("bnez", rs1)),
+                "bge" if self.is_zero(rs1) => Some(("blez", rs2)),
+                "bge" if self.is_zero(rs2) => Some(("bgez", rs1)),
+                "blt" if self.is_zero(rs2) => Some(("bltz", rs1)),
+                "blt" if self.is_zero(rs1) => Some(("bgtz", rs2)),
+                _ => None,
+            };
+            if let Some((mnemonic, rs)) = zero_form {
+                return parts(mnemonic, vec![self.reg(rs), self.target(op.imm)]);
+            }
+        }
+        parts(mnemonic, vec![self.reg(rs1), self.reg(rs2), self.target(op.imm)])
+    }
+
+    fn amo(&self, mnemonic: &str, op: &AOperands) -> Parts {
+        let ordering = match (op.aq, op.rl) {
+            (false, false) => "",
+            (true, false) => ".aq",
+            (false, true) => ".rl",
+            (true, true) => ".aqrl",
+        };
+        let mnemonic = format!("{}.w{}", mnemonic, ordering);
+        let addr = format!("({})", self.reg(op.rs1));
+        if mnemonic.starts_with("lr") {
+            parts(&mnemonic, vec![self.reg(op.rd), addr])
+        } else {
+            parts(&mnemonic, vec![self.reg(op.rd), self.reg(op.rs2), addr])
+        }
+    }
+
+    // csrr, csrw and friends, or None if the plain form is needed.
+    fn csr_alias(&self, inst: &Instruction) -> Option<Parts> {
+        let counter = |csr| match csr {
+            csr::CYCLE => Some("rdcycle"),
+            csr::INSTRET => Some("rdinstret"),
+            csr::CYCLEH => Some("rdcycleh"),
+            csr::INSTRETH => Some("rdinstreth"),
+            _ => None,
+        };
+
+        match *inst {
+            CSRRS(ref op) if self.is_zero(op.rs1) => Some(match counter(op.csr) {
+                Some(name) => parts(name, vec![self.reg(op.rd)]),
+                None => parts("csrr", vec![self.reg(op.rd), self.csr(op.csr)]),
+            }),
+
+            CSRRW(ref op) if self.is_zero(op.rd)
+                => Some(parts("csrw", vec![self.csr(op.csr), self.reg(op.rs1)])),
+            CSRRS(ref op) if self.is_zero(op.rd)
+                => Some(parts("csrs", vec![self.csr(op.csr), self.reg(op.rs1)])),
+            CSRRC(ref op) if self.is_zero(op.rd)
+                => Some(parts("csrc", vec![self.csr(op.csr), self.reg(op.rs1)])),
+            CSRRWI(ref op) if self.is_zero(op.rd)
+                => Some(parts("csrwi", vec![self.csr(op.csr), format!("{}", op.uimm)])),
+            CSRRSI(ref op) if self.is_zero(op.rd)
+                => Some(parts("csrsi", vec![self.csr(op.csr), format!("{}", op.uimm)])),
+            CSRRCI(ref op) if self.is_zero(op.rd)
+                => Some(parts("csrci", vec![self.csr(op.csr), format!("{}", op.uimm)])),
+
+            _ => None,
+        }
+    }
+
+    fn pseudo(&self, inst: &Instruction) -> Option<Parts> {
+        let zero = |r: Reg| self.is_zero(r);
+        let reg = |r: Reg| self.reg(r);
+
+        match *inst {
+            ADDI(ref op) if zero(op.rd) && zero(op.rs1) && op.imm == 0
+                => Some(parts("nop", vec![])),
+            ADDI(ref op) if zero(op.rs1)
+                => Some(parts("li", vec![reg(op.rd), format!("{}", op.imm as i32)])),
+            ADDI(ref op) if op.imm == 0
+                => Some(parts("mv", vec![reg(op.rd), reg(op.rs1)])),
+            ADD(ref op) if zero(op.rs1)
+                => Some(parts("mv", vec![reg(op.rd), reg(op.rs2)])),
+            XORI(ref op) if op.imm == !0
+                => Some(parts("not", vec![reg(op.rd), reg(op.rs1)])),
+            SUB(ref op) if zero(op.rs1)
+                => Some(parts("neg", vec![reg(op.rd), reg(op.rs2)])),
+            SLTIU(ref op) if op.imm == 1
+                => Some(parts("seqz", vec![reg(op.rd), reg(op.rs1)])),
+            SLTU(ref op) if zero(op.rs1)
+                => Some(parts("snez", vec![reg(op.rd), reg(op.rs2)])),
+            SLT(ref op) if zero(op.rs2)
+                => Some(parts("sltz", vec![reg(op.rd), reg(op.rs1)])),
+            SLT(ref op) if zero(op.rs1)
+                => Some(parts("sgtz", vec![reg(op.rd), reg(op.rs2)])),
+
+            JAL(ref op) if zero(op.rd)
+                => Some(parts("j", vec![self.target(op.imm)])),
+            JAL(ref op) if op.rd == Reg::ra()
+                => Some(parts("jal", vec![self.target(op.imm)])),
+
+            JALR(ref op) if zero(op.rd) && op.rs1 == Reg::ra() && op.imm == 0
+                => Some(parts("ret", vec![])),
+            JALR(ref op) if zero(op.rd) && op.imm == 0
+                => Some(parts("jr", vec![reg(op.rs1)])),
+            JALR(ref op) if op.rd == Reg::ra() && op.imm == 0
+                => Some(parts("jalr", vec![reg(op.rs1)])),
+
+            CSRRW(_) | CSRRS(_) | CSRRC(_) | CSRRWI(_) | CSRRSI(_) | CSRRCI(_)
+                => self.csr_alias(inst),
+
+            _ => None,
+        }
+    }
+
+    fn parts(&self, inst: &Instruction) -> Parts {
+        if self.syntax.pseudo {
+            if let Some(p) = self.pseudo(inst) {
+                return p;
+            }
+        }
+
+        match *inst {
+            ADDI(ref op)  => self.i("addi", op),
+            SLTI(ref op)  => self.i("slti", op),
+            SLTIU(ref op) => self.i("sltiu", op),
+            ANDI(ref op)  => self.i("andi", op),
+            ORI(ref op)   => self.i("ori", op),
+            XORI(ref op)  => self.i("xori", op),
+            SLLI(ref op)  => self.shift("slli", op),
+            SRLI(ref op)  => self.shift("srli", op),
+            SRAI(ref op)  => self.shift("srai", op),
+
+            LUI(ref op)   => parts("lui", vec![self.reg(op.rd), format!("0x{:x}", op.imm >> 12)]),
+            AUIPC(ref op) => parts("auipc", vec![self.reg(op.rd), format!("0x{:x}", op.imm >> 12)]),
+
+            ADD(ref op)  => self.r("add", op),
+            SLT(ref op)  => self.r("slt", op),
+            SLTU(ref op) => self.r("sltu", op),
+            AND(ref op)  => self.r("and", op),
+            OR(ref op)   => self.r("or", op),
+            XOR(ref op)  => self.r("xor", op),
+            SLL(ref op)  => self.r("sll", op),
+            SRL(ref op)  => self.r("srl", op),
+            SRA(ref op)  => self.r("sra", op),
+            SUB(ref op)  => self.r("sub", op),
+
+            MUL(ref op)    => self.r("mul", op),
+            MULH(ref op)   => self.r("mulh", op),
+            MULHSU(ref op) => self.r("mulhsu", op),
+            MULHU(ref op)  => self.r("mulhu", op),
+            DIV(ref op)    => self.r("div", op),
+            DIVU(ref op)   => self.r("divu", op),
+            REM(ref op)    => self.r("rem", op),
+            REMU(ref op)   => self.r("remu", op),
+
+            JAL(ref op) => parts("jal", vec![self.reg(op.rd), self.target(op.imm)]),
+            JALR(ref op) => self.load("jalr", op),
+
+            BEQ(ref op)  => self.branch("beq", op),
+            BNE(ref op)  => self.branch("bne", op),
+            BLT(ref op)  => self.branch("blt", op),
+            BLTU(ref op) => self.branch("bltu", op),
+            BGE(ref op)  => self.branch("bge", op),
+            BGEU(ref op) => self.branch("bgeu", op),
+
+            LW(ref op)  => self.load("lw", op),
+            LH(ref op)  => self.load("lh", op),
+            LHU(ref op) => self.load("lhu", op),
+            LB(ref op)  => self.load("lb", op),
+            LBU(ref op) => self.load("lbu", op),
+            SW(ref op)  => self.store("sw", op),
+            SH(ref op)  => self.store("sh", op),
+            SB(ref op)  => self.store("sb", op),
+
+            LR(ref op)      => self.amo("lr", op),
+            SC(ref op)      => self.amo("sc", op),
+            AMOSWAP(ref op) => self.amo("amoswap", op),
+            AMOADD(ref op)  => self.amo("amoadd", op),
+            AMOXOR(ref op)  => self.amo("amoxor", op),
+            AMOAND(ref op)  => self.amo("amoand", op),
+            AMOOR(ref op)   => self.amo("amoor", op),
+            AMOMIN(ref op)  => self.amo("amomin", op),
+            AMOMAX(ref op)  => self.amo("amomax", op),
+            AMOMINU(ref op) => self.amo("amominu", op),
+            AMOMAXU(ref op) => self.amo("amomaxu", op),
+
+            CSRRW(ref op) => parts("csrrw", vec![self.reg(op.rd), self.csr(op.csr), self.reg(op.rs1)]),
+            CSRRS(ref op) => parts("csrrs", vec![self.reg(op.rd), self.csr(op.csr), self.reg(op.rs1)]),
+            CSRRC(ref op) => parts("csrrc", vec![self.reg(op.rd), self.csr(op.csr), self.reg(op.rs1)]),
+            CSRRWI(ref op) => parts("csrrwi", vec![self.reg(op.rd), self.csr(op.csr), format!("{}", op.uimm)]),
+            CSRRSI(ref op) => parts("csrrsi", vec![self.reg(op.rd), self.csr(op.csr), format!("{}", op.uimm)]),
+            CSRRCI(ref op) => parts("csrrci", vec![self.reg(op.rd), self.csr(op.csr), format!("{}", op.uimm)]),
+
+            FENCE(ref op) if self.syntax.pseudo && op.pred == 0b1111 && op.succ == 0b1111
+                => parts("fence", vec![]),
+            FENCE(ref op) => parts("fence", vec![fence_set(op.pred), fence_set(op.succ)]),
+            FENCEI => parts("fence.i", vec![]),
+
+            ECALL  => parts("ecall", vec![]),
+            EBREAK => parts("ebreak", vec![]),
+            MRET   => parts("mret", vec![]),
+        }
+    }
+}
+
+/// Disassemble one instruction, objdump style.
+///
+/// If `pc` is the address of the instruction, branch and jump targets are
+/// printed as absolute addresses; otherwise as offsets.
+pub fn disassemble(inst: &Instruction, pc: Option<u32>, syntax: Syntax) -> String {
+    let printer = Printer { syntax, pc };
+    let (mnemonic, operands) = printer.parts(inst);
+    if operands.is_empty() {
+        mnemonic
+    } else {
+        format!("{} {}", mnemonic, operands.join(","))
+    }
+}
+
+/// Prints with the default `Syntax`, and relative branch targets.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&disassemble(self, None, Syntax::default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{disassemble, Syntax};
+    use decode::decode;
+
+    fn dis(bits: u32, pc: Option<u32>, syntax: Syntax) -> String {
+        disassemble(&decode(bits).unwrap(), pc, syntax)
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let plain = Syntax { abi_names: false, pseudo: false };
+        let abi = Syntax { abi_names: true, pseudo: false };
+        let pseudo = Syntax::default();
+
+        assert_eq!("addi a5,a0,-1", dis(0xfff50793, None, pseudo));
+        assert_eq!("addi x15,x10,-1", dis(0xfff50793, None, plain));
+        assert_eq!("li a4,1", dis(0x00100713, None, pseudo));
+        assert_eq!("addi a4,zero,1", dis(0x00100713, None, abi));
+        assert_eq!("mv a3,a4", dis(0x00070693, None, pseudo));
+        assert_eq!("bnez a5,0x14", dis(0xfe0798e3, Some(0x24), pseudo));
+        assert_eq!("bne a5,zero,-16", dis(0xfe0798e3, None, abi));
+        assert_eq!("j 0x0", dis(0xffdff06f, Some(4), pseudo));
+        assert_eq!("ret", dis(0x00008067, None, pseudo));
+        assert_eq!("jalr zero,0(ra)", dis(0x00008067, None, abi));
+        assert_eq!("sw s0,12(sp)", dis(0x00812623, None, pseudo));
+        assert_eq!("lui t0,0x12345", dis(0x123452b7, None, pseudo));
+        assert_eq!("srai a0,a1,0x2", dis(0x4025d513, None, pseudo));
+        assert_eq!("lr.w.aqrl a2,(a0)", dis(0x1605262f, None, pseudo));
+        assert_eq!("amoswap.w a4,a1,(a0)", dis(0x08b5272f, None, pseudo));
+        assert_eq!("csrr a0,mhartid", dis(0xf1402573, None, pseudo));
+        assert_eq!("csrrs a0,mhartid,zero", dis(0xf1402573, None, abi));
+        assert_eq!("ecall", dis(0x00000073, None, pseudo));
+        assert_eq!("fence", dis(0x0ff0000f, None, pseudo));
+        assert_eq!("fence iorw,iorw", dis(0x0ff0000f, None, abi));
+        assert_eq!("fence i,o", dis(0x0840000f, None, pseudo));
+        assert_eq!("fence.i", dis(0x0000100f, None, pseudo));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!("beqz a0,44", format!("{}", decode(0x02050663).unwrap()));
+    }
+}
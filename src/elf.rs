@@ -0,0 +1,421 @@
+//! Loading ELF32 RISC-V executables into a `Machine`.
+
+use std::str;
+
+use emu::Machine;
+use emu::bus::{Bus, Perms};
+use {Error, Result};
+
+const EM_RISCV: u16 = 243;
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const SHT_SYMTAB: u32 = 2;
+
+// The most zero-filled memory, past the end of segments' file data, that
+// a file may ask for in all, so that a tiny file can't use up the host's
+// memory.
+const MAX_ZERO_FILL: u32 = 256 << 20;
+
+// We have no floating point registers, so only the soft-float ABI works.
+const EF_RISCV_FLOAT_ABI: u32 = 0x6;
+
+// RV32E code assumes only 16 registers, and its ABI differs from RV32I's.
+const EF_RISCV_RVE: u32 = 0x8;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SymbolKind {
+    NoType,
+    Object,
+    Func,
+    Section,
+    File,
+    Other(u8),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u32,
+    pub size: u32,
+    pub kind: SymbolKind,
+    pub global: bool,
+}
+
+/// What `load` learned about the executable.
+#[derive(Clone, Debug)]
+pub struct Image {
+    pub entry: u32,
+
+    /// The first address past the highest loaded segment, where a heap
+    /// would start.
+    pub end: u32,
+
+    /// Where the program headers ended up in memory, or 0 if no segment
+    /// loads them, and how many there are. Static C libraries find their
+    /// TLS segment through these.
+    pub phdr: u32,
+    pub phnum: u32,
+
+    pub symbols: Vec<Symbol>,
+}
+
+impl Image {
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+}
+
+// Bounds-checked little-endian reads from the file.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, off: u32, len: u32) -> Result<&'a [u8]> {
+        let start = off as usize;
+        let end = start.checked_add(len as usize).ok_or(Error::BadElf)?;
+        self.data.get(start..end).ok_or(Error::BadElf)
+    }
+
+    // The `len` bytes at `off`, to read a header from without worrying
+    // about the field offsets overflowing.
+    fn sub(&self, off: u32, len: u32) -> Result<Reader<'a>> {
+        Ok(Reader { data: self.bytes(off, len)? })
+    }
+
+    fn u8(&self, off: u32) -> Result<u8> {
+        Ok(self.bytes(off, 1)?[0])
+    }
+
+    fn u16(&self, off: u32) -> Result<u16> {
+        let b = self.bytes(off, 2)?;
+        Ok(b[0] as u16 | ((b[1] as u16) << 8))
+    }
+
+    fn u32(&self, off: u32) -> Result<u32> {
+        let b = self.bytes(off, 4)?;
+        Ok(b[0] as u32
+           | ((b[1] as u32) << 8)
+           | ((b[2] as u32) << 16)
+           | ((b[3] as u32) << 24))
+    }
+
+    fn c_str(&self, off: u32) -> Result<&'a str> {
+        let rest = self.data.get(off as usize..).ok_or(Error::BadElf)?;
+        let len = rest.iter().position(|&b| b == 0).ok_or(Error::BadElf)?;
+        str::from_utf8(&rest[..len]).map_err(|_| Error::BadElf)
+    }
+}
+
+// Offsets and addresses in the file may be anything, so arithmetic on
+// them is checked.
+fn add(a: u32, b: u32) -> Result<u32> {
+    a.checked_add(b).ok_or(Error::BadElf)
+}
+
+fn index(base: u32, i: u32, size: u32) -> Result<u32> {
+    add(base, i.checked_mul(size).ok_or(Error::BadElf)?)
+}
+
+fn check_header(r: &Reader) -> Result<()> {
+    if r.bytes(0, 4)? != b"\x7fELF" {
+        return Err(Error::BadElf);
+    }
+
+    // ELFCLASS32, ELFDATA2LSB, EV_CURRENT
+    if r.u8(4)? != 1 || r.u8(5)? != 1 || r.u8(6)? != 1 {
+        return Err(Error::BadElf);
+    }
+
+    if r.u16(16)? != ET_EXEC || r.u16(18)? != EM_RISCV {
+        return Err(Error::BadElf);
+    }
+
+    if r.u32(36)? & (EF_RISCV_FLOAT_ABI | EF_RISCV_RVE) != 0 {
+        return Err(Error::BadElf);
+    }
+
+    Ok(())
+}
+
+// Returns the end of the highest segment, and the address of the program
+// headers.
+fn load_segments<B: Bus>(r: &Reader, machine: &mut Machine<B>) -> Result<(u32, u32)> {
+    let phoff = r.u32(28)?;
+    let phentsize = r.u16(42)? as u32;
+    let phnum = r.u16(44)? as u32;
+
+    let mut end = 0;
+    let mut phdr = None;
+    let mut segments = vec![];
+    let mut zeroed = 0u32;
+    for i in 0..phnum {
+        let ph = r.sub(index(phoff, i, phentsize)?, 28)?;
+        match ph.u32(0)? {
+            PT_LOAD => (),
+            PT_PHDR => {
+                phdr = Some(ph.u32(8)?);
+                continue;
+            }
+            _ => continue,
+        }
+
+        let offset = ph.u32(4)?;
+        let vaddr = ph.u32(8)?;
+        let filesz = ph.u32(16)?;
+        let memsz = ph.u32(20)?;
+        let flags = ph.u32(24)?;
+        if filesz > memsz {
+            return Err(Error::BadElf);
+        }
+        zeroed = add(zeroed, memsz - filesz)?;
+        if zeroed > MAX_ZERO_FILL {
+            return Err(Error::BadElf);
+        }
+        end = end.max(add(vaddr, memsz)?);
+        if phdr.is_none() && offset <= phoff && phoff - offset < filesz {
+            phdr = Some(add(vaddr, phoff - offset)?);
+        }
+
+        segments.push((vaddr, r.bytes(offset, filesz)?, memsz, Perms {
+            read: flags & PF_R != 0,
+            write: flags & PF_W != 0,
+            exec: flags & PF_X != 0,
+        }));
+    }
+
+    // Nothing is written until every header has been checked. Copy the
+    // file contents, then zero the rest (.bss).
+    for &(vaddr, contents, memsz, _) in &segments {
+        let filesz = contents.len() as u32;
+        machine.write_bytes(vaddr, contents)?;
+        machine.discard(vaddr.wrapping_add(filesz), memsz - filesz)?;
+    }
+
+    // Only once everything is written, since segments may share pages.
+    for (vaddr, _, memsz, perms) in segments {
+        machine.protect(vaddr, memsz, perms)?;
+    }
+
+    Ok((end, phdr.unwrap_or(0)))
+}
+
+fn symbol_kind(info: u8) -> SymbolKind {
+    match info & 0xF {
+        0 => SymbolKind::NoType,
+        1 => SymbolKind::Object,
+        2 => SymbolKind::Func,
+        3 => SymbolKind::Section,
+        4 => SymbolKind::File,
+        n => SymbolKind::Other(n),
+    }
+}
+
+fn read_symbols(r: &Reader) -> Result<Vec<Symbol>> {
+    let shoff = r.u32(32)?;
+    let shentsize = r.u16(46)? as u32;
+    let shnum = r.u16(48)? as u32;
+
+    let mut symbols = vec![];
+    for i in 0..shnum {
+        let sh = r.sub(index(shoff, i, shentsize)?, 40)?;
+        if sh.u32(4)? != SHT_SYMTAB {
+            continue;
+        }
+
+        let offset = sh.u32(16)?;
+        let size = sh.u32(20)?;
+        let entsize = sh.u32(36)?;
+        if entsize < 16 {
+            return Err(Error::BadElf);
+        }
+
+        // sh_link names the string table.
+        let strtab = r.sub(index(shoff, sh.u32(24)?, shentsize)?, 40)?;
+        let stroff = strtab.u32(16)?;
+
+        // Entry 0 is always the undefined symbol.
+        for j in 1..(size / entsize) {
+            let sym = r.sub(index(offset, j, entsize)?, 16)?;
+            let name = r.c_str(add(stroff, sym.u32(0)?)?)?;
+            if name.is_empty() {
+                continue;
+            }
+
+            let info = sym.u8(12)?;
+            symbols.push(Symbol {
+                name: name.to_owned(),
+                value: sym.u32(4)?,
+                size: sym.u32(8)?,
+                kind: symbol_kind(info),
+                global: info >> 4 != 0,
+            });
+        }
+    }
+
+    Ok(symbols)
+}
+
+/// Load a statically linked ELF32 RISC-V executable.
+///
+/// Every `PT_LOAD` segment is copied to its virtual address, with the part
+/// past the end of the file data zero-filled, and then protected according
+/// to its flags if the bus supports permissions. `pc` is set to the entry
+/// point. Files asking for more than 256 MiB of zero-filled memory in all
+/// are rejected, and bad program headers are found before anything is
+/// written. The returned image holds the symbol table, which is empty if
+/// the file was stripped.
+pub fn load<B: Bus>(machine: &mut Machine<B>, data: &[u8]) -> Result<Image> {
+    let r = Reader { data };
+    check_header(&r)?;
+    let (end, phdr) = load_segments(&r, machine)?;
+
+    let entry = r.u32(24)?;
+    machine.pc = entry;
+
+    Ok(Image {
+        entry,
+        end,
+        phdr,
+        phnum: r.u16(44)? as u32,
+        symbols: read_symbols(&r)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, SymbolKind};
+    use emu::{Machine, StepOutcome};
+    use emu::bus::{Access, Perms};
+    use emu::sparse::{SparseMemory, UnmappedPolicy};
+    use Error;
+
+    fn push16(v: &mut Vec<u8>, x: u16) {
+        v.extend_from_slice(&[x as u8, (x >> 8) as u8]);
+    }
+
+    fn push32(v: &mut Vec<u8>, x: u32) {
+        push16(v, x as u16);
+        push16(v, (x >> 16) as u16);
+    }
+
+    // An executable with one segment (4 bytes of code, 8 of bss) at 0x100,
+    // and a symbol table holding "_start".
+    fn tiny_elf(flags: u32) -> Vec<u8> {
+        let mut v = b"\x7fELF\x01\x01\x01".to_vec();
+        v.resize(16, 0);
+        push16(&mut v, 2);        // e_type
+        push16(&mut v, 243);      // e_machine
+        push32(&mut v, 1);        // e_version
+        push32(&mut v, 0x100);    // e_entry
+        push32(&mut v, 52);       // e_phoff
+        push32(&mut v, 88);       // e_shoff
+        push32(&mut v, flags);    // e_flags
+        push16(&mut v, 52);       // e_ehsize
+        push16(&mut v, 32);       // e_phentsize
+        push16(&mut v, 1);        // e_phnum
+        push16(&mut v, 40);       // e_shentsize
+        push16(&mut v, 3);        // e_shnum
+        push16(&mut v, 0);        // e_shstrndx
+
+        for &x in &[1, 84, 0x100, 0x100, 4, 12, 5, 4] {
+            push32(&mut v, x);
+        }
+        push32(&mut v, 0x00000073);
+
+        v.resize(88 + 40, 0);
+        for &x in &[0, 2, 0, 0, 248, 32, 2, 0, 4, 16] {
+            push32(&mut v, x);
+        }
+        for &x in &[0, 3, 0, 0, 280, 8, 0, 0, 1, 0] {
+            push32(&mut v, x);
+        }
+
+        v.resize(248 + 16, 0);
+        for &x in &[1, 0x100, 4] {
+            push32(&mut v, x);
+        }
+        v.extend_from_slice(&[0x12, 0, 1, 0]);
+        v.extend_from_slice(b"\0_start\0");
+        v
+    }
+
+    #[test]
+    fn test_load() {
+        let mut m = Machine::with_memory(0x200);
+        m.store32(0x104, !0).unwrap();
+        let image = load(&mut m, &tiny_elf(1)).unwrap();
+
+        assert_eq!(0x100, m.pc);
+        assert_eq!(0x10c, image.end);
+        assert_eq!(0x00000073, m.load32(0x100).unwrap());
+        assert_eq!(0, m.load32(0x104).unwrap());
+
+        let start = image.symbol("_start").unwrap();
+        assert_eq!((0x100, 4, SymbolKind::Func, true),
+                   (start.value, start.size, start.kind, start.global));
+    }
+
+    #[test]
+    fn test_protect_segments() {
+        let mut mem = SparseMemory::new(UnmappedPolicy::Fault);
+        mem.default_perms = Perms::RW;
+        let mut m = Machine::new(mem);
+        load(&mut m, &tiny_elf(1)).unwrap();
+
+        assert_eq!(Perms::RX, m.memory.perms(0x100));
+        match m.store8(0x100, 0) {
+            Err(Error::AccessFault(Access::Store)) => (),
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(StepOutcome::Syscall, m.step().unwrap());
+    }
+
+    fn patch32(v: &mut [u8], off: usize, x: u32) {
+        let mut b = vec![];
+        push32(&mut b, x);
+        v[off..off + 4].copy_from_slice(&b);
+    }
+
+    #[test]
+    fn test_reject_malformed() {
+        let bad = |off, x| {
+            let mut elf = tiny_elf(1);
+            patch32(&mut elf, off, x);
+            let mut m = Machine::new(SparseMemory::new(UnmappedPolicy::ReadZero));
+            match load(&mut m, &elf) {
+                Err(Error::BadElf) => (),
+                r => panic!("{:#x}: {:?}", off, r.map(|i| i.entry)),
+            }
+        };
+
+        bad(32, 0xffff_fff0);      // e_shoff
+        bad(52 + 8, 0xffff_fffc);  // p_vaddr
+        bad(52 + 20, 0xffff_0000); // p_memsz
+        bad(128 + 24, 0x0400_0000);  // sh_link
+        bad(248 + 16, 0xffff_fffe);  // st_name
+
+        // Nothing is written if a later program header runs off the end.
+        let mut elf = tiny_elf(1);
+        elf[44] = 100;  // e_phnum
+        let mut m = Machine::new(SparseMemory::new(UnmappedPolicy::ReadZero));
+        assert!(load(&mut m, &elf).is_err());
+        assert_eq!(0, m.memory.mapped_pages());
+    }
+
+    #[test]
+    fn test_reject_hard_float() {
+        let mut m = Machine::with_memory(0x200);
+        assert!(load(&mut m, &tiny_elf(0x4)).is_err());
+    }
+
+    #[test]
+    fn test_reject_rve() {
+        let mut m = Machine::with_memory(0x200);
+        assert!(load(&mut m, &tiny_elf(0x8)).is_err());
+    }
+}
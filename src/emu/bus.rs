@@ -0,0 +1,514 @@
+//! What the CPU sees when it loads and stores.
+
+use std::io::{self, Read, Write};
+use std::ops::{Deref, DerefMut};
+
+use emu::snapshot::{self, read_perms, read_u32, read_u64, write_perms, write_u32, write_u64};
+use {Error, Result};
+
+/// The kind of memory access, as reported by `Error::AccessFault`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+/// Read, write and execute permissions for a range of memory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Perms {
+    pub read: bool,
+    pub write: bool,
+    pub exec: bool,
+}
+
+impl Perms {
+    pub const NONE: Perms = Perms { read: false, write: false, exec: false };
+    pub const R: Perms = Perms { read: true, write: false, exec: false };
+    pub const RW: Perms = Perms { read: true, write: true, exec: false };
+    pub const RX: Perms = Perms { read: true, write: false, exec: true };
+    pub const RWX: Perms = Perms { read: true, write: true, exec: true };
+
+    pub fn allows(self, access: Access) -> bool {
+        match access {
+            Access::Fetch => self.exec,
+            Access::Load => self.read,
+            Access::Store => self.write,
+        }
+    }
+
+    /// Fail with `Error::AccessFault` unless `access` is allowed.
+    pub fn check(self, access: Access) -> Result<()> {
+        if !self.allows(access) {
+            return Err(Error::AccessFault(access));
+        }
+        Ok(())
+    }
+}
+
+/// An address space, or a device within one.
+///
+/// Only the byte accessors are required. The wider ones default to
+/// little-endian sequences of byte accesses, which is enough for simple
+/// devices; memories override them for speed. Loads take `&mut self`
+/// because reading a device register may have side effects.
+///
+/// Buses that enforce permissions fail with `Error::AccessFault`, naming
+/// the kind of access that was refused. Instruction fetches go through
+/// `fetch16` so that they can be told apart from loads.
+pub trait Bus {
+    fn load8(&mut self, addr: u32) -> Result<u8>;
+    fn store8(&mut self, addr: u32, val: u8) -> Result<()>;
+
+    fn load16(&mut self, addr: u32) -> Result<u16> {
+        Ok(self.load8(addr)? as u16
+           | ((self.load8(addr.wrapping_add(1))? as u16) << 8))
+    }
+
+    fn load32(&mut self, addr: u32) -> Result<u32> {
+        Ok(self.load16(addr)? as u32
+           | ((self.load16(addr.wrapping_add(2))? as u32) << 16))
+    }
+
+    fn store16(&mut self, addr: u32, val: u16) -> Result<()> {
+        self.store8(addr, val as u8)?;
+        self.store8(addr.wrapping_add(1), (val >> 8) as u8)
+    }
+
+    fn store32(&mut self, addr: u32, val: u32) -> Result<()> {
+        self.store16(addr, val as u16)?;
+        self.store16(addr.wrapping_add(2), (val >> 16) as u16)
+    }
+
+    fn fetch16(&mut self, addr: u32) -> Result<u16> {
+        self.load16(addr)
+    }
+
+    /// Set the permissions of `len` bytes at `addr`, at whatever
+    /// granularity the bus supports. Buses without permissions accept and
+    /// ignore this.
+    fn protect(&mut self, _addr: u32, _len: u32, _perms: Perms) -> Result<()> {
+        Ok(())
+    }
+
+    /// Make `len` bytes at `addr` read as zero, as memory mapped afresh
+    /// does. The default stores zeroes, stopping at the first failure;
+    /// memory allocated on demand can free it instead.
+    fn discard(&mut self, addr: u32, len: u32) -> Result<()> {
+        for i in 0..len {
+            self.store8(addr.wrapping_add(i), 0)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the instruction fetched from `addr` can be decoded once and
+    /// reused, because nothing but stores through the bus can change it.
+    /// True for plain memory; devices default to false.
+    fn cacheable(&self, _addr: u32) -> bool {
+        false
+    }
+
+    /// Hold off other harts sharing the bus until `end_atomic`, so that
+    /// LR, SC and AMOs are indivisible. Buses with one hart do nothing.
+    fn begin_atomic(&mut self) {}
+
+    fn end_atomic(&mut self) {}
+
+    /// Note an LR reservation on the word at `addr`, for stores from
+    /// other harts sharing the bus to break.
+    fn reserve(&mut self, _addr: u32) {}
+
+    /// Whether the reservation noted by `reserve` still stands. A machine
+    /// tracks its own stores; with one hart there are no others.
+    fn reserved(&mut self, _addr: u32) -> bool {
+        true
+    }
+
+    /// Write whatever state `restore_state` needs to put the bus back as
+    /// it is now, for `Machine::save_snapshot`. Devices without state,
+    /// or whose state is fixed when they are built, write nothing.
+    fn save_state(&self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Read back what `save_state` wrote, failing with
+    /// `io::ErrorKind::InvalidData` if it doesn't fit this bus.
+    fn restore_state(&mut self, _input: &mut dyn Read) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The index of the first byte of an access, if all of it is in bounds.
+fn index(addr: u32, len: u32, size: usize) -> Result<usize> {
+    match addr.checked_add(len - 1) {
+        Some(last) if (last as usize) < size => Ok(addr as usize),
+        _ => Err(Error::MemoryOutOfBounds),
+    }
+}
+
+/// Flat, zero-initialized RAM starting at address 0.
+///
+/// This derefs to its contents, so it can be sliced and indexed directly.
+#[derive(Clone, Debug)]
+pub struct Ram {
+    bytes: Vec<u8>,
+}
+
+impl Ram {
+    pub fn new(size: usize) -> Ram {
+        Ram {
+            bytes: vec![0; size],
+        }
+    }
+}
+
+impl Deref for Ram {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl DerefMut for Ram {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+}
+
+impl Bus for Ram {
+    fn load8(&mut self, addr: u32) -> Result<u8> {
+        let i = index(addr, 1, self.bytes.len())?;
+        Ok(self.bytes[i])
+    }
+
+    fn load16(&mut self, addr: u32) -> Result<u16> {
+        let i = index(addr, 2, self.bytes.len())?;
+        Ok(self.bytes[i] as u16
+           | ((self.bytes[i+1] as u16) << 8))
+    }
+
+    fn load32(&mut self, addr: u32) -> Result<u32> {
+        let i = index(addr, 4, self.bytes.len())?;
+        Ok(self.bytes[i] as u32
+           | ((self.bytes[i+1] as u32) << 8)
+           | ((self.bytes[i+2] as u32) << 16)
+           | ((self.bytes[i+3] as u32) << 24))
+    }
+
+    fn store8(&mut self, addr: u32, val: u8) -> Result<()> {
+        let i = index(addr, 1, self.bytes.len())?;
+        self.bytes[i] = val;
+        Ok(())
+    }
+
+    fn store16(&mut self, addr: u32, val: u16) -> Result<()> {
+        let i = index(addr, 2, self.bytes.len())?;
+        self.bytes[i] = val as u8;
+        self.bytes[i+1] = (val >> 8) as u8;
+        Ok(())
+    }
+
+    fn store32(&mut self, addr: u32, val: u32) -> Result<()> {
+        let i = index(addr, 4, self.bytes.len())?;
+        self.bytes[i] = val as u8;
+        self.bytes[i+1] = (val >> 8) as u8;
+        self.bytes[i+2] = (val >> 16) as u8;
+        self.bytes[i+3] = (val >> 24) as u8;
+        Ok(())
+    }
+
+    fn cacheable(&self, _addr: u32) -> bool {
+        true
+    }
+
+    /// The size, then the contents.
+    fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_u64(out, self.bytes.len() as u64)?;
+        out.write_all(&self.bytes)
+    }
+
+    fn restore_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        if read_u64(input)? != self.bytes.len() as u64 {
+            return Err(snapshot::invalid("RAM size differs"));
+        }
+        input.read_exact(&mut self.bytes)
+    }
+}
+
+/// Read-only memory starting at address 0. Stores fail with
+/// `Error::AccessFault(Access::Store)`.
+#[derive(Clone, Debug)]
+pub struct Rom {
+    ram: Ram,
+}
+
+impl Rom {
+    pub fn new(contents: &[u8]) -> Rom {
+        Rom {
+            ram: Ram {
+                bytes: contents.to_vec(),
+            },
+        }
+    }
+}
+
+impl Deref for Rom {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.ram
+    }
+}
+
+impl Bus for Rom {
+    fn load8(&mut self, addr: u32) -> Result<u8> {
+        self.ram.load8(addr)
+    }
+
+    fn load16(&mut self, addr: u32) -> Result<u16> {
+        self.ram.load16(addr)
+    }
+
+    fn load32(&mut self, addr: u32) -> Result<u32> {
+        self.ram.load32(addr)
+    }
+
+    fn store8(&mut self, addr: u32, _: u8) -> Result<()> {
+        index(addr, 1, self.ram.len())?;
+        Err(Error::AccessFault(Access::Store))
+    }
+
+    fn cacheable(&self, _addr: u32) -> bool {
+        true
+    }
+}
+
+struct Region {
+    base: u32,
+    size: u32,
+    perms: Perms,
+    device: Box<dyn Bus + Send>,
+}
+
+/// An address space built from devices mapped at arbitrary base addresses.
+///
+/// Each device sees addresses relative to its own base. An access must
+/// fall entirely within one region; anything else, including accesses to
+/// unmapped addresses, fails with `Error::MemoryOutOfBounds`. Each region
+/// also has permissions, checked before the device sees the access.
+#[derive(Default)]
+pub struct SystemBus {
+    regions: Vec<Region>,
+}
+
+impl SystemBus {
+    pub fn new() -> SystemBus {
+        SystemBus {
+            regions: vec![],
+        }
+    }
+
+    /// Map `device` at `base`, covering `size` bytes.
+    ///
+    /// Fails with `Error::OverlappingRegion` if the new region would
+    /// overlap an existing one or wrap around the address space.
+    pub fn map(&mut self, base: u32, size: u32, perms: Perms,
+               device: Box<dyn Bus + Send>) -> Result<()> {
+        let end = base as u64 + size as u64;
+        if size == 0 || end > 1 << 32 {
+            return Err(Error::OverlappingRegion);
+        }
+        for r in &self.regions {
+            if (base as u64) < r.base as u64 + r.size as u64 && (r.base as u64) < end {
+                return Err(Error::OverlappingRegion);
+            }
+        }
+
+        self.regions.push(Region { base, size, perms, device });
+        Ok(())
+    }
+
+    pub fn map_ram(&mut self, base: u32, size: u32) -> Result<()> {
+        self.map(base, size, Perms::RWX, Box::new(Ram::new(size as usize)))
+    }
+
+    pub fn map_rom(&mut self, base: u32, contents: &[u8]) -> Result<()> {
+        self.map(base, contents.len() as u32, Perms::RX, Box::new(Rom::new(contents)))
+    }
+
+    /// Change the permissions of the region mapped at `base`. A `Machine`
+    /// using the bus should then flush its decode cache, if it is on.
+    pub fn set_perms(&mut self, base: u32, perms: Perms) -> Result<()> {
+        match self.regions.iter_mut().find(|r| r.base == base) {
+            Some(r) => {
+                r.perms = perms;
+                Ok(())
+            }
+            None => Err(Error::MemoryOutOfBounds),
+        }
+    }
+
+    // Find the region holding the whole access, and the offset into it.
+    fn region(&mut self, addr: u32, len: u32) -> Result<&mut Region> {
+        for r in &mut self.regions {
+            let offset = addr.wrapping_sub(r.base);
+            if offset < r.size && len - 1 <= r.size - 1 - offset {
+                return Ok(r);
+            }
+        }
+        Err(Error::MemoryOutOfBounds)
+    }
+
+    fn route(&mut self, addr: u32, len: u32, access: Access)
+        -> Result<(&mut (dyn Bus + Send), u32)>
+    {
+        let r = self.region(addr, len)?;
+        r.perms.check(access)?;
+        Ok((&mut *r.device, addr.wrapping_sub(r.base)))
+    }
+}
+
+impl Bus for SystemBus {
+    fn load8(&mut self, addr: u32) -> Result<u8> {
+        let (dev, offset) = self.route(addr, 1, Access::Load)?;
+        dev.load8(offset)
+    }
+
+    fn load16(&mut self, addr: u32) -> Result<u16> {
+        let (dev, offset) = self.route(addr, 2, Access::Load)?;
+        dev.load16(offset)
+    }
+
+    fn load32(&mut self, addr: u32) -> Result<u32> {
+        let (dev, offset) = self.route(addr, 4, Access::Load)?;
+        dev.load32(offset)
+    }
+
+    fn store8(&mut self, addr: u32, val: u8) -> Result<()> {
+        let (dev, offset) = self.route(addr, 1, Access::Store)?;
+        dev.store8(offset, val)
+    }
+
+    fn store16(&mut self, addr: u32, val: u16) -> Result<()> {
+        let (dev, offset) = self.route(addr, 2, Access::Store)?;
+        dev.store16(offset, val)
+    }
+
+    fn store32(&mut self, addr: u32, val: u32) -> Result<()> {
+        let (dev, offset) = self.route(addr, 4, Access::Store)?;
+        dev.store32(offset, val)
+    }
+
+    fn fetch16(&mut self, addr: u32) -> Result<u16> {
+        let (dev, offset) = self.route(addr, 2, Access::Fetch)?;
+        dev.fetch16(offset)
+    }
+
+    /// Forwarded to the device holding the range, which must lie within a
+    /// single region. Use `set_perms` to change a whole region.
+    fn protect(&mut self, addr: u32, len: u32, perms: Perms) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let r = self.region(addr, len)?;
+        r.device.protect(addr.wrapping_sub(r.base), len, perms)
+    }
+
+    /// Forwarded like `protect`, regardless of the region's permissions.
+    fn discard(&mut self, addr: u32, len: u32) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let r = self.region(addr, len)?;
+        r.device.discard(addr.wrapping_sub(r.base), len)
+    }
+
+    fn cacheable(&self, addr: u32) -> bool {
+        self.regions.iter().any(|r| {
+            let offset = addr.wrapping_sub(r.base);
+            offset < r.size && r.device.cacheable(offset)
+        })
+    }
+
+    /// The number of regions, then the base, size and permissions of
+    /// each followed by the state of its device.
+    fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_u32(out, self.regions.len() as u32)?;
+        for r in &self.regions {
+            write_u32(out, r.base)?;
+            write_u32(out, r.size)?;
+            write_perms(out, r.perms)?;
+            r.device.save_state(out)?;
+        }
+        Ok(())
+    }
+
+    fn restore_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        if read_u32(input)? != self.regions.len() as u32 {
+            return Err(snapshot::invalid("bus layout differs"));
+        }
+        for r in &mut self.regions {
+            if read_u32(input)? != r.base || read_u32(input)? != r.size {
+                return Err(snapshot::invalid("bus layout differs"));
+            }
+            r.perms = read_perms(input)?;
+            r.device.restore_state(input)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Access, Bus, Perms, SystemBus};
+    use {Error, Result};
+
+    // A device that counts its reads.
+    struct Counter(u8);
+
+    impl Bus for Counter {
+        fn load8(&mut self, _: u32) -> Result<u8> {
+            self.0 += 1;
+            Ok(self.0)
+        }
+
+        fn store8(&mut self, _: u32, val: u8) -> Result<()> {
+            self.0 = val;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_system_bus() {
+        let mut bus = SystemBus::new();
+        bus.map_rom(0x1000, &[1, 2, 3, 4]).unwrap();
+        bus.map_ram(0x8000_0000, 0x1000).unwrap();
+        bus.map(0xFFFF_FFFC, 4, Perms::RW, Box::new(Counter(0))).unwrap();
+
+        assert_eq!(0x04030201, bus.load32(0x1000).unwrap());
+        match bus.store8(0x1000, 0) {
+            Err(Error::AccessFault(Access::Store)) => (),
+            r => panic!("{:?}", r),
+        }
+
+        bus.store32(0x8000_0FFC, 0xdeadbeef).unwrap();
+        assert_eq!(0xdeadbeef, bus.load32(0x8000_0FFC).unwrap());
+        assert!(bus.load32(0x8000_0FFE).is_err());
+        assert!(bus.load8(0x2000).is_err());
+
+        bus.store8(0xFFFF_FFFF, 7).unwrap();
+        assert_eq!(8, bus.load8(0xFFFF_FFFF).unwrap());
+        assert_eq!(0x0A09, bus.load16(0xFFFF_FFFE).unwrap());
+        match bus.fetch16(0xFFFF_FFFE) {
+            Err(Error::AccessFault(Access::Fetch)) => (),
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(0x0201, bus.fetch16(0x1000).unwrap());
+
+        match bus.map_ram(0x8000_0800, 0x1000) {
+            Err(Error::OverlappingRegion) => (),
+            r => panic!("{:?}", r),
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! Machine-mode control and status registers.
+
+use std::io::{self, Read, Write};
+
+use decode::Csr;
+use emu::snapshot::{invalid, read_u32, read_u64, write_u32, write_u64};
+use {Error, Result};
+
+pub const MSTATUS:   Csr = 0x300;
+pub const MISA:      Csr = 0x301;
+pub const MIE:       Csr = 0x304;
+pub const MTVEC:     Csr = 0x305;
+pub const MSTATUSH:  Csr = 0x310;
+pub const MSCRATCH:  Csr = 0x340;
+pub const MEPC:      Csr = 0x341;
+pub const MCAUSE:    Csr = 0x342;
+pub const MTVAL:     Csr = 0x343;
+pub const MIP:       Csr = 0x344;
+pub const MCYCLE:    Csr = 0xB00;
+pub const MINSTRET:  Csr = 0xB02;
+pub const MCYCLEH:   Csr = 0xB80;
+pub const MINSTRETH: Csr = 0xB82;
+pub const CYCLE:     Csr = 0xC00;
+pub const INSTRET:   Csr = 0xC02;
+pub const CYCLEH:    Csr = 0xC80;
+pub const INSTRETH:  Csr = 0xC82;
+pub const MVENDORID: Csr = 0xF11;
+pub const MARCHID:   Csr = 0xF12;
+pub const MIMPID:    Csr = 0xF13;
+pub const MHARTID:   Csr = 0xF14;
+
+static NAMES: &[(Csr, &str)] = &[
+    (MSTATUS, "mstatus"), (MISA, "misa"), (MIE, "mie"), (MTVEC, "mtvec"),
+    (MSTATUSH, "mstatush"), (MSCRATCH, "mscratch"), (MEPC, "mepc"),
+    (MCAUSE, "mcause"), (MTVAL, "mtval"), (MIP, "mip"),
+    (MCYCLE, "mcycle"), (MINSTRET, "minstret"),
+    (MCYCLEH, "mcycleh"), (MINSTRETH, "minstreth"),
+    (CYCLE, "cycle"), (INSTRET, "instret"),
+    (CYCLEH, "cycleh"), (INSTRETH, "instreth"),
+    (MVENDORID, "mvendorid"), (MARCHID, "marchid"),
+    (MIMPID, "mimpid"), (MHARTID, "mhartid"),
+];
+
+/// The assembler name of a CSR that this emulator implements.
+pub fn name(csr: Csr) -> Option<&'static str> {
+    NAMES.iter().find(|&&(n, _)| n == csr).map(|&(_, name)| name)
+}
+
+pub fn from_name(name: &str) -> Option<Csr> {
+    NAMES.iter().find(|&&(_, s)| s == name).map(|&(n, _)| n)
+}
+
+pub const MSTATUS_MIE:  u32 = 1 << 3;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_MPP:  u32 = 0b11 << 11;
+
+/// MXL = 1 (RV32), with the A, C, I and M extension bits set.
+pub const MISA_VALUE: u32 = (1 << 30) | (1 << 12) | (1 << 8) | (1 << 2) | 1;
+
+// Interrupt enable bits that exist on a machine-mode-only hart.
+const MIE_MASK: u32 = 0b1000_1000_1000;
+
+/// The CSRs of a single hart.
+///
+/// Writes go through `write`, which applies each register's WARL rules:
+/// unsupported field values are replaced by legal ones instead of being
+/// rejected. Only machine mode exists, so `mstatus.MPP` always reads as M.
+#[derive(Clone, Debug)]
+pub struct CsrFile {
+    mstatus: u32,
+    mie: u32,
+    mtvec: u32,
+    mscratch: u32,
+    mepc: u32,
+    mcause: u32,
+    mtval: u32,
+    mcycle: u64,
+    minstret: u64,
+    mhartid: u32,
+
+    // Set when the counters are written, so that the next `retire` leaves
+    // them alone.
+    mcycle_written: bool,
+    minstret_written: bool,
+}
+
+fn set_lo(counter: &mut u64, val: u32) {
+    *counter = (*counter & !0xFFFF_FFFF) | val as u64;
+}
+
+fn set_hi(counter: &mut u64, val: u32) {
+    *counter = (*counter & 0xFFFF_FFFF) | ((val as u64) << 32);
+}
+
+impl CsrFile {
+    pub fn new(hart_id: u32) -> CsrFile {
+        CsrFile {
+            mstatus: MSTATUS_MPP,
+            mie: 0,
+            mtvec: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            mcycle: 0,
+            minstret: 0,
+            mhartid: hart_id,
+            mcycle_written: false,
+            minstret_written: false,
+        }
+    }
+
+    /// Is this CSR read-only, judging by its address?
+    pub fn is_read_only(csr: Csr) -> bool {
+        (csr >> 10) & 0b11 == 0b11
+    }
+
+    pub fn read(&self, csr: Csr) -> Result<u32> {
+        Ok(match csr {
+            MSTATUS => self.mstatus,
+            MISA => MISA_VALUE,
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MSTATUSH => 0,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => 0,
+            MCYCLE | CYCLE => self.mcycle as u32,
+            MINSTRET | INSTRET => self.minstret as u32,
+            MCYCLEH | CYCLEH => (self.mcycle >> 32) as u32,
+            MINSTRETH | INSTRETH => (self.minstret >> 32) as u32,
+            MVENDORID | MARCHID | MIMPID => 0,
+            MHARTID => self.mhartid,
+
+            _ => return Err(Error::BadCsr),
+        })
+    }
+
+    pub fn write(&mut self, csr: Csr, val: u32) -> Result<()> {
+        // Unknown CSRs are reported as such, even if read-only.
+        self.read(csr)?;
+        if CsrFile::is_read_only(csr) {
+            return Err(Error::ReadOnlyCsr);
+        }
+
+        match csr {
+            MSTATUS => {
+                self.mstatus = (val & (MSTATUS_MIE | MSTATUS_MPIE)) | MSTATUS_MPP;
+            }
+
+            MIE => self.mie = val & MIE_MASK,
+
+            // Modes other than direct (0) and vectored (1) are reserved,
+            // so keep the old mode.
+            MTVEC => {
+                let mode = if val & 0b11 < 2 { val & 0b11 } else { self.mtvec & 0b11 };
+                self.mtvec = (val & !0b11) | mode;
+            }
+
+            MSCRATCH => self.mscratch = val,
+
+            // With compressed instructions, only bit 0 is always zero.
+            MEPC => self.mepc = val & !1,
+
+            MCAUSE => self.mcause = val,
+            MTVAL => self.mtval = val,
+
+            MCYCLE => {
+                set_lo(&mut self.mcycle, val);
+                self.mcycle_written = true;
+            }
+            MCYCLEH => {
+                set_hi(&mut self.mcycle, val);
+                self.mcycle_written = true;
+            }
+            MINSTRET => {
+                set_lo(&mut self.minstret, val);
+                self.minstret_written = true;
+            }
+            MINSTRETH => {
+                set_hi(&mut self.minstret, val);
+                self.minstret_written = true;
+            }
+
+            // misa, mstatush and mip have no writable fields.
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /// For `Machine::save_snapshot`.
+    pub fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+        for &r in &[self.mstatus, self.mie, self.mtvec, self.mscratch,
+                    self.mepc, self.mcause, self.mtval] {
+            write_u32(out, r)?;
+        }
+        write_u64(out, self.mcycle)?;
+        write_u64(out, self.minstret)?;
+        write_u32(out, self.mhartid)
+    }
+
+    /// For `Machine::restore_snapshot`. Registers go through the same WARL
+    /// rules as `write`, and a value they would change is refused with
+    /// `io::ErrorKind::InvalidData`, leaving the CSRs as they were.
+    pub fn restore_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        let mut csrs = self.clone();
+        for &csr in &[MSTATUS, MIE, MTVEC, MSCRATCH, MEPC, MCAUSE, MTVAL] {
+            let val = read_u32(input)?;
+            csrs.write(csr, val).map_err(|_| invalid("bad CSR"))?;
+            if csrs.read(csr).ok() != Some(val) {
+                return Err(invalid("illegal CSR value"));
+            }
+        }
+        csrs.mcycle = read_u64(input)?;
+        csrs.minstret = read_u64(input)?;
+        csrs.mhartid = read_u32(input)?;
+        *self = csrs;
+        Ok(())
+    }
+
+    /// Account for one retired instruction. A counter written since the
+    /// last call keeps the written value, as an explicit write takes the
+    /// place of the writing instruction's increment.
+    pub fn retire(&mut self) {
+        if !self.mcycle_written {
+            self.mcycle = self.mcycle.wrapping_add(1);
+        }
+        if !self.minstret_written {
+            self.minstret = self.minstret.wrapping_add(1);
+        }
+        self.mcycle_written = false;
+        self.minstret_written = false;
+    }
+
+    /// Account for `count` retired instructions at once.
+    pub fn retire_many(&mut self, count: u64) {
+        self.mcycle = self.mcycle.wrapping_add(count);
+        self.minstret = self.minstret.wrapping_add(count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Error;
+
+    #[test]
+    fn test_misa() {
+        assert_eq!(0x40001105, MISA_VALUE);
+    }
+
+    #[test]
+    fn test_warl() {
+        let mut csrs = CsrFile::new(3);
+        csrs.write(MSTATUS, !0).unwrap();
+        assert_eq!(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP, csrs.read(MSTATUS).unwrap());
+
+        csrs.write(MTVEC, 0x1001).unwrap();
+        csrs.write(MTVEC, 0x2003).unwrap();
+        assert_eq!(0x2001, csrs.read(MTVEC).unwrap());
+
+        csrs.write(MEPC, 0x1003).unwrap();
+        assert_eq!(0x1002, csrs.read(MEPC).unwrap());
+
+        csrs.write(MISA, 0).unwrap();
+        assert_eq!(MISA_VALUE, csrs.read(MISA).unwrap());
+    }
+
+    #[test]
+    fn test_restore_state() {
+        let mut csrs = CsrFile::new(0);
+        csrs.write(MTVEC, 0x1001).unwrap();
+        csrs.write(MEPC, 0x2000).unwrap();
+        let mut state = vec![];
+        csrs.save_state(&mut state).unwrap();
+
+        let mut restored = CsrFile::new(0);
+        restored.restore_state(&mut &state[..]).unwrap();
+        assert_eq!(0x1001, restored.read(MTVEC).unwrap());
+        assert_eq!(0x2000, restored.read(MEPC).unwrap());
+
+        // mstatus with MPP = U, a reserved mtvec mode and an odd mepc.
+        for &(offset, val) in &[(0, MSTATUS_MIE), (8, 0x1003), (16, 0x2001)] {
+            let mut bad = state.clone();
+            bad[offset..offset + 4].copy_from_slice(&u32::to_le_bytes(val));
+            let err = restored.restore_state(&mut &bad[..]).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+        assert_eq!(0x2000, restored.read(MEPC).unwrap());
+    }
+
+    #[test]
+    fn test_read_only_and_unknown() {
+        let mut csrs = CsrFile::new(3);
+        assert_eq!(3, csrs.read(MHARTID).unwrap());
+        match csrs.write(MHARTID, 0) {
+            Err(Error::ReadOnlyCsr) => (),
+            r => panic!("{:?}", r),
+        }
+        match csrs.read(0x7FF) {
+            Err(Error::BadCsr) => (),
+            r => panic!("{:?}", r),
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! Host functions the guest can call with ECALL.
+
+use decode::Reg;
+use emu::Machine;
+use emu::bus::Bus;
+use Result;
+
+// A handler that can be cloned along with its machine.
+trait EcallFn<B>: Send {
+    fn call(&mut self, call: &mut Ecall<B>);
+    fn clone_box(&self) -> Box<dyn EcallFn<B>>;
+}
+
+impl<B, F> EcallFn<B> for F
+    where F: FnMut(&mut Ecall<B>) + Clone + Send + 'static,
+{
+    fn call(&mut self, call: &mut Ecall<B>) {
+        self(call)
+    }
+
+    fn clone_box(&self) -> Box<dyn EcallFn<B>> {
+        Box::new(self.clone())
+    }
+}
+
+// `None` while the handler is running, so that it can step the machine
+// without reentering itself.
+pub(super) struct EcallHandler<B>(Option<Box<dyn EcallFn<B>>>);
+
+impl<B> Clone for EcallHandler<B> {
+    fn clone(&self) -> EcallHandler<B> {
+        EcallHandler(self.0.as_ref().map(|f| f.clone_box()))
+    }
+}
+
+/// A guest's call into a handler registered with
+/// `Machine::register_ecall`.
+///
+/// Following the RISC-V calling convention, the call number is in a7 and
+/// the arguments are in a0 to a6. `pc` is already past the ECALL.
+pub struct Ecall<'a, B: 'a> {
+    machine: &'a mut Machine<B>,
+}
+
+impl<'a, B: Bus> Ecall<'a, B> {
+    /// The call number, from a7.
+    pub fn number(&self) -> u32 {
+        self.machine.get_reg(Reg::a7())
+    }
+
+    /// Argument `n`, from a0 to a6.
+    pub fn arg(&self, n: u32) -> u32 {
+        assert!(n < 7, "ECALL argument {} out of range", n);
+        self.machine.get_reg(Reg::new(10 + n).unwrap())
+    }
+
+    pub fn arg_i32(&self, n: u32) -> i32 {
+        self.arg(n) as i32
+    }
+
+    /// A 64-bit argument passed in a pair of registers, low half first.
+    pub fn arg_u64(&self, n: u32) -> u64 {
+        self.arg(n) as u64 | (self.arg(n + 1) as u64) << 32
+    }
+
+    pub fn read_bytes(&mut self, addr: u32, buf: &mut [u8]) -> Result<()> {
+        self.machine.read_bytes(addr, buf)
+    }
+
+    pub fn write_bytes(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        self.machine.write_bytes(addr, data)
+    }
+
+    /// Read a NUL-terminated string of at most `max` bytes, not counting
+    /// the NUL. A longer one is cut short.
+    pub fn read_cstr(&mut self, addr: u32, max: usize) -> Result<Vec<u8>> {
+        let mut s = vec![];
+        while s.len() < max {
+            match self.machine.load8(addr.wrapping_add(s.len() as u32))? {
+                0 => break,
+                b => s.push(b),
+            }
+        }
+        Ok(s)
+    }
+
+    /// The whole machine, for anything else.
+    pub fn machine(&mut self) -> &mut Machine<B> {
+        self.machine
+    }
+}
+
+/// What an ECALL handler can return to the guest.
+pub trait EcallReturn {
+    fn write<B: Bus>(self, machine: &mut Machine<B>);
+}
+
+/// Leaves a0 and a1 as they were.
+impl EcallReturn for () {
+    fn write<B: Bus>(self, _: &mut Machine<B>) {}
+}
+
+impl EcallReturn for u32 {
+    fn write<B: Bus>(self, m: &mut Machine<B>) {
+        m.set_reg(Reg::a0(), self);
+    }
+}
+
+impl EcallReturn for i32 {
+    fn write<B: Bus>(self, m: &mut Machine<B>) {
+        (self as u32).write(m);
+    }
+}
+
+impl EcallReturn for bool {
+    fn write<B: Bus>(self, m: &mut Machine<B>) {
+        (self as u32).write(m);
+    }
+}
+
+/// In a0 and a1, in that order.
+impl EcallReturn for (u32, u32) {
+    fn write<B: Bus>(self, m: &mut Machine<B>) {
+        m.set_reg(Reg::a0(), self.0);
+        m.set_reg(Reg::a1(), self.1);
+    }
+}
+
+/// Low half in a0, high half in a1.
+impl EcallReturn for u64 {
+    fn write<B: Bus>(self, m: &mut Machine<B>) {
+        (self as u32, (self >> 32) as u32).write(m);
+    }
+}
+
+impl EcallReturn for i64 {
+    fn write<B: Bus>(self, m: &mut Machine<B>) {
+        (self as u64).write(m);
+    }
+}
+
+impl<B: Bus> Machine<B> {
+    /// Handle ECALLs with call number `num` in a7 by calling `f`, and write
+    /// what it returns to a0 and a1.
+    ///
+    /// `step` then returns `StepOutcome::Running` for those calls, even in
+    /// trap mode; other numbers still return `StepOutcome::Syscall` or
+    /// trap. A handler replaces any earlier one for the same number.
+    /// Clones of the machine get their own clones of its handlers.
+    ///
+    /// A handler may step the machine through `Ecall::machine`. An ECALL
+    /// with its own number is then handled as if it weren't registered.
+    pub fn register_ecall<F, R>(&mut self, num: u32, mut f: F)
+        where F: FnMut(&mut Ecall<B>) -> R + Clone + Send + 'static,
+              R: EcallReturn,
+    {
+        let handler = move |call: &mut Ecall<B>| {
+            let ret = f(call);
+            ret.write(call.machine);
+        };
+        self.ecalls.insert(num, EcallHandler(Some(Box::new(handler))));
+    }
+
+    /// Returns whether there was a handler for `num`.
+    pub fn unregister_ecall(&mut self, num: u32) -> bool {
+        self.ecalls.remove(&num).is_some()
+    }
+
+    // Whether there is a handler, not already running, for the call
+    // number in a7.
+    pub(super) fn ecall_handled(&self) -> bool {
+        self.ecalls.get(&self.get_reg(Reg::a7())).is_some_and(|h| h.0.is_some())
+    }
+
+    // Run the handler for the ECALL just executed. There must be one.
+    //
+    // It is taken out while it runs, and put back unless it was replaced
+    // or unregistered meanwhile.
+    pub(super) fn dispatch_ecall(&mut self) {
+        let num = self.get_reg(Reg::a7());
+        let mut f = self.ecalls.get_mut(&num).and_then(|h| h.0.take()).unwrap();
+        f.call(&mut Ecall { machine: self });
+        if let Some(h @ EcallHandler(None)) = self.ecalls.get_mut(&num) {
+            h.0 = Some(f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use asm::assemble;
+    use decode::Reg;
+    use emu::{Machine, StepOutcome};
+
+    #[test]
+    fn test_ecall_handlers() {
+        let mut m = Machine::with_memory(64);
+        assemble("
+                    li      a7, 1
+                    ecall
+                    li      a7, 2
+                    ecall
+                    li      a7, 3
+                    ecall
+        ", 0).unwrap().load(&mut m).unwrap();
+        m.write_bytes(0x30, b"hi\0").unwrap();
+        m.set_reg(Reg::a0(), 0xFFFF_FFFF);
+        m.set_reg(Reg::a1(), 0x30);
+
+        m.register_ecall(1, |call| call.arg(0) as u64 + 1);
+        let printed = Arc::new(Mutex::new(vec![]));
+        let p = printed.clone();
+        m.register_ecall(2, move |call| {
+            assert_eq!(2, call.number());
+            let s = call.read_cstr(0x30, 16).unwrap();
+            p.lock().unwrap().extend(s);
+            -1
+        });
+
+        assert_eq!(StepOutcome::Running, m.step().unwrap());
+        assert_eq!(StepOutcome::Running, m.step().unwrap());
+        assert_eq!((0, 1), (m.get_reg(Reg::a0()), m.get_reg(Reg::a1())));
+        m.step().unwrap();
+        assert_eq!(StepOutcome::Running, m.step().unwrap());
+        assert_eq!(0xFFFF_FFFF, m.get_reg(Reg::a0()));
+        assert_eq!(b"hi", &printed.lock().unwrap()[..]);
+
+        m.step().unwrap();
+        assert_eq!(StepOutcome::Syscall, m.step().unwrap());
+        assert_eq!(24, m.pc);
+    }
+
+    #[test]
+    fn test_handler_steps() {
+        let mut m = Machine::with_memory(64);
+        assemble("
+                    li      a7, 1
+                    ecall
+                    ecall
+                    ecall
+                    nop
+        ", 0).unwrap().load(&mut m).unwrap();
+
+        // The nested ECALL isn't handled, since the handler is running.
+        m.register_ecall(1, |call| call.machine().step().unwrap() == StepOutcome::Syscall);
+        m.step().unwrap();
+        assert_eq!(StepOutcome::Running, m.step().unwrap());
+        assert_eq!((1, 12), (m.get_reg(Reg::a0()), m.pc));
+        assert_eq!(StepOutcome::Running, m.step().unwrap());
+        assert_eq!((0, 20), (m.get_reg(Reg::a0()), m.pc));
+
+        // A handler may unregister itself.
+        m.pc = 4;
+        m.register_ecall(1, |call| call.machine().unregister_ecall(1));
+        assert_eq!(StepOutcome::Running, m.step().unwrap());
+        assert_eq!(1, m.get_reg(Reg::a0()));
+        assert_eq!(StepOutcome::Syscall, m.step().unwrap());
+    }
+}
@@ -0,0 +1,168 @@
+//! Bounding how much work a guest may do.
+
+use decode::Instruction;
+use decode::Instruction::*;
+use emu::Machine;
+
+/// Broad groups of instructions, each with its own fuel cost.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Class {
+    /// Integer arithmetic, logic, shifts and comparisons, LUI and AUIPC.
+    Alu,
+    /// MUL and MULH*.
+    Mul,
+    /// DIV, DIVU, REM and REMU.
+    Div,
+    Load,
+    Store,
+    /// LR, SC and the AMOs.
+    Atomic,
+    Branch,
+    /// JAL and JALR.
+    Jump,
+    /// The CSR instructions.
+    Csr,
+    /// ECALL, EBREAK, MRET and the fences.
+    System,
+    /// Taking a trap in trap mode, charged in place of the instruction
+    /// that raised it. `Class::of` never returns this.
+    Trap,
+}
+
+const CLASSES: usize = 11;
+
+impl Class {
+    pub fn of(inst: &Instruction) -> Class {
+        match *inst {
+            ADDI(_) | SLTI(_) | SLTIU(_) | ANDI(_) | ORI(_) | XORI(_) | SLLI(_) | SRLI(_)
+                | SRAI(_) | LUI(_) | AUIPC(_) | ADD(_) | SLT(_) | SLTU(_) | AND(_) | OR(_)
+                | XOR(_) | SLL(_) | SRL(_) | SRA(_) | SUB(_) => Class::Alu,
+            MUL(_) | MULH(_) | MULHSU(_) | MULHU(_) => Class::Mul,
+            DIV(_) | DIVU(_) | REM(_) | REMU(_) => Class::Div,
+            JAL(_) | JALR(_) => Class::Jump,
+            BEQ(_) | BNE(_) | BLT(_) | BLTU(_) | BGE(_) | BGEU(_) => Class::Branch,
+            LW(_) | LH(_) | LHU(_) | LB(_) | LBU(_) => Class::Load,
+            SW(_) | SH(_) | SB(_) => Class::Store,
+            LR(_) | SC(_) | AMOSWAP(_) | AMOADD(_) | AMOXOR(_) | AMOAND(_) | AMOOR(_)
+                | AMOMIN(_) | AMOMAX(_) | AMOMINU(_) | AMOMAXU(_) => Class::Atomic,
+            CSRRW(_) | CSRRS(_) | CSRRC(_) | CSRRWI(_) | CSRRSI(_) | CSRRCI(_) => Class::Csr,
+            ECALL | EBREAK | MRET | FENCE(_) | FENCEI => Class::System,
+        }
+    }
+}
+
+/// The fuel charged for each class of instruction. Everything costs 1
+/// unless set otherwise.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuelCosts {
+    costs: [u64; CLASSES],
+}
+
+impl Default for FuelCosts {
+    fn default() -> FuelCosts {
+        FuelCosts { costs: [1; CLASSES] }
+    }
+}
+
+impl FuelCosts {
+    pub fn new() -> FuelCosts {
+        FuelCosts::default()
+    }
+
+    pub fn get(&self, class: Class) -> u64 {
+        self.costs[class as usize]
+    }
+
+    pub fn set(&mut self, class: Class, cost: u64) {
+        self.costs[class as usize] = cost;
+    }
+
+    pub fn cost(&self, inst: &Instruction) -> u64 {
+        self.get(Class::of(inst))
+    }
+}
+
+impl<B> Machine<B> {
+    /// The fuel left, or `None` if the machine runs without a limit, as it
+    /// does to begin with.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Limit the machine to `fuel`, or lift the limit with `None`.
+    ///
+    /// Each retired instruction then uses up its cost from `fuel_costs`.
+    /// An instruction costing more than what is left isn't run: `step`
+    /// returns `StepOutcome::OutOfFuel` with the machine unchanged and
+    /// without calling any hooks, and runs it once there is enough fuel
+    /// again. In trap mode, an instruction that traps costs
+    /// `Class::Trap` instead, and the trap is only taken if that much is
+    /// left.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Top up the fuel, if it is limited.
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(ref mut f) = self.fuel {
+            *f = f.saturating_add(fuel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Class;
+    use asm::assemble;
+    use decode::Reg;
+    use emu::{Machine, StepOutcome};
+    use emu::trap::Cause;
+
+    #[test]
+    fn test_fuel() {
+        let mut m = Machine::with_memory(64);
+        assemble("
+            loop:   addi    a0, a0, 1
+                    mul     a0, a0, a0
+                    j       loop
+        ", 0).unwrap().load(&mut m).unwrap();
+        m.fuel_costs.set(Class::Mul, 3);
+        m.set_fuel(Some(3));
+
+        assert_eq!(StepOutcome::Running, m.step().unwrap());
+        assert_eq!(Some(2), m.fuel());
+        assert_eq!(StepOutcome::OutOfFuel, m.step().unwrap());
+        assert_eq!(StepOutcome::OutOfFuel, m.step().unwrap());
+        assert_eq!((4, 1), (m.pc, m.get_reg(Reg::a0())));
+
+        m.add_fuel(1);
+        assert_eq!(StepOutcome::Running, m.step().unwrap());
+        assert_eq!((8, 1, Some(0)), (m.pc, m.get_reg(Reg::a0()), m.fuel()));
+        assert_eq!(StepOutcome::OutOfFuel, m.step().unwrap());
+
+        m.set_fuel(None);
+        for _ in 0..10 {
+            assert_eq!(StepOutcome::Running, m.step().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_trap_loop() {
+        // mtvec is 0, so the ecall is its own handler.
+        let mut m = Machine::with_memory(64);
+        assemble("
+                    ecall
+        ", 0).unwrap().load(&mut m).unwrap();
+        m.trap_mode = true;
+        m.fuel_costs.set(Class::Trap, 2);
+        m.set_fuel(Some(5));
+
+        for _ in 0..2 {
+            assert_eq!(StepOutcome::Trap(Cause::MachineEcall), m.step().unwrap());
+        }
+        assert_eq!(Some(1), m.fuel());
+        assert_eq!(StepOutcome::OutOfFuel, m.step().unwrap());
+        assert_eq!(StepOutcome::OutOfFuel, m.step().unwrap());
+        assert_eq!(Some(1), m.fuel());
+    }
+}
@@ -0,0 +1,200 @@
+//! Recording execution so that it can be undone.
+
+use std::collections::VecDeque;
+
+use emu::Machine;
+use emu::bus::Bus;
+use emu::csr::CsrFile;
+use emu::run::{Breakpoints, StopReason};
+
+// What one step changed, with the old values needed to undo it.
+#[derive(Clone)]
+struct Entry {
+    pc: u32,
+    csrs: CsrFile,
+    reservation: Option<u32>,
+    regs: Vec<(u8, u32)>,
+    mem: Vec<(u32, u32, u32)>,
+}
+
+#[derive(Clone)]
+pub(super) struct History {
+    entries: VecDeque<Entry>,
+    capacity: usize,
+    current: Option<Entry>,
+}
+
+impl<B: Bus> Machine<B> {
+    /// Keep an undo log of the last `capacity` steps, so that they can be
+    /// rewound with `step_back` and `reverse_continue`. Any log kept so
+    /// far is dropped.
+    ///
+    /// Each step records the pc, the CSRs and the old value of every
+    /// register and every byte of memory it writes. Memory is read through
+    /// the bus just before each store, so recording is meant for plain
+    /// memory: devices whose loads have side effects will see them, and
+    /// stores to memory that can't be loaded can't be undone. Rewinding
+    /// doesn't give back fuel, or take back what the host did in response
+    /// to an ECALL.
+    pub fn start_recording(&mut self, capacity: usize) {
+        self.history = Some(History {
+            entries: VecDeque::with_capacity(capacity.min(1 << 16)),
+            capacity,
+            current: None,
+        });
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.history = None;
+    }
+
+    /// How many steps can be undone.
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, |h| h.entries.len())
+    }
+
+    /// Undo the last recorded step, returning false if there is none.
+    pub fn step_back(&mut self) -> bool {
+        let entry = match self.history.as_mut().and_then(|h| h.entries.pop_back()) {
+            Some(e) => e,
+            None => return false,
+        };
+
+        // Stores are undone through the bus, which must not be recorded.
+        let history = self.history.take();
+        for &(addr, old, size) in entry.mem.iter().rev() {
+            self.invalidate_code(addr, size);
+            let _ = match size {
+                1 => self.memory.store8(addr, old as u8),
+                2 => self.memory.store16(addr, old as u16),
+                _ => self.memory.store32(addr, old),
+            };
+        }
+        self.history = history;
+
+        for &(rd, old) in entry.regs.iter().rev() {
+            self.iregs[rd as usize - 1] = old;
+        }
+        self.pc = entry.pc;
+        self.csrs = entry.csrs;
+        self.reservation = entry.reservation;
+        true
+    }
+
+    /// Step back until the instruction at `pc` is one that `run_until`
+    /// would stop before: one at a breakpoint, or one touching memory
+    /// under a watchpoint. Stops with `StopReason::EndOfHistory` once
+    /// there is nothing left to undo.
+    pub fn reverse_continue(&mut self, stops: &Breakpoints) -> StopReason {
+        loop {
+            if !self.step_back() {
+                return StopReason::EndOfHistory;
+            }
+            if let Some(stop) = self.check_stops(stops, false) {
+                return stop;
+            }
+        }
+    }
+
+    // Drop the undo log, if any, when the whole machine state is replaced.
+    pub(super) fn history_clear(&mut self) {
+        if let Some(ref mut h) = self.history {
+            h.entries.clear();
+            h.current = None;
+        }
+    }
+
+    pub(super) fn history_begin(&mut self) {
+        if let Some(ref mut h) = self.history {
+            h.current = Some(Entry {
+                pc: self.pc,
+                csrs: self.csrs.clone(),
+                reservation: self.reservation,
+                regs: vec![],
+                mem: vec![],
+            });
+        }
+    }
+
+    // Keep what the step did, unless it did nothing.
+    pub(super) fn history_commit(&mut self, keep: bool) {
+        if let Some(ref mut h) = self.history {
+            if let Some(entry) = h.current.take() {
+                if keep && h.capacity > 0 {
+                    if h.entries.len() == h.capacity {
+                        h.entries.pop_front();
+                    }
+                    h.entries.push_back(entry);
+                }
+            }
+        }
+    }
+
+    pub(super) fn history_reg(&mut self, rd: u8, old: u32) {
+        if let Some(History { current: Some(ref mut e), .. }) = self.history {
+            e.regs.push((rd, old));
+        }
+    }
+
+    // Remember what a store of `size` bytes at `addr` will overwrite.
+    pub(super) fn history_store(&mut self, addr: u32, size: u32) {
+        if let Some(History { current: Some(_), .. }) = self.history {
+            let old = match size {
+                1 => self.memory.load8(addr).map(|v| v as u32),
+                2 => self.memory.load16(addr).map(|v| v as u32),
+                _ => self.memory.load32(addr),
+            };
+            if let (Ok(old), Some(History { current: Some(ref mut e), .. }))
+                = (old, self.history.as_mut())
+            {
+                e.mem.push((addr, old, size));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use asm::assemble;
+    use decode::Reg;
+    use emu::Machine;
+    use emu::csr;
+    use emu::run::{Breakpoints, StopReason, Watchpoint};
+
+    #[test]
+    fn test_history() {
+        let mut m = Machine::with_memory(256);
+        assemble("
+            loop:   addi    a0, a0, 1
+                    sw      a0, 0(a1)
+                    addi    a1, a1, 4
+                    j       loop
+        ", 0).unwrap().load(&mut m).unwrap();
+        m.set_reg(Reg::a1(), 0x80);
+        m.start_recording(10);
+        for _ in 0..12 {
+            m.step().unwrap();
+        }
+        assert_eq!(10, m.history_len());
+        assert_eq!((0, 3, 0x8c), (m.pc, m.get_reg(Reg::a0()), m.get_reg(Reg::a1())));
+
+        assert!(m.step_back());
+        assert_eq!(12, m.pc);
+        assert_eq!(11, m.csrs.read(csr::MINSTRET).unwrap());
+
+        let mut stops = Breakpoints::new();
+        stops.add_watchpoint(Watchpoint::on_write(0x84, 4));
+        match m.reverse_continue(&stops) {
+            StopReason::Watchpoint { addr: 0x84, .. } => (),
+            r => panic!("{:?}", r),
+        }
+        assert_eq!((4, 2), (m.pc, m.get_reg(Reg::a0())));
+        assert_eq!(0, m.load32(0x84).unwrap());
+        assert_eq!(1, m.load32(0x80).unwrap());
+
+        assert_eq!(StopReason::EndOfHistory, m.reverse_continue(&Breakpoints::new()));
+        assert_eq!(0, m.history_len());
+        assert_eq!((8, 1, 0x80), (m.pc, m.get_reg(Reg::a0()), m.get_reg(Reg::a1())));
+        assert!(!m.step_back());
+    }
+}
@@ -0,0 +1,140 @@
+//! Watching a machine run, for profilers, coverage and fuzzers.
+
+use std::sync::{Arc, Mutex};
+
+use decode::Instruction;
+use emu::Machine;
+
+/// Callbacks from `Machine::step`. Each does nothing unless overridden.
+///
+/// Loads and stores are reported for every successful access through
+/// `Machine`, including the embedder's own between steps, but not for
+/// instruction fetches.
+pub trait Hooks {
+    /// An instruction was fetched from `pc` and is about to be decoded.
+    /// It isn't called for an instruction left waiting for fuel.
+    fn pre_decode(&mut self, _pc: u32, _bits: u32) {}
+
+    /// The instruction at `pc` ran without raising an exception.
+    fn post_execute(&mut self, _pc: u32, _inst: &Instruction) {}
+
+    /// `size` bytes were loaded from `addr`.
+    fn load(&mut self, _addr: u32, _size: u32, _val: u32) {}
+
+    /// `size` bytes were stored to `addr`.
+    fn store(&mut self, _addr: u32, _size: u32, _val: u32) {}
+
+    /// The instruction at `pc`, a taken branch or a jump, went to
+    /// `target`.
+    fn branch(&mut self, _pc: u32, _target: u32) {}
+}
+
+pub(super) type SharedHooks = Arc<Mutex<dyn Hooks + Send>>;
+
+impl<B> Machine<B> {
+    /// Call `hooks` from now on, instead of any installed before. Keep a
+    /// clone of the `Arc` to look at what they collected.
+    ///
+    /// Without hooks, each would-be call costs a check of an `Option`.
+    /// Clones of a machine share its hooks.
+    pub fn set_hooks<H: Hooks + Send + 'static>(&mut self, hooks: Arc<Mutex<H>>) {
+        self.hooks = Some(hooks);
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.hooks = None;
+    }
+
+    pub(super) fn with_hooks<F: FnOnce(&mut dyn Hooks)>(&self, f: F) {
+        if let Some(ref hooks) = self.hooks {
+            f(&mut *hooks.lock().unwrap_or_else(|e| e.into_inner()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::Hooks;
+    use asm::assemble;
+    use decode::{Instruction, Reg};
+    use emu::{Machine, StepOutcome};
+
+    #[derive(Default)]
+    struct Log(Vec<String>);
+
+    impl Hooks for Log {
+        fn pre_decode(&mut self, pc: u32, bits: u32) {
+            self.0.push(format!("fetch {:x} {:08x}", pc, bits));
+        }
+
+        fn post_execute(&mut self, pc: u32, inst: &Instruction) {
+            self.0.push(format!("exec {:x} {:?}", pc, inst).split('(').next().unwrap().to_owned());
+        }
+
+        fn load(&mut self, addr: u32, size: u32, val: u32) {
+            self.0.push(format!("load {:x} {} {}", addr, size, val));
+        }
+
+        fn store(&mut self, addr: u32, size: u32, val: u32) {
+            self.0.push(format!("store {:x} {} {}", addr, size, val));
+        }
+
+        fn branch(&mut self, pc: u32, target: u32) {
+            self.0.push(format!("branch {:x} {:x}", pc, target));
+        }
+    }
+
+    #[test]
+    fn test_hooks() {
+        let mut m = Machine::with_memory(256);
+        assemble("
+                    sh      a0, 0(a1)
+                    lbu     a2, 0(a1)
+                    beqz    a2, 0x10
+                    beq     a3, a2, 0x14
+        ", 0).unwrap().load(&mut m).unwrap();
+        m.set_reg(Reg::a0(), 0x1234);
+        m.set_reg(Reg::a1(), 0x80);
+        m.set_reg(Reg::a3(), 0x34);
+
+        let log = Arc::new(Mutex::new(Log::default()));
+        m.set_hooks(log.clone());
+        for _ in 0..4 {
+            m.step().unwrap();
+        }
+        m.clear_hooks();
+        m.step().unwrap_err();
+
+        assert_eq!(vec![
+            "fetch 0 00a59023",
+            "store 80 2 4660",
+            "exec 0 SH",
+            "fetch 4 0005c603",
+            "load 80 1 52",
+            "exec 4 LBU",
+            "fetch 8 00060463",
+            "exec 8 BEQ",
+            "fetch c 00c68463",
+            "exec c BEQ",
+            "branch c 14",
+        ], log.lock().unwrap().0);
+    }
+
+    #[test]
+    fn test_hooks_out_of_fuel() {
+        let mut m = Machine::with_memory(64);
+        assemble("
+                    addi    a0, a0, 1
+                    addi    a0, a0, 1
+        ", 0).unwrap().load(&mut m).unwrap();
+        let log = Arc::new(Mutex::new(Log::default()));
+        m.set_hooks(log.clone());
+        m.set_fuel(Some(1));
+        assert_eq!(StepOutcome::Running, m.step().unwrap());
+        assert_eq!(StepOutcome::OutOfFuel, m.step().unwrap());
+
+        assert_eq!(vec!["fetch 0 00150513", "exec 0 ADDI"], log.lock().unwrap().0);
+    }
+}
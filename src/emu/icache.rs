@@ -0,0 +1,238 @@
+//! Caching decoded instructions, so that loops aren't decoded again on
+//! every iteration.
+
+use std::collections::HashMap;
+
+use decode::Instruction;
+use emu::Machine;
+use emu::bus::{Bus, Perms};
+use Result;
+
+const PAGE_SHIFT: u32 = 12;
+const PAGE_SIZE: u32 = 1 << PAGE_SHIFT;
+
+// Instructions start on halfword boundaries.
+const SLOTS: usize = (PAGE_SIZE / 2) as usize;
+
+#[derive(Clone)]
+pub(super) struct Decoded {
+    pub bits: u32,
+    pub len: u32,
+    pub inst: Instruction,
+}
+
+type Page = Box<[Option<Decoded>]>;
+
+/// Decoded instructions, a page at a time.
+///
+/// An instruction is cached only if the bus says it came from plain
+/// memory, and only if it lies within one page, so that a store to a page
+/// is all it takes to invalidate what was decoded from it.
+#[derive(Clone)]
+pub(super) struct DecodeCache {
+    pub enabled: bool,
+
+    // The page last executed from is kept out of the map, so that hashing
+    // is needed only when execution moves to another page.
+    current: Option<(u32, Page)>,
+    pages: HashMap<u32, Page>,
+}
+
+impl DecodeCache {
+    pub fn new() -> DecodeCache {
+        DecodeCache {
+            enabled: false,
+            current: None,
+            pages: HashMap::new(),
+        }
+    }
+
+    // Make `page` current, if it has been cached.
+    fn switch(&mut self, page: u32) -> Option<&mut Page> {
+        if self.current.as_ref().map(|c| c.0) != Some(page) {
+            let next = self.pages.remove(&page)?;
+            if let Some((old, slots)) = self.current.replace((page, next)) {
+                self.pages.insert(old, slots);
+            }
+        }
+        self.current.as_mut().map(|c| &mut c.1)
+    }
+
+    pub fn get(&mut self, pc: u32) -> Option<&Decoded> {
+        let slots = self.switch(pc >> PAGE_SHIFT)?;
+        slots[((pc & (PAGE_SIZE - 1)) >> 1) as usize].as_ref()
+    }
+
+    fn insert(&mut self, pc: u32, decoded: Decoded) {
+        let page = pc >> PAGE_SHIFT;
+        if self.switch(page).is_none() {
+            let slots = vec![None; SLOTS].into_boxed_slice();
+            if let Some((old, slots)) = self.current.replace((page, slots)) {
+                self.pages.insert(old, slots);
+            }
+        }
+        if let Some((_, ref mut slots)) = self.current {
+            slots[((pc & (PAGE_SIZE - 1)) >> 1) as usize] = Some(decoded);
+        }
+    }
+
+    /// Forget every page overlapping `len` bytes at `addr`.
+    pub fn invalidate(&mut self, addr: u32, len: u32) {
+        if len == 0 || (self.current.is_none() && self.pages.is_empty()) {
+            return;
+        }
+        let mask = u32::MAX >> PAGE_SHIFT;
+        let first = addr >> PAGE_SHIFT;
+        let span = (addr.wrapping_add(len - 1) >> PAGE_SHIFT).wrapping_sub(first) & mask;
+        let inside = |page: u32| page.wrapping_sub(first) & mask <= span;
+
+        if self.current.as_ref().is_some_and(|c| inside(c.0)) {
+            self.current = None;
+        }
+        // Stores touch a page or two, but a range may hold more pages than
+        // are cached.
+        if (span as usize) < self.pages.len() {
+            for i in 0..=span {
+                self.pages.remove(&(first.wrapping_add(i) & mask));
+            }
+        } else {
+            self.pages.retain(|&page, _| !inside(page));
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.current = None;
+        self.pages.clear();
+    }
+}
+
+impl<B: Bus> Machine<B> {
+    /// Turn caching of decoded instructions on or off. It is off to begin
+    /// with, since it changes what the machine runs when code is changed
+    /// behind its back.
+    ///
+    /// The cache is kept up to date with stores made through `Machine`,
+    /// including the guest's, and with `Machine::protect`, and FENCE.I
+    /// flushes it. While it is on, after changing code or permissions any
+    /// other way, such as through `memory` directly or by a device, call
+    /// `flush_decode_cache`, or the machine may keep running the old code.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.icache.enabled = enabled;
+        self.flush_code();
+    }
+
+    pub fn flush_decode_cache(&mut self) {
+        self.flush_code();
+    }
+
+    /// Set the permissions of `len` bytes at `addr` with `Bus::protect`,
+    /// keeping the decode cache in step.
+    pub fn protect(&mut self, addr: u32, len: u32, perms: Perms) -> Result<()> {
+        self.invalidate_code(addr, len);
+        self.memory.protect(addr, len, perms)
+    }
+
+    /// Make `len` bytes at `addr` read as zero with `Bus::discard`, keeping
+    /// the decode cache in step. Unlike stores, this isn't recorded for
+    /// `step_back`.
+    pub fn discard(&mut self, addr: u32, len: u32) -> Result<()> {
+        self.invalidate_code(addr, len);
+        self.invalidate_reservation(addr, len);
+        self.memory.discard(addr, len)
+    }
+
+    // Forget code decoded, or translated, from `len` bytes at `addr`.
+    pub(super) fn invalidate_code(&mut self, addr: u32, len: u32) {
+        self.icache.invalidate(addr, len);
+        #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+        self.jit.invalidate(addr, len);
+    }
+
+    pub(super) fn flush_code(&mut self) {
+        self.icache.flush();
+        #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+        self.jit.flush();
+    }
+
+    pub(super) fn cache_decoded(&mut self, pc: u32, bits: u32, len: u32, inst: &Instruction) {
+        let fits = (pc & (PAGE_SIZE - 1)) + len <= PAGE_SIZE;
+        if self.icache.enabled && fits && self.memory.cacheable(pc) {
+            self.icache.insert(pc, Decoded { bits, len, inst: inst.clone() });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use asm::assemble;
+    use decode::Reg;
+    use emu::{Machine, StepOutcome};
+    use emu::bus::{Bus, Perms};
+    use emu::sparse::{SparseMemory, UnmappedPolicy};
+    use Error;
+
+    #[test]
+    fn test_off_by_default() {
+        let mut m = Machine::with_memory(64);
+        assemble("
+            loop:   addi    a0, a0, 1
+                    j       loop
+        ", 0).unwrap().load(&mut m).unwrap();
+        m.step().unwrap();
+        m.step().unwrap();
+        m.memory.store32(0, 0x00000073).unwrap();  // ecall
+        assert_eq!(StepOutcome::Syscall, m.step().unwrap());
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let mut m = Machine::with_memory(64);
+        m.set_decode_cache(true);
+        assemble("
+            loop:   addi    a0, a0, 1
+                    sw      a1, 0(zero)
+                    j       loop
+        ", 0).unwrap().load(&mut m).unwrap();
+        m.set_reg(Reg::a1(), 0x00250513);  // addi    a0,a0,2
+        for _ in 0..4 {
+            m.step().unwrap();
+        }
+        assert_eq!(3, m.get_reg(Reg::a0()));
+    }
+
+    #[test]
+    fn test_fence_i() {
+        let mut m = Machine::with_memory(64);
+        m.set_decode_cache(true);
+        assemble("
+            loop:   addi    a0, a0, 1
+                    fence.i
+                    j       loop
+        ", 0).unwrap().load(&mut m).unwrap();
+        for _ in 0..4 {
+            m.step().unwrap();
+        }
+        m.memory.store32(0, 0x00000073).unwrap();  // ecall
+        m.step().unwrap();
+        m.step().unwrap();
+        assert_eq!(StepOutcome::Syscall, m.step().unwrap());
+        assert_eq!(2, m.get_reg(Reg::a0()));
+    }
+
+    #[test]
+    fn test_protect() {
+        let mut m = Machine::new(SparseMemory::new(UnmappedPolicy::Fault));
+        m.set_decode_cache(true);
+        m.store32(0, 0xffdff06f).unwrap();  // j       0xfffffffc
+        m.store32(0xffff_fffc, 0x0040006f).unwrap();  // j       0
+        m.step().unwrap();
+        m.step().unwrap();
+        assert_eq!(0, m.pc);
+
+        m.protect(0, 4, Perms::RW).unwrap();
+        match m.step() {
+            Err(Error::AccessFault(_)) => (),
+            r => panic!("{:?}", r),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Memory for translated code, mapped straight from the OS.
+//!
+//! Code is never writable and executable at once: the mapping is made
+//! writable only while a block is copied in.
+
+use std::ptr;
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+
+#[cfg(target_os = "linux")]
+const MAP_ANONYMOUS: i32 = 0x20;
+#[cfg(not(target_os = "linux"))]
+const MAP_ANONYMOUS: i32 = 0x1000;
+
+extern "C" {
+    fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+    fn mprotect(addr: *mut u8, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut u8, len: usize) -> i32;
+}
+
+/// A fixed-size arena, filled from the start and emptied all at once.
+pub struct ExecMemory {
+    base: *mut u8,
+    size: usize,
+    used: usize,
+}
+
+// The arena is only reached through the `Machine` owning it.
+unsafe impl Send for ExecMemory {}
+
+impl ExecMemory {
+    /// Map `size` bytes, a multiple of the page size.
+    pub fn new(size: usize) -> Option<ExecMemory> {
+        let base = unsafe {
+            mmap(ptr::null_mut(), size, PROT_READ | PROT_EXEC, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0)
+        };
+        if base.is_null() || base as isize == -1 {
+            return None;
+        }
+        Some(ExecMemory { base, size, used: 0 })
+    }
+
+    /// Copy in `code`, returning where it landed, or `None` if the arena
+    /// is full.
+    pub fn install(&mut self, code: &[u8]) -> Option<*const u8> {
+        // Keep blocks 16-byte aligned, as compilers do for branch targets.
+        let start = (self.used + 15) & !15;
+        if start + code.len() > self.size {
+            return None;
+        }
+        unsafe {
+            if mprotect(self.base, self.size, PROT_READ | PROT_WRITE) != 0 {
+                return None;
+            }
+            ptr::copy_nonoverlapping(code.as_ptr(), self.base.add(start), code.len());
+            if mprotect(self.base, self.size, PROT_READ | PROT_EXEC) != 0 {
+                return None;
+            }
+        }
+        self.used = start + code.len();
+        Some(unsafe { self.base.add(start) })
+    }
+
+    /// Forget everything installed. Nothing from it may still be running.
+    pub fn reset(&mut self) {
+        self.used = 0;
+    }
+}
+
+impl Drop for ExecMemory {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.base, self.size);
+        }
+    }
+}
@@ -0,0 +1,685 @@
+//! Translating hot code to x86-64, with the `jit` feature.
+//!
+//! `Machine::run` and `run_until` count how often each address is reached.
+//! Once one gets hot, the straight-line code from there to the next jump,
+//! up to the end of its page, is translated into a block of host code.
+//! Conditional branches leave the block when taken.
+//!
+//! Guest registers live in a `Context` while blocks run. Loads and stores
+//! call back into `Machine`, so they see the same bus, permissions and
+//! invalidation as the interpreter. Anything else the translator doesn't
+//! handle, such as division, atomics, CSRs, ECALL and EBREAK, ends the
+//! block, and the interpreter runs it. So does an access that fails: the
+//! block stops before it, and the interpreter runs it again to raise the
+//! exception.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::mem;
+use std::ptr;
+
+use decode::{self, Instruction, Reg};
+use decode::Instruction::*;
+use decode::formats::{BOperands, IOperands, ROperands, SOperands};
+use emu::Machine;
+use emu::bus::Bus;
+
+use self::exec::ExecMemory;
+use self::x64::{Alu, Assembler, Cond, Shift, R};
+
+mod exec;
+mod x64;
+
+// How often an address is reached before the code there is translated.
+const HOT: u32 = 16;
+
+const MAX_BLOCK: u32 = 64;
+const ARENA_SIZE: usize = 16 << 20;
+
+const PAGE_SHIFT: u32 = 12;
+
+// What `load` should do, and what `store` reports.
+const LOAD_W: u32 = 0;
+const LOAD_H: u32 = 1;
+const LOAD_HU: u32 = 2;
+const LOAD_B: u32 = 3;
+const LOAD_BU: u32 = 4;
+const STORE_FAULT: u32 = 1;
+const STORE_CODE_CHANGED: u32 = 2;
+
+/// What translated code works on. x0 is always zero.
+#[repr(C)]
+struct Context {
+    regs: [u32; 32],
+
+    // Set by a block on the way out: where to go next, how many
+    // instructions it ran, and whether the interpreter must run the next.
+    pc: u32,
+    retired: u32,
+    interpret: u32,
+
+    machine: *mut u8,
+}
+
+const PC: i32 = mem::offset_of!(Context, pc) as i32;
+const RETIRED: i32 = mem::offset_of!(Context, retired) as i32;
+const INTERPRET: i32 = mem::offset_of!(Context, interpret) as i32;
+
+fn reg(r: Reg) -> i32 {
+    mem::offset_of!(Context, regs) as i32 + 4 * r.num() as i32
+}
+
+type BlockFn = unsafe extern "sysv64" fn(*mut Context);
+
+#[derive(Copy, Clone)]
+struct Block {
+    code: BlockFn,
+    len: u32,
+}
+
+enum Entry {
+    Counting(u32),
+    Compiled(Block),
+    Untranslatable,
+}
+
+// Guest addresses are well spread already, and hashing them is on the
+// path of every interpreted step.
+#[derive(Default)]
+struct PcHasher(u64);
+
+impl Hasher for PcHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 << 8 | b as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        }
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.0 = (n as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    }
+}
+
+pub(super) struct Jit {
+    entries: HashMap<u32, Entry, BuildHasherDefault<PcHasher>>,
+    memory: Option<ExecMemory>,
+
+    // One bit per guest page holding translated code, or empty while
+    // translation is off.
+    code_pages: Vec<u64>,
+
+    // Whether a store has invalidated a block since this was cleared.
+    code_changed: bool,
+}
+
+impl Jit {
+    pub fn new() -> Jit {
+        Jit {
+            entries: HashMap::default(),
+            memory: None,
+            code_pages: vec![],
+            code_changed: false,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        !self.code_pages.is_empty()
+    }
+
+    fn has_code(&self, page: u32) -> bool {
+        self.code_pages[page as usize / 64] & 1 << (page % 64) != 0
+    }
+
+    /// Forget blocks translated from any page overlapping `len` bytes at
+    /// `addr`.
+    pub fn invalidate(&mut self, addr: u32, len: u32) {
+        if !self.enabled() || len == 0 {
+            return;
+        }
+        let last = addr.wrapping_add(len - 1) >> PAGE_SHIFT;
+        let mut page = addr >> PAGE_SHIFT;
+        loop {
+            if self.has_code(page) {
+                self.code_pages[page as usize / 64] &= !(1 << (page % 64));
+                self.entries.retain(|&pc, e| match *e {
+                    Entry::Counting(_) => true,
+                    _ => pc >> PAGE_SHIFT != page,
+                });
+                self.code_changed = true;
+            }
+            if page == last {
+                break;
+            }
+            page = page.wrapping_add(1) & (u32::MAX >> PAGE_SHIFT);
+        }
+    }
+
+    /// Forget every block. None may be running.
+    pub fn flush(&mut self) {
+        self.entries.clear();
+        for w in &mut self.code_pages {
+            *w = 0;
+        }
+        if let Some(ref mut memory) = self.memory {
+            memory.reset();
+        }
+        self.code_changed = true;
+    }
+
+    // Install `code` for a block in `page`, making room if need be.
+    fn install(&mut self, page: u32, code: &[u8]) -> Option<BlockFn> {
+        if self.memory.is_none() {
+            self.memory = Some(ExecMemory::new(ARENA_SIZE)?);
+        }
+        let ptr = match self.memory.as_mut()?.install(code) {
+            Some(ptr) => ptr,
+            None => {
+                self.flush();
+                self.memory.as_mut()?.install(code)?
+            }
+        };
+        self.code_pages[page as usize / 64] |= 1 << (page % 64);
+        Some(unsafe { mem::transmute::<*const u8, BlockFn>(ptr) })
+    }
+}
+
+// Each machine translates its own code.
+impl Clone for Jit {
+    fn clone(&self) -> Jit {
+        let mut jit = Jit::new();
+        jit.code_pages = vec![0; self.code_pages.len()];
+        jit
+    }
+}
+
+unsafe extern "sysv64" fn load<B: Bus>(ctx: *mut Context, addr: u32, kind: u32) -> u64 {
+    let m = &mut *((*ctx).machine as *mut Machine<B>);
+    let val = match kind {
+        LOAD_W => m.load32(addr),
+        LOAD_H => m.load16(addr).map(|v| v as i16 as i32 as u32),
+        LOAD_HU => m.load16(addr).map(|v| v as u32),
+        LOAD_B => m.load8(addr).map(|v| v as i8 as i32 as u32),
+        _ => m.load8(addr).map(|v| v as u32),
+    };
+    match val {
+        Ok(v) => v as u64,
+        Err(_) => 1 << 32,
+    }
+}
+
+unsafe extern "sysv64" fn store<B: Bus>(ctx: *mut Context, addr: u32, val: u32, size: u32) -> u32 {
+    let m = &mut *((*ctx).machine as *mut Machine<B>);
+    let res = match size {
+        1 => m.store8(addr, val as u8),
+        2 => m.store16(addr, val as u16),
+        _ => m.store32(addr, val),
+    };
+    if res.is_err() {
+        STORE_FAULT
+    } else if mem::replace(&mut m.jit.code_changed, false) {
+        STORE_CODE_CHANGED
+    } else {
+        0
+    }
+}
+
+enum Flow {
+    Next,
+    End,
+    Unsupported,
+}
+
+// Leave the block with `retired` instructions done.
+fn exit(a: &mut Assembler, pc: u32, retired: u32, interpret: bool) {
+    a.store_imm(PC, pc);
+    a.store_imm(RETIRED, retired);
+    if interpret {
+        a.store_imm(INTERPRET, 1);
+    }
+    a.epilogue();
+}
+
+fn op_imm(a: &mut Assembler, op: &IOperands, alu: Alu) {
+    if op.rd.num() != 0 {
+        a.load(R::Eax, reg(op.rs1));
+        a.alu_imm(alu, R::Eax, op.imm);
+        a.store(reg(op.rd), R::Eax);
+    }
+}
+
+fn shift_imm(a: &mut Assembler, op: &IOperands, shift: Shift) {
+    if op.rd.num() != 0 {
+        a.load(R::Eax, reg(op.rs1));
+        a.shift_imm(shift, R::Eax, op.imm);
+        a.store(reg(op.rd), R::Eax);
+    }
+}
+
+fn set_imm(a: &mut Assembler, op: &IOperands, cond: Cond) {
+    if op.rd.num() != 0 {
+        a.load(R::Eax, reg(op.rs1));
+        a.alu_imm(Alu::Cmp, R::Eax, op.imm);
+        a.set_eax(cond);
+        a.store(reg(op.rd), R::Eax);
+    }
+}
+
+// Compute `rs1 op rs2` into EAX with `f`.
+fn op_reg<F: FnOnce(&mut Assembler)>(a: &mut Assembler, op: &ROperands, f: F) {
+    if op.rd.num() != 0 {
+        a.load(R::Eax, reg(op.rs1));
+        a.load(R::Ecx, reg(op.rs2));
+        f(a);
+        a.store(reg(op.rd), R::Eax);
+    }
+}
+
+// Leave for the target unless `not_taken` holds.
+fn branch(a: &mut Assembler, op: &BOperands, not_taken: Cond, pc: u32, n: u32) {
+    a.load(R::Eax, reg(op.rs1));
+    a.load(R::Ecx, reg(op.rs2));
+    a.alu(Alu::Cmp, R::Eax, R::Ecx);
+    let skip = a.jump_if(not_taken);
+    exit(a, pc.wrapping_add(op.imm), n + 1, false);
+    a.bind(skip);
+}
+
+fn load_op<B: Bus>(a: &mut Assembler, op: &IOperands, kind: u32, pc: u32, n: u32) {
+    a.load(R::Eax, reg(op.rs1));
+    a.alu_imm(Alu::Add, R::Eax, op.imm);
+    a.mov(R::Esi, R::Eax);
+    a.mov_imm(R::Edx, kind);
+    a.call(load::<B> as *const () as usize);
+    a.test_bit32();
+    let ok = a.jump_if(Cond::Ae);
+    exit(a, pc, n, true);
+    a.bind(ok);
+    if op.rd.num() != 0 {
+        a.store(reg(op.rd), R::Eax);
+    }
+}
+
+fn store_op<B: Bus>(a: &mut Assembler, op: &SOperands, size: u32, pc: u32, len: u32, n: u32) {
+    a.load(R::Eax, reg(op.rs1));
+    a.alu_imm(Alu::Add, R::Eax, op.imm);
+    a.mov(R::Esi, R::Eax);
+    a.load(R::Edx, reg(op.rs2));
+    a.mov_imm(R::Ecx, size);
+    a.call(store::<B> as *const () as usize);
+    a.test_eax();
+    let ok = a.jump_if(Cond::E);
+    a.alu_imm(Alu::Cmp, R::Eax, STORE_FAULT);
+    let changed = a.jump_if(Cond::Ne);
+    exit(a, pc, n, true);
+    a.bind(changed);
+    exit(a, pc.wrapping_add(len), n + 1, false);
+    a.bind(ok);
+}
+
+// Translate the `n`th instruction of a block.
+fn emit<B: Bus>(a: &mut Assembler, inst: &Instruction, pc: u32, len: u32, n: u32) -> Flow {
+    let next_pc = pc.wrapping_add(len);
+    match *inst {
+        ADDI(ref op) => op_imm(a, op, Alu::Add),
+        ANDI(ref op) => op_imm(a, op, Alu::And),
+         ORI(ref op) => op_imm(a, op, Alu::Or),
+        XORI(ref op) => op_imm(a, op, Alu::Xor),
+        SLLI(ref op) => shift_imm(a, op, Shift::Shl),
+        SRLI(ref op) => shift_imm(a, op, Shift::Shr),
+        SRAI(ref op) => shift_imm(a, op, Shift::Sar),
+        SLTI(ref op) => set_imm(a, op, Cond::L),
+        SLTIU(ref op) => set_imm(a, op, Cond::B),
+
+        LUI(ref op) if op.rd.num() != 0 => a.store_imm(reg(op.rd), op.imm),
+        AUIPC(ref op) if op.rd.num() != 0 => a.store_imm(reg(op.rd), pc.wrapping_add(op.imm)),
+        LUI(_) | AUIPC(_) => (),
+
+        ADD(ref op) => op_reg(a, op, |a| a.alu(Alu::Add, R::Eax, R::Ecx)),
+        SUB(ref op) => op_reg(a, op, |a| a.alu(Alu::Sub, R::Eax, R::Ecx)),
+        AND(ref op) => op_reg(a, op, |a| a.alu(Alu::And, R::Eax, R::Ecx)),
+         OR(ref op) => op_reg(a, op, |a| a.alu(Alu::Or, R::Eax, R::Ecx)),
+        XOR(ref op) => op_reg(a, op, |a| a.alu(Alu::Xor, R::Eax, R::Ecx)),
+        SLL(ref op) => op_reg(a, op, |a| a.shift_cl(Shift::Shl, R::Eax)),
+        SRL(ref op) => op_reg(a, op, |a| a.shift_cl(Shift::Shr, R::Eax)),
+        SRA(ref op) => op_reg(a, op, |a| a.shift_cl(Shift::Sar, R::Eax)),
+
+        SLT(ref op) => op_reg(a, op, |a| {
+            a.alu(Alu::Cmp, R::Eax, R::Ecx);
+            a.set_eax(Cond::L);
+        }),
+
+        SLTU(ref op) => op_reg(a, op, |a| {
+            a.alu(Alu::Cmp, R::Eax, R::Ecx);
+            a.set_eax(Cond::B);
+        }),
+
+        MUL(ref op) => op_reg(a, op, |a| a.imul()),
+
+        MULH(ref op) => op_reg(a, op, |a| {
+            a.sign_extend(R::Eax);
+            a.sign_extend(R::Ecx);
+            a.imul_high();
+        }),
+
+        MULHSU(ref op) => op_reg(a, op, |a| {
+            a.sign_extend(R::Eax);
+            a.imul_high();
+        }),
+
+        MULHU(ref op) => op_reg(a, op, |a| a.imul_high()),
+
+        JAL(ref op) => {
+            if op.rd.num() != 0 {
+                a.store_imm(reg(op.rd), next_pc);
+            }
+            exit(a, pc.wrapping_add(op.imm), n + 1, false);
+            return Flow::End;
+        }
+
+        JALR(ref op) => {
+            a.load(R::Eax, reg(op.rs1));
+            a.alu_imm(Alu::Add, R::Eax, op.imm);
+            a.alu_imm(Alu::And, R::Eax, !1);
+            a.store(PC, R::Eax);
+            if op.rd.num() != 0 {
+                a.store_imm(reg(op.rd), next_pc);
+            }
+            a.store_imm(RETIRED, n + 1);
+            a.epilogue();
+            return Flow::End;
+        }
+
+         BEQ(ref op) => branch(a, op, Cond::Ne, pc, n),
+         BNE(ref op) => branch(a, op, Cond::E, pc, n),
+         BLT(ref op) => branch(a, op, Cond::Ge, pc, n),
+        BLTU(ref op) => branch(a, op, Cond::Ae, pc, n),
+         BGE(ref op) => branch(a, op, Cond::L, pc, n),
+        BGEU(ref op) => branch(a, op, Cond::B, pc, n),
+
+         LW(ref op) => load_op::<B>(a, op, LOAD_W, pc, n),
+         LH(ref op) => load_op::<B>(a, op, LOAD_H, pc, n),
+        LHU(ref op) => load_op::<B>(a, op, LOAD_HU, pc, n),
+         LB(ref op) => load_op::<B>(a, op, LOAD_B, pc, n),
+        LBU(ref op) => load_op::<B>(a, op, LOAD_BU, pc, n),
+
+        SW(ref op) => store_op::<B>(a, op, 4, pc, len, n),
+        SH(ref op) => store_op::<B>(a, op, 2, pc, len, n),
+        SB(ref op) => store_op::<B>(a, op, 1, pc, len, n),
+
+        _ => return Flow::Unsupported,
+    }
+    Flow::Next
+}
+
+impl<B: Bus> Machine<B> {
+    /// Translate hot code to x86-64 in `run` and `run_until`. It is off to
+    /// begin with.
+    ///
+    /// Translated code runs only while there are no breakpoints or
+    /// watchpoints to check, and no trace, hooks, recording or fuel limit;
+    /// otherwise the interpreter does all the work. Either way the results
+    /// are the same, down to `minstret`, as long as code only changes
+    /// through `Machine`. `step` always interprets.
+    ///
+    /// The code is kept up to date in the same way as the decode cache,
+    /// whether or not that is on: after changing code through `memory`
+    /// directly, or by a device, call `flush_decode_cache`, which drops
+    /// translations too.
+    pub fn set_jit(&mut self, enabled: bool) {
+        self.jit.flush();
+        self.jit.code_pages = if enabled { vec![0; 1 << (32 - PAGE_SHIFT - 6)] } else { vec![] };
+    }
+
+    /// Run translated blocks from `pc` while they fit in `budget`
+    /// instructions, returning how many ran. The interpreter should run
+    /// the next instruction.
+    pub(super) fn jit_run(&mut self, budget: u64) -> u64 {
+        if !self.jit.enabled() || self.trace.is_some() || self.hooks.is_some()
+            || self.history.is_some() || self.fuel.is_some()
+        {
+            return 0;
+        }
+
+        let mut ctx = Context {
+            regs: [0; 32],
+            pc: 0,
+            retired: 0,
+            interpret: 0,
+            machine: ptr::null_mut(),
+        };
+        let mut entered = false;
+        let mut ran = 0;
+        while let Some(block) = self.jit_block() {
+            if block.len as u64 > budget - ran {
+                break;
+            }
+            if !entered {
+                ctx.regs[1..].copy_from_slice(&self.iregs);
+                entered = true;
+            }
+            ctx.interpret = 0;
+            ctx.machine = self as *mut Machine<B> as *mut u8;
+            self.jit.code_changed = false;
+            unsafe {
+                (block.code)(&mut ctx);
+            }
+
+            self.pc = ctx.pc;
+            self.csrs.retire_many(ctx.retired as u64);
+            ran += ctx.retired as u64;
+            if ctx.interpret != 0 {
+                break;
+            }
+        }
+        if entered {
+            self.iregs.copy_from_slice(&ctx.regs[1..]);
+        }
+        ran
+    }
+
+    // The block at `pc`, translating it if it has become hot.
+    fn jit_block(&mut self) -> Option<Block> {
+        let pc = self.pc;
+        match *self.jit.entries.entry(pc).or_insert(Entry::Counting(0)) {
+            Entry::Compiled(block) => return Some(block),
+            Entry::Untranslatable => return None,
+            Entry::Counting(ref mut count) => {
+                *count += 1;
+                if *count < HOT {
+                    return None;
+                }
+            }
+        }
+
+        let block = self.translate(pc);
+        let entry = match block {
+            Some(b) => Entry::Compiled(b),
+            None => Entry::Untranslatable,
+        };
+        self.jit.entries.insert(pc, entry);
+        block
+    }
+
+    fn translate(&mut self, start: u32) -> Option<Block> {
+        let page = start >> PAGE_SHIFT;
+        let mut a = Assembler::new();
+        a.prologue();
+
+        let mut pc = start;
+        let mut n = 0;
+        loop {
+            if n == MAX_BLOCK || pc >> PAGE_SHIFT != page {
+                exit(&mut a, pc, n, false);
+                break;
+            }
+            let (inst, len) = match self.jit_fetch(pc) {
+                Some(i) => i,
+                None => {
+                    exit(&mut a, pc, n, true);
+                    break;
+                }
+            };
+            match emit::<B>(&mut a, &inst, pc, len, n) {
+                Flow::Next => (),
+                Flow::End => {
+                    n += 1;
+                    break;
+                }
+                Flow::Unsupported => {
+                    exit(&mut a, pc, n, true);
+                    break;
+                }
+            }
+            n += 1;
+            pc = pc.wrapping_add(len);
+        }
+
+        if n == 0 {
+            return None;
+        }
+        let code = self.jit.install(page, &a.code)?;
+        Some(Block { code, len: n })
+    }
+
+    // An instruction from plain memory, entirely within one page.
+    fn jit_fetch(&mut self, pc: u32) -> Option<(Instruction, u32)> {
+        if !self.memory.cacheable(pc) {
+            return None;
+        }
+        let (bits, len) = self.fetch(pc).ok()?;
+        if (pc & ((1 << PAGE_SHIFT) - 1)) + len > 1 << PAGE_SHIFT {
+            return None;
+        }
+        Some((decode::decode(bits).ok()?, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use asm::assemble;
+    use decode::Reg;
+    use emu::{csr, Machine, StepOutcome};
+    use emu::run::StopReason;
+
+    // Run `source` with and without translation, and check that both
+    // machines end up the same.
+    fn compare(source: &str, a0: u32, trap_mode: bool) -> (Machine, StopReason) {
+        let program = assemble(source, 0).unwrap();
+        let mut results = vec![];
+        for &jit in &[false, true] {
+            let mut m = Machine::with_memory(0x2000);
+            program.load(&mut m).unwrap();
+            m.set_reg(Reg::a0(), a0);
+            m.trap_mode = trap_mode;
+            m.set_jit(jit);
+            let stop = m.run(100_000).unwrap();
+            results.push((m, stop));
+        }
+
+        let (jit, jit_stop) = results.pop().unwrap();
+        let (interp, stop) = results.pop().unwrap();
+        assert_eq!(stop, jit_stop);
+        assert_eq!(interp.pc, jit.pc);
+        for i in 0..32 {
+            let r = Reg::new(i).unwrap();
+            assert_eq!(interp.get_reg(r), jit.get_reg(r), "x{}", i);
+        }
+        assert_eq!(interp.csrs.read(csr::MINSTRET).unwrap(), jit.csrs.read(csr::MINSTRET).unwrap());
+        assert_eq!(&interp.memory[..], &jit.memory[..]);
+        (jit, stop)
+    }
+
+    fn translated(m: &Machine) -> bool {
+        m.jit.entries.values().any(|e| matches!(*e, super::Entry::Compiled(_)))
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let (m, stop) = compare("
+                    li      s0, 0x1000
+                    li      s1, 0
+            loop:   mul     t0, a0, a0
+                    mulh    t1, a0, s1
+                    mulhu   t2, a0, s1
+                    mulhsu  t3, s1, a0
+                    slt     t4, s1, a0
+                    sltu    t5, s1, a0
+                    slti    t6, s1, -5
+                    sltiu   a1, s1, -5
+                    sra     a2, s1, a0
+                    srli    a3, s1, 3
+                    xori    a4, a3, 0x555
+                    add     s1, s1, t0
+                    sub     s1, s1, t3
+                    xor     s1, s1, t1
+                    or      s1, s1, t2
+                    sw      s1, 0(s0)
+                    sh      a2, 4(s0)
+                    sb      a4, 7(s0)
+                    lh      a5, 6(s0)
+                    lbu     a6, 4(s0)
+                    lb      a7, 7(s0)
+                    addi    s0, s0, 8
+                    addi    a0, a0, -1
+                    bnez    a0, loop
+                    jal     ra, done
+            done:   auipc   a0, 0
+                    jalr    zero, 8(ra)
+                    ecall
+        ", 300, false);
+        assert_eq!(StopReason::Outcome(StepOutcome::Syscall), stop);
+        assert!(translated(&m));
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        // Each time round, flip the instruction at `patch` between adding
+        // one and adding two.
+        compare("
+                    la      s0, patch
+                    lw      t0, 0(s0)
+                    la      t1, new
+                    lw      t1, 0(t1)
+                    xor     s1, t0, t1
+            loop:   addi    a1, a1, 1
+                    lw      t0, 0(s0)
+                    xor     t0, t0, s1
+                    sw      t0, 0(s0)
+            patch:  addi    a2, a2, 1
+                    addi    a0, a0, -1
+                    bnez    a0, loop
+                    ecall
+            new:    addi    a2, a2, 2
+        ", 100, false);
+    }
+
+    #[test]
+    fn test_faults() {
+        // Loads past the end of memory fail partway through a hot block,
+        // until `a0` gets small enough. The handler skips them.
+        let (m, stop) = compare("
+                    la      t0, handler
+                    csrw    mtvec, t0
+            loop:   addi    a1, a1, 1
+                    slli    t0, a0, 6
+                    lw      t1, 0(t0)
+                    add     a2, a2, t1
+                    addi    a0, a0, -1
+                    bnez    a0, loop
+            done:   j       done
+            handler:
+                    csrr    t2, mepc
+                    addi    t2, t2, 4
+                    csrw    mepc, t2
+                    addi    a3, a3, 1
+                    mret
+        ", 200, true);
+        assert_eq!(StopReason::BudgetExhausted, stop);
+        assert_eq!(73, m.get_reg(Reg::a3()));
+        assert!(translated(&m));
+    }
+}
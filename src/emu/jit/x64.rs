@@ -0,0 +1,223 @@
+//! Just enough of an x86-64 assembler for the translator.
+//!
+//! Translated code keeps a pointer to its `Context` in RBX and works in
+//! EAX, ECX and EDX, so the 32-bit forms here address memory only as
+//! `[rbx + disp32]`.
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum R {
+    Eax = 0,
+    Ecx = 1,
+    Edx = 2,
+    Esi = 6,
+}
+
+/// Two-operand ALU instructions, by their ModRM extension in the `81 /n`
+/// immediate form.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Alu {
+    Add = 0,
+    Or = 1,
+    And = 4,
+    Sub = 5,
+    Xor = 6,
+    Cmp = 7,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Shift {
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+/// Condition codes, as in the low nibble of `Jcc` and `SETcc`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Cond {
+    B = 0x2,
+    Ae = 0x3,
+    E = 0x4,
+    Ne = 0x5,
+    L = 0xC,
+    Ge = 0xD,
+}
+
+const RBX: u8 = 3;
+
+/// A forward jump waiting for `Assembler::bind`.
+#[must_use]
+pub struct Label(usize);
+
+#[derive(Default)]
+pub struct Assembler {
+    pub code: Vec<u8>,
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler { code: vec![] }
+    }
+
+    fn bytes(&mut self, b: &[u8]) {
+        self.code.extend_from_slice(b);
+    }
+
+    fn imm32(&mut self, val: u32) {
+        self.bytes(&val.to_le_bytes());
+    }
+
+    fn modrm_rbx(&mut self, reg: u8, disp: i32) {
+        self.bytes(&[0x80 | reg << 3 | RBX]);
+        self.imm32(disp as u32);
+    }
+
+    fn modrm_reg(&mut self, reg: u8, rm: R) {
+        self.bytes(&[0xC0 | reg << 3 | rm as u8]);
+    }
+
+    /// `push rbx; mov rbx, rdi`
+    pub fn prologue(&mut self) {
+        self.bytes(&[0x53, 0x48, 0x89, 0xFB]);
+    }
+
+    /// `pop rbx; ret`
+    pub fn epilogue(&mut self) {
+        self.bytes(&[0x5B, 0xC3]);
+    }
+
+    /// `mov dst, [rbx + disp]`
+    pub fn load(&mut self, dst: R, disp: i32) {
+        self.bytes(&[0x8B]);
+        self.modrm_rbx(dst as u8, disp);
+    }
+
+    /// `mov [rbx + disp], src`
+    pub fn store(&mut self, disp: i32, src: R) {
+        self.bytes(&[0x89]);
+        self.modrm_rbx(src as u8, disp);
+    }
+
+    /// `mov dword [rbx + disp], imm`
+    pub fn store_imm(&mut self, disp: i32, imm: u32) {
+        self.bytes(&[0xC7]);
+        self.modrm_rbx(0, disp);
+        self.imm32(imm);
+    }
+
+    /// `mov dst, src`
+    pub fn mov(&mut self, dst: R, src: R) {
+        self.bytes(&[0x89]);
+        self.modrm_reg(src as u8, dst);
+    }
+
+    /// `mov dst, imm`
+    pub fn mov_imm(&mut self, dst: R, imm: u32) {
+        self.bytes(&[0xB8 + dst as u8]);
+        self.imm32(imm);
+    }
+
+    /// `op dst, src`
+    pub fn alu(&mut self, op: Alu, dst: R, src: R) {
+        self.bytes(&[(op as u8) << 3 | 1]);
+        self.modrm_reg(src as u8, dst);
+    }
+
+    /// `op dst, imm`
+    pub fn alu_imm(&mut self, op: Alu, dst: R, imm: u32) {
+        self.bytes(&[0x81]);
+        self.modrm_reg(op as u8, dst);
+        self.imm32(imm);
+    }
+
+    /// `op dst, imm`, with the count already masked to 5 bits.
+    pub fn shift_imm(&mut self, op: Shift, dst: R, count: u32) {
+        self.bytes(&[0xC1]);
+        self.modrm_reg(op as u8, dst);
+        self.bytes(&[count as u8 & 31]);
+    }
+
+    /// `op dst, cl`, which masks the count to 5 bits as RISC-V does.
+    pub fn shift_cl(&mut self, op: Shift, dst: R) {
+        self.bytes(&[0xD3]);
+        self.modrm_reg(op as u8, dst);
+    }
+
+    /// `setcc al; movzx eax, al`
+    pub fn set_eax(&mut self, cond: Cond) {
+        self.bytes(&[0x0F, 0x90 | cond as u8, 0xC0, 0x0F, 0xB6, 0xC0]);
+    }
+
+    /// `imul eax, ecx`
+    pub fn imul(&mut self) {
+        self.bytes(&[0x0F, 0xAF, 0xC1]);
+    }
+
+    /// `movsxd reg, reg`, sign-extending it to 64 bits. Other 32-bit
+    /// operations zero-extend.
+    pub fn sign_extend(&mut self, reg: R) {
+        self.bytes(&[0x48, 0x63]);
+        self.modrm_reg(reg as u8, reg);
+    }
+
+    /// `imul rax, rcx; shr rax, 32`: the high half of the product, for
+    /// operands already extended to 64 bits.
+    pub fn imul_high(&mut self) {
+        self.bytes(&[0x48, 0x0F, 0xAF, 0xC1, 0x48, 0xC1, 0xE8, 0x20]);
+    }
+
+    /// `mov rdi, rbx; mov rax, func; call rax`, for a helper taking the
+    /// context first. The stack is still aligned from the prologue.
+    pub fn call(&mut self, func: usize) {
+        self.bytes(&[0x48, 0x89, 0xDF, 0x48, 0xB8]);
+        self.bytes(&(func as u64).to_le_bytes());
+        self.bytes(&[0xFF, 0xD0]);
+    }
+
+    /// `bt rax, 32`, leaving bit 32 of the result of a call in CF.
+    pub fn test_bit32(&mut self) {
+        self.bytes(&[0x48, 0x0F, 0xBA, 0xE0, 0x20]);
+    }
+
+    /// `test eax, eax`
+    pub fn test_eax(&mut self) {
+        self.bytes(&[0x85, 0xC0]);
+    }
+
+    /// `jcc rel32`, to be bound later.
+    pub fn jump_if(&mut self, cond: Cond) -> Label {
+        self.bytes(&[0x0F, 0x80 | cond as u8]);
+        self.imm32(0);
+        Label(self.code.len())
+    }
+
+    /// Point `label` at the next instruction.
+    pub fn bind(&mut self, label: Label) {
+        let rel = (self.code.len() - label.0) as u32;
+        self.code[label.0 - 4..label.0].copy_from_slice(&rel.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding() {
+        let mut a = Assembler::new();
+        a.load(R::Ecx, 0x7c);
+        a.alu_imm(Alu::Add, R::Eax, 0xFFFF_FFFF);
+        a.alu(Alu::Sub, R::Eax, R::Ecx);
+        a.shift_cl(Shift::Sar, R::Eax);
+        let l = a.jump_if(Cond::Ne);
+        a.store_imm(0x80, 4);
+        a.bind(l);
+        assert_eq!(a.code, [
+            0x8B, 0x8B, 0x7c, 0, 0, 0,                   // mov ecx, [rbx+0x7c]
+            0x81, 0xC0, 0xFF, 0xFF, 0xFF, 0xFF,          // add eax, -1
+            0x29, 0xC8,                                  // sub eax, ecx
+            0xD3, 0xF8,                                  // sar eax, cl
+            0x0F, 0x85, 10, 0, 0, 0,                     // jne +10
+            0xC7, 0x83, 0x80, 0, 0, 0, 4, 0, 0, 0,       // mov dword [rbx+0x80], 4
+        ][..]);
+    }
+}
@@ -1,115 +1,258 @@
+use std::collections::HashMap;
 use std::io;
 
 use decode;
-use decode::Reg;
+use decode::{Reg, Csr};
 use decode::Instruction::*;
-use decode::formats::{IOperands, ROperands, BOperands};
+use decode::formats::{IOperands, ROperands, AOperands, BOperands};
 use {Error, Result};
 
+use self::bus::{Bus, Ram};
+use self::csr::CsrFile;
+use self::ecall::EcallHandler;
+use self::fuel::{Class, FuelCosts};
+use self::history::History;
+use self::hooks::SharedHooks;
+use self::icache::DecodeCache;
+use self::trace::Trace;
+use self::trap::{Cause, Exception};
+
+pub mod bus;
+pub mod csr;
+pub mod ecall;
+pub mod fuel;
+pub mod history;
+pub mod hooks;
+pub mod icache;
+#[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+mod jit;
+pub mod run;
+pub mod snapshot;
+pub mod smp;
+pub mod sparse;
+pub mod trace;
+pub mod trap;
+
+type StepResult<T> = ::std::result::Result<T, Exception>;
+
+// Atomics, unlike ordinary loads and stores, must be naturally aligned.
+fn check_aligned(addr: u32) -> Result<()> {
+    if addr & 3 != 0 {
+        return Err(Error::MisalignedAccess);
+    }
+    Ok(())
+}
+
+/// A RISC-V hart attached to a memory bus.
+///
+/// The bus defaults to flat `Ram` at address 0. Use `SystemBus` to place
+/// RAM, ROM and devices at arbitrary addresses.
 #[derive(Clone)]
-pub struct Machine {
+pub struct Machine<B = Ram> {
     pub pc: u32,
     iregs: [u32; 31],
-    pub memory: Vec<u8>,
+    pub memory: B,
+    pub csrs: CsrFile,
+
+    /// Deliver exceptions to the guest's trap handler instead of returning
+    /// them as errors from `step`.
+    ///
+    /// In this mode illegal instructions, misaligned accesses, access
+    /// faults, ECALL and EBREAK write `mepc`, `mcause` and `mtval` and jump
+    /// to `mtvec`. `step` then returns `StepOutcome::Trap`.
+    pub trap_mode: bool,
+
+    // Word address reserved by the last LR, if any.
+    reservation: Option<u32>,
+
+    fuel: Option<u64>,
+
+    /// What each instruction costs when fuel is limited with `set_fuel`.
+    pub fuel_costs: FuelCosts,
+
+    ecalls: HashMap<u32, EcallHandler<B>>,
+    trace: Option<Trace>,
+    hooks: Option<SharedHooks>,
+    history: Option<History>,
+    icache: DecodeCache,
+    #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+    jit: jit::Jit,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum StepOutcome {
     Running,
     Syscall,
     Breakpoint,
+
+    /// Only in trap mode: the instruction raised an exception, and `pc`
+    /// now points at the trap handler.
+    Trap(Cause),
+
+    /// The next instruction costs more fuel than is left, so it didn't
+    /// run. Step again after `add_fuel` to resume.
+    OutOfFuel,
 }
 
-impl Machine {
+impl Machine<Ram> {
+    /// A machine with `size` bytes of RAM starting at address 0.
+    pub fn with_memory(size: usize) -> Machine {
+        Machine::new(Ram::new(size))
+    }
+}
+
+impl<B: Bus> Machine<B> {
+    pub fn new(memory: B) -> Machine<B> {
+        Machine {
+            pc: 0,
+            iregs: [0; 31],
+            memory,
+            csrs: CsrFile::new(0),
+            trap_mode: false,
+            reservation: None,
+            fuel: None,
+            fuel_costs: FuelCosts::new(),
+            ecalls: HashMap::new(),
+            trace: None,
+            hooks: None,
+            history: None,
+            icache: DecodeCache::new(),
+            #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+            jit: jit::Jit::new(),
+        }
+    }
+
     pub fn dump<W>(&self, writer: &mut W)
         where W: io::Write,
     {
-        write!(writer, "PC : {:08X}\n", self.pc).unwrap();
+        writeln!(writer, "PC : {:08X}", self.pc).unwrap();
         for i in 0..32 {
             let v = self.get_reg(Reg::new(i).unwrap());
             write!(writer, "R{:<2}: {:08X}    ", i, v).unwrap();
 
             if (i % 4) == 3 {
-                write!(writer, "\n").unwrap();
+                writeln!(writer).unwrap();
             }
         }
     }
 
-    pub fn with_memory(size: usize) -> Machine {
-        Machine {
-            pc: 0,
-            iregs: [0; 31],
-            memory: vec![0; size],
-        }
+    pub fn load8(&mut self, addr: u32) -> Result<u8> {
+        let val = self.memory.load8(addr)?;
+        self.trace_load(addr);
+        self.with_hooks(|h| h.load(addr, 1, val as u32));
+        Ok(val)
     }
 
-    pub fn load8(&self, addr: u32) -> Result<u8> {
-        if addr as usize >= self.memory.len() {
-            return Err(Error::MemoryOutOfBounds);
-        }
-
-        Ok(self.memory[addr as usize])
+    pub fn load16(&mut self, addr: u32) -> Result<u16> {
+        let val = self.memory.load16(addr)?;
+        self.trace_load(addr);
+        self.with_hooks(|h| h.load(addr, 2, val as u32));
+        Ok(val)
     }
 
-    pub fn load16(&self, addr: u32) -> Result<u16> {
-        if let Some(last_addr) = addr.checked_add(1) {
-            if (last_addr as usize) < self.memory.len() {
-                return Ok(
-                    (self.memory[addr as usize] as u16)
-                    | ((self.memory[(addr+1) as usize] as u16) << 8));
-            }
-        }
+    pub fn load32(&mut self, addr: u32) -> Result<u32> {
+        let val = self.memory.load32(addr)?;
+        self.trace_load(addr);
+        self.with_hooks(|h| h.load(addr, 4, val));
+        Ok(val)
+    }
 
-        Err(Error::MemoryOutOfBounds)
+    pub fn store8(&mut self, addr: u32, val: u8) -> Result<()> {
+        self.invalidate_reservation(addr, 1);
+        self.invalidate_code(addr, 1);
+        self.history_store(addr, 1);
+        self.memory.store8(addr, val)?;
+        self.trace_store(addr, val as u32, 1);
+        self.with_hooks(|h| h.store(addr, 1, val as u32));
+        Ok(())
     }
 
-    pub fn load32(&self, addr: u32) -> Result<u32> {
-        if let Some(last_addr) = addr.checked_add(3) {
-            if (last_addr as usize) < self.memory.len() {
-                return Ok(
-                    (self.memory[addr as usize] as u32)
-                    | ((self.memory[(addr+1) as usize] as u32) << 8)
-                    | ((self.memory[(addr+2) as usize] as u32) << 16)
-                    | ((self.memory[(addr+3) as usize] as u32) << 24));
-            }
-        }
+    pub fn store16(&mut self, addr: u32, val: u16) -> Result<()> {
+        self.invalidate_reservation(addr, 2);
+        self.invalidate_code(addr, 2);
+        self.history_store(addr, 2);
+        self.memory.store16(addr, val)?;
+        self.trace_store(addr, val as u32, 2);
+        self.with_hooks(|h| h.store(addr, 2, val as u32));
+        Ok(())
+    }
 
-        Err(Error::MemoryOutOfBounds)
+    pub fn store32(&mut self, addr: u32, val: u32) -> Result<()> {
+        self.invalidate_reservation(addr, 4);
+        self.invalidate_code(addr, 4);
+        self.history_store(addr, 4);
+        self.memory.store32(addr, val)?;
+        self.trace_store(addr, val, 4);
+        self.with_hooks(|h| h.store(addr, 4, val));
+        Ok(())
     }
 
-    pub fn store8(&mut self, addr: u32, val: u8) -> Result<()> {
-        if addr as usize >= self.memory.len() {
-            return Err(Error::MemoryOutOfBounds);
+    /// Fill `buf` from guest memory starting at `addr`.
+    pub fn read_bytes(&mut self, addr: u32, buf: &mut [u8]) -> Result<()> {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = self.load8(addr.wrapping_add(i as u32))?;
         }
-
-        self.memory[addr as usize] = val;
         Ok(())
     }
 
-    pub fn store16(&mut self, addr: u32, val: u16) -> Result<()> {
-        if let Some(last_addr) = addr.checked_add(1) {
-            if (last_addr as usize) < self.memory.len() {
-                self.memory[addr as usize] = (val & 0xFF) as u8;
-                self.memory[(addr+1) as usize] = ((val >> 8) & 0xFF) as u8;
-                return Ok(());
-            }
+    /// Copy `data` into guest memory starting at `addr`, as a run of byte
+    /// stores.
+    pub fn write_bytes(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
         }
+        // Invalidate once for the whole range, rather than for each byte.
+        self.invalidate_reservation(addr, data.len() as u32);
+        self.invalidate_code(addr, data.len() as u32);
+        for (i, &b) in data.iter().enumerate() {
+            let addr = addr.wrapping_add(i as u32);
+            self.history_store(addr, 1);
+            self.memory.store8(addr, b)?;
+            self.trace_store(addr, b as u32, 1);
+            self.with_hooks(|h| h.store(addr, 1, b as u32));
+        }
+        Ok(())
+    }
 
-        Err(Error::MemoryOutOfBounds)
+    /// The word address reserved by the last LR, if the reservation is
+    /// still valid.
+    pub fn reservation(&self) -> Option<u32> {
+        self.reservation
     }
 
-    pub fn store32(&mut self, addr: u32, val: u32) -> Result<()> {
-        if let Some(last_addr) = addr.checked_add(3) {
-            if (last_addr as usize) < self.memory.len() {
-                self.memory[addr as usize] = (val & 0xFF) as u8;
-                self.memory[(addr+1) as usize] = ((val >> 8) & 0xFF) as u8;
-                self.memory[(addr+2) as usize] = ((val >> 16) & 0xFF) as u8;
-                self.memory[(addr+3) as usize] = ((val >> 24) & 0xFF) as u8;
-                return Ok(());
+    /// Drop any outstanding LR reservation, so that the next SC fails.
+    ///
+    /// Embedders that multiplex several guest threads onto one `Machine`
+    /// should call this on every context switch.
+    pub fn clear_reservation(&mut self) {
+        self.reservation = None;
+    }
+
+    // Any store that overlaps the reserved word breaks the reservation,
+    // whether it comes from this hart or from the embedder.
+    fn invalidate_reservation(&mut self, addr: u32, len: u32) {
+        if let Some(resv) = self.reservation {
+            let first = addr & !3;
+            let last = addr.wrapping_add(len.max(1) - 1) & !3;
+            if resv.wrapping_sub(first) <= last.wrapping_sub(first) {
+                self.reservation = None;
             }
         }
+    }
 
-        Err(Error::MemoryOutOfBounds)
+    /// Fetch the instruction at `addr`, returning its bits and length.
+    ///
+    /// A compressed instruction is fetched as a single halfword, so it can
+    /// sit in the last two bytes of memory.
+    pub fn fetch(&mut self, addr: u32) -> Result<(u32, u32)> {
+        let lo = self.memory.fetch16(addr)?;
+        match decode::instruction_length(lo) {
+            2 => Ok((lo as u32, 2)),
+            _ => {
+                let hi = self.memory.fetch16(addr.wrapping_add(2))?;
+                Ok((lo as u32 | ((hi as u32) << 16), 4))
+            }
+        }
     }
 
     pub fn get_reg(&self, reg: Reg) -> u32 {
@@ -122,7 +265,10 @@ impl Machine {
     pub fn set_reg(&mut self, reg: Reg, val: u32) {
         let num = reg.num() as usize;
         if num > 0 {
+            let old = self.iregs[num - 1];
+            self.history_reg(num as u8, old);
             self.iregs[num - 1] = val;
+            self.trace_reg(num as u8, val);
         }
     }
 
@@ -148,11 +294,186 @@ impl Machine {
         }
     }
 
+    // Common part of the CSR instructions. The CSR is only read when `read`
+    // is set, which the write forms leave off when rd is x0, and only
+    // written when `write` is set, which the set/clear forms leave off when
+    // their source is x0 or zero.
+    fn csr_op<F>(&mut self, rd: Reg, csr: Csr, src: u32, read: bool, write: bool, f: F)
+        -> Result<()>
+        where F: FnOnce(u32, u32) -> u32,
+    {
+        let old = if read { self.csrs.read(csr)? } else { 0 };
+        if write {
+            self.csrs.write(csr, f(old, src))?;
+        }
+        self.set_reg(rd, old);
+        Ok(())
+    }
+
+    fn amo<F>(&mut self, op: &AOperands, f: F) -> StepResult<()>
+        where F: FnOnce(u32, u32) -> u32,
+    {
+        self.atomic(|m| {
+            let addr = m.get_reg(op.rs1);
+            let store_fault = |e| Exception::store(e, addr);
+            check_aligned(addr).map_err(store_fault)?;
+            let old = m.load32(addr).map_err(store_fault)?;
+            let res = f(old, m.get_reg(op.rs2));
+            m.store32(addr, res).map_err(store_fault)?;
+            m.set_reg(op.rd, old);
+            Ok(())
+        })
+    }
+
+    // Run `f` with any other harts on the bus held off.
+    fn atomic<F>(&mut self, f: F) -> StepResult<()>
+        where F: FnOnce(&mut Self) -> StepResult<()>,
+    {
+        self.memory.begin_atomic();
+        let res = f(self);
+        self.memory.end_atomic();
+        res
+    }
+
+    /// Enter the trap handler at `mtvec`, as if the instruction at `pc`
+    /// had raised `cause`.
+    pub fn take_trap(&mut self, cause: Cause, tval: u32) {
+        let mstatus = self.csrs.read(csr::MSTATUS).unwrap();
+        let mpie = if mstatus & csr::MSTATUS_MIE != 0 { csr::MSTATUS_MPIE } else { 0 };
+        let mstatus = (mstatus & !(csr::MSTATUS_MIE | csr::MSTATUS_MPIE)) | mpie;
+
+        // None of these are read-only, so the writes can't fail.
+        self.csrs.write(csr::MSTATUS, mstatus).unwrap();
+        self.csrs.write(csr::MEPC, self.pc).unwrap();
+        self.csrs.write(csr::MCAUSE, cause as u32).unwrap();
+        self.csrs.write(csr::MTVAL, tval).unwrap();
+
+        // Exceptions go to the base address even in vectored mode.
+        self.pc = self.csrs.read(csr::MTVEC).unwrap() & !0b11;
+        self.reservation = None;
+    }
+
     pub fn step(&mut self) -> Result<StepOutcome> {
-        let mut next_pc = self.pc.wrapping_add(4);
+        self.history_begin();
+        let res = self.step_inner();
+        self.history_commit(!matches!(res, Ok(StepOutcome::OutOfFuel) | Err(_)));
+        res
+    }
+
+    fn step_inner(&mut self) -> Result<StepOutcome> {
+        let pc = self.pc;
+        match self.execute() {
+            Ok((StepOutcome::OutOfFuel, _, _)) => Ok(StepOutcome::OutOfFuel),
+
+            Ok((StepOutcome::Syscall, next_pc, cost))
+                if self.ecall_handled() =>
+            {
+                // The handler sees pc past the ECALL, and what it does is
+                // logged as part of the ECALL.
+                self.advance(next_pc, cost);
+                self.dispatch_ecall();
+                self.trace_commit(pc);
+                Ok(StepOutcome::Running)
+            }
+
+            Ok((StepOutcome::Syscall, _, _)) if self.trap_mode => {
+                Ok(self.trap(Cause::MachineEcall, 0))
+            }
+
+            Ok((StepOutcome::Breakpoint, _, _)) if self.trap_mode => {
+                Ok(self.trap(Cause::Breakpoint, pc))
+            }
+
+            Ok((outcome, next_pc, cost)) => {
+                self.retire(next_pc, cost);
+                Ok(outcome)
+            }
+
+            Err(ex) => {
+                if self.trap_mode {
+                    Ok(self.trap(ex.cause, ex.tval))
+                } else {
+                    Err(ex.error)
+                }
+            }
+        }
+    }
+
+    // Take a trap in place of retiring the instruction, if there is the
+    // fuel to pay for it.
+    fn trap(&mut self, cause: Cause, tval: u32) -> StepOutcome {
+        let cost = self.fuel_costs.get(Class::Trap);
+        if let Some(ref mut fuel) = self.fuel {
+            if cost > *fuel {
+                return StepOutcome::OutOfFuel;
+            }
+            *fuel -= cost;
+        }
+        self.take_trap(cause, tval);
+        StepOutcome::Trap(cause)
+    }
+
+    fn retire(&mut self, next_pc: u32, cost: u64) {
+        self.trace_commit(self.pc);
+        self.advance(next_pc, cost);
+    }
+
+    // Retire the current instruction without logging it.
+    fn advance(&mut self, next_pc: u32, cost: u64) {
+        self.pc = next_pc;
+        self.csrs.retire();
+        if let Some(ref mut fuel) = self.fuel {
+            *fuel -= cost;
+        }
+    }
+
+    // Execute one instruction, returning the outcome, the next pc and the
+    // fuel it costs, without updating `self.pc` or the fuel.
+    fn execute(&mut self) -> StepResult<(StepOutcome, u32, u64)> {
+        if self.pc & 1 != 0 {
+            return Err(Exception::fetch(Error::MisalignedAccess, self.pc));
+        }
+
+        let pc = self.pc;
+        let (bits, len, cached) = match self.icache.get(pc) {
+            Some(d) => (d.bits, d.len, Some(d.inst.clone())),
+            None => {
+                let (bits, len) = self.fetch(pc).map_err(|e| Exception::fetch(e, pc))?;
+                (bits, len, None)
+            }
+        };
+        let was_cached = cached.is_some();
+        let decoded = match cached {
+            Some(inst) => Ok(inst),
+            None => decode::decode(bits),
+        };
+
+        // An instruction short of fuel isn't reported to the trace or the
+        // hooks, since it doesn't run until the step is retried.
+        let cost = match (decoded.as_ref(), self.fuel) {
+            (Ok(inst), Some(fuel)) => {
+                let cost = self.fuel_costs.cost(inst);
+                if cost > fuel {
+                    return Ok((StepOutcome::OutOfFuel, pc, 0));
+                }
+                cost
+            }
+            _ => 0,
+        };
+
+        self.trace_begin(bits, len);
+        self.with_hooks(|h| h.pre_decode(pc, bits));
+        let illegal = |e| Exception::illegal(e, bits);
+
+        let mut next_pc = self.pc.wrapping_add(len);
         let mut outcome = StepOutcome::Running;
 
-        match decode::decode(self.load32(self.pc)?)? {
+        let inst = decoded.map_err(illegal)?;
+        if !was_cached {
+            self.cache_decoded(pc, bits, len, &inst);
+        }
+
+        match inst {
             ADDI(ref op) => self.op_imm(op, |x, y| x.wrapping_add(y)),
             ANDI(ref op) => self.op_imm(op, |x, y| x & y),
              ORI(ref op) => self.op_imm(op, |x, y| x | y),
@@ -174,9 +495,9 @@ impl Machine {
             AND(ref op) => self.op_reg(op, |x, y| x & y),
              OR(ref op) => self.op_reg(op, |x, y| x | y),
             XOR(ref op) => self.op_reg(op, |x, y| x ^ y),
-            SLL(ref op) => self.op_reg(op, |x, y| x << y),
-            SRL(ref op) => self.op_reg(op, |x, y| x >> y),
-            SRA(ref op) => self.op_reg(op, |x, y| ((x as i32) >> y) as u32),
+            SLL(ref op) => self.op_reg(op, |x, y| x << (y & 0b_11111)),
+            SRL(ref op) => self.op_reg(op, |x, y| x >> (y & 0b_11111)),
+            SRA(ref op) => self.op_reg(op, |x, y| ((x as i32) >> (y & 0b_11111)) as u32),
 
             SLT(ref op) => self.op_reg(op, |x, y| {
                 if (x as i32) < (y as i32) { 1 } else { 0 }
@@ -186,6 +507,34 @@ impl Machine {
                 if x < y { 1 } else { 0 }
             }),
 
+            MUL(ref op) => self.op_reg(op, |x, y| x.wrapping_mul(y)),
+
+            MULH(ref op) => self.op_reg(op, |x, y| {
+                ((x as i32 as i64 * y as i32 as i64) >> 32) as u32
+            }),
+
+            MULHSU(ref op) => self.op_reg(op, |x, y| {
+                ((x as i32 as i64 * y as i64) >> 32) as u32
+            }),
+
+            MULHU(ref op) => self.op_reg(op, |x, y| {
+                ((x as u64 * y as u64) >> 32) as u32
+            }),
+
+            // Division by zero and signed overflow don't trap; the results
+            // are fixed by the spec.
+            DIV(ref op) => self.op_reg(op, |x, y| {
+                if y == 0 { !0 } else { (x as i32).wrapping_div(y as i32) as u32 }
+            }),
+
+            DIVU(ref op) => self.op_reg(op, |x, y| x.checked_div(y).unwrap_or(!0)),
+
+            REM(ref op) => self.op_reg(op, |x, y| {
+                if y == 0 { x } else { (x as i32).wrapping_rem(y as i32) as u32 }
+            }),
+
+            REMU(ref op) => self.op_reg(op, |x, y| x.checked_rem(y).unwrap_or(x)),
+
             LUI(ref op) => self.set_reg(op.rd, op.imm),
 
             AUIPC(ref op) => {
@@ -212,52 +561,137 @@ impl Machine {
 
             LW(ref op) => {
                 let addr = self.get_reg(op.rs1).wrapping_add(op.imm);
-                let val = self.load32(addr)?;
+                let val = self.load32(addr).map_err(|e| Exception::load(e, addr))?;
                 self.set_reg(op.rd, val);
             }
 
             LH(ref op) => {
                 let addr = self.get_reg(op.rs1).wrapping_add(op.imm);
-                let val = self.load16(addr)? as i16;
+                let val = self.load16(addr).map_err(|e| Exception::load(e, addr))? as i16;
                 self.set_reg(op.rd, val as i32 as u32);
             }
 
             LHU(ref op) => {
                 let addr = self.get_reg(op.rs1).wrapping_add(op.imm);
-                let val = self.load16(addr)?;
+                let val = self.load16(addr).map_err(|e| Exception::load(e, addr))?;
                 self.set_reg(op.rd, val as u32);
             }
 
             LB(ref op) => {
                 let addr = self.get_reg(op.rs1).wrapping_add(op.imm);
-                let val = self.load8(addr)? as i8;
+                let val = self.load8(addr).map_err(|e| Exception::load(e, addr))? as i8;
                 self.set_reg(op.rd, val as i32 as u32);
             }
 
             LBU(ref op) => {
                 let addr = self.get_reg(op.rs1).wrapping_add(op.imm);
-                let val = self.load8(addr)?;
+                let val = self.load8(addr).map_err(|e| Exception::load(e, addr))?;
                 self.set_reg(op.rd, val as u32);
             }
 
             SW(ref op) => {
                 let addr = self.get_reg(op.rs1).wrapping_add(op.imm);
                 let val = self.get_reg(op.rs2);
-                self.store32(addr, val)?;
+                self.store32(addr, val).map_err(|e| Exception::store(e, addr))?;
             }
 
             SH(ref op) => {
                 let addr = self.get_reg(op.rs1).wrapping_add(op.imm);
                 let val = self.get_reg(op.rs2) & 0xFFFF;
-                self.store16(addr, val as u16)?;
+                self.store16(addr, val as u16).map_err(|e| Exception::store(e, addr))?;
             }
 
             SB(ref op) => {
                 let addr = self.get_reg(op.rs1).wrapping_add(op.imm);
                 let val = self.get_reg(op.rs2) & 0xFF;
-                self.store8(addr, val as u8)?;
+                self.store8(addr, val as u8).map_err(|e| Exception::store(e, addr))?;
+            }
+
+            LR(ref op) => self.atomic(|m| {
+                let addr = m.get_reg(op.rs1);
+                let load_fault = |e| Exception::load(e, addr);
+                check_aligned(addr).map_err(load_fault)?;
+                let val = m.load32(addr).map_err(load_fault)?;
+                m.set_reg(op.rd, val);
+                m.reservation = Some(addr);
+                m.memory.reserve(addr);
+                Ok(())
+            })?,
+
+            // SC succeeds only if the reservation from the matching LR is
+            // still intact, here and on the bus. Either way the
+            // reservation is consumed.
+            SC(ref op) => self.atomic(|m| {
+                let addr = m.get_reg(op.rs1);
+                let store_fault = |e| Exception::store(e, addr);
+                check_aligned(addr).map_err(store_fault)?;
+                if m.reservation == Some(addr) && m.memory.reserved(addr) {
+                    let val = m.get_reg(op.rs2);
+                    m.store32(addr, val).map_err(store_fault)?;
+                    m.set_reg(op.rd, 0);
+                } else {
+                    m.set_reg(op.rd, 1);
+                }
+                m.reservation = None;
+                Ok(())
+            })?,
+
+            AMOSWAP(ref op) => self.amo(op, |_, y| y)?,
+             AMOADD(ref op) => self.amo(op, |x, y| x.wrapping_add(y))?,
+             AMOXOR(ref op) => self.amo(op, |x, y| x ^ y)?,
+             AMOAND(ref op) => self.amo(op, |x, y| x & y)?,
+              AMOOR(ref op) => self.amo(op, |x, y| x | y)?,
+             AMOMIN(ref op) => self.amo(op, |x, y| (x as i32).min(y as i32) as u32)?,
+             AMOMAX(ref op) => self.amo(op, |x, y| (x as i32).max(y as i32) as u32)?,
+            AMOMINU(ref op) => self.amo(op, |x, y| x.min(y))?,
+            AMOMAXU(ref op) => self.amo(op, |x, y| x.max(y))?,
+
+            CSRRW(ref op) => {
+                let src = self.get_reg(op.rs1);
+                let read = op.rd.num() != 0;
+                self.csr_op(op.rd, op.csr, src, read, true, |_, y| y).map_err(illegal)?;
+            }
+
+            CSRRS(ref op) => {
+                let src = self.get_reg(op.rs1);
+                let write = op.rs1.num() != 0;
+                self.csr_op(op.rd, op.csr, src, true, write, |x, y| x | y).map_err(illegal)?;
+            }
+
+            CSRRC(ref op) => {
+                let src = self.get_reg(op.rs1);
+                let write = op.rs1.num() != 0;
+                self.csr_op(op.rd, op.csr, src, true, write, |x, y| x & !y).map_err(illegal)?;
+            }
+
+            CSRRWI(ref op) => {
+                let read = op.rd.num() != 0;
+                self.csr_op(op.rd, op.csr, op.uimm, read, true, |_, y| y).map_err(illegal)?;
+            }
+
+            CSRRSI(ref op) => {
+                let write = op.uimm != 0;
+                self.csr_op(op.rd, op.csr, op.uimm, true, write, |x, y| x | y).map_err(illegal)?;
+            }
+
+            CSRRCI(ref op) => {
+                let write = op.uimm != 0;
+                self.csr_op(op.rd, op.csr, op.uimm, true, write, |x, y| x & !y).map_err(illegal)?;
+            }
+
+            MRET => {
+                let mstatus = self.csrs.read(csr::MSTATUS).map_err(illegal)?;
+                let mie = if mstatus & csr::MSTATUS_MPIE != 0 { csr::MSTATUS_MIE } else { 0 };
+                let mstatus = (mstatus & !csr::MSTATUS_MIE) | mie | csr::MSTATUS_MPIE;
+                self.csrs.write(csr::MSTATUS, mstatus).map_err(illegal)?;
+                next_pc = self.csrs.read(csr::MEPC).map_err(illegal)?;
             }
 
+            // Memory is never reordered, so FENCE has nothing to do.
+            // FENCE.I makes code written behind the machine's back visible.
+            FENCE(_) => (),
+            FENCEI => self.flush_code(),
+
             ECALL => {
                 outcome = StepOutcome::Syscall;
             }
@@ -267,8 +701,238 @@ impl Machine {
             }
         }
 
-        self.pc = next_pc;
+        if self.hooks.is_some() {
+            self.with_hooks(|h| h.post_execute(pc, &inst));
+            let taken = match inst {
+                JAL(_) | JALR(_) => true,
+                BEQ(_) | BNE(_) | BLT(_) | BLTU(_) | BGE(_) | BGEU(_) => {
+                    next_pc != pc.wrapping_add(len)
+                }
+                _ => false,
+            };
+            if taken {
+                self.with_hooks(|h| h.branch(pc, next_pc));
+            }
+        }
+
+        Ok((outcome, next_pc, cost))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{csr, Machine, StepOutcome};
+    use super::bus::SystemBus;
+    use super::trap::Cause;
+    use decode::Reg;
+
+    // Run a single R-type instruction "op a0, a1, a2" and return a0.
+    fn run_op(funct7: u32, funct3: u32, x: u32, y: u32) -> u32 {
+        let bits = (funct7 << 25) | (12 << 20) | (11 << 15)
+                 | (funct3 << 12) | (10 << 7) | 0b01_100_11;
+
+        let mut m = Machine::with_memory(4);
+        m.store32(0, bits).unwrap();
+        m.set_reg(Reg::a1(), x);
+        m.set_reg(Reg::a2(), y);
+        m.step().unwrap();
+        m.get_reg(Reg::a0())
+    }
+
+    fn machine_with(program: &[u32]) -> Machine {
+        let mut m = Machine::with_memory(256);
+        for (i, &word) in program.iter().enumerate() {
+            m.store32(4*i as u32, word).unwrap();
+        }
+        m
+    }
+
+    #[test]
+    fn test_mul() {
+        assert_eq!(42, run_op(1, 0b000, 6, 7));
+        assert_eq!(0xFFFF_FFFF, run_op(1, 0b001, !0, 1));
+        assert_eq!(0xFFFF_FFFF, run_op(1, 0b010, !0, 1));
+        assert_eq!(0x0000_0000, run_op(1, 0b011, !0, 1));
+        assert_eq!(0xFFFF_FFFE, run_op(1, 0b011, !0, !0));
+        assert_eq!(0x0000_0000, run_op(1, 0b001, !0, !0));
+        assert_eq!(0xFFFF_FFFF, run_op(1, 0b010, !0, !0));
+    }
+
+    #[test]
+    fn test_div_by_zero() {
+        assert_eq!(0xFFFF_FFFF, run_op(1, 0b100, 7, 0));
+        assert_eq!(0xFFFF_FFFF, run_op(1, 0b101, 7, 0));
+        assert_eq!(7, run_op(1, 0b110, 7, 0));
+        assert_eq!(7, run_op(1, 0b111, 7, 0));
+    }
+
+    #[test]
+    fn test_div_overflow() {
+        assert_eq!(0x8000_0000, run_op(1, 0b100, 0x8000_0000, !0));
+        assert_eq!(0, run_op(1, 0b110, 0x8000_0000, !0));
+        assert_eq!(-3i32 as u32, run_op(1, 0b100, -7i32 as u32, 2));
+        assert_eq!(-1i32 as u32, run_op(1, 0b110, -7i32 as u32, 2));
+    }
+
+    #[test]
+    fn test_shift_amount_masked() {
+        assert_eq!(2, run_op(0, 0b001, 1, 33));
+        assert_eq!(1, run_op(0, 0b101, 2, 33));
+    }
+
+    // a0 = address, a1 = value
+    const LR_A2: u32 = 0x1005262f;       // lr.w      a2,(a0)
+    const SC_A3: u32 = 0x18b526af;       // sc.w      a3,a1,(a0)
+    const AMOADD_A4: u32 = 0x00b5272f;   // amoadd.w  a4,a1,(a0)
+
+    #[test]
+    fn test_lr_sc() {
+        let mut m = machine_with(&[LR_A2, SC_A3]);
+        m.store32(0x80, 5).unwrap();
+        m.set_reg(Reg::a0(), 0x80);
+        m.set_reg(Reg::a1(), 9);
+        m.step().unwrap();
+        assert_eq!(5, m.get_reg(Reg::a2()));
+        assert_eq!(Some(0x80), m.reservation());
+        m.step().unwrap();
+        assert_eq!(0, m.get_reg(Reg::a3()));
+        assert_eq!(9, m.load32(0x80).unwrap());
+        assert_eq!(None, m.reservation());
+    }
+
+    #[test]
+    fn test_sc_fails_after_store() {
+        let mut m = machine_with(&[LR_A2, SC_A3]);
+        m.set_reg(Reg::a0(), 0x80);
+        m.set_reg(Reg::a1(), 9);
+        m.step().unwrap();
+        m.store8(0x83, 1).unwrap();
+        m.step().unwrap();
+        assert_eq!(1, m.get_reg(Reg::a3()));
+        assert_eq!(0x0100_0000, m.load32(0x80).unwrap());
+    }
+
+    #[test]
+    fn test_amo() {
+        let mut m = machine_with(&[AMOADD_A4]);
+        m.store32(0x80, 5).unwrap();
+        m.set_reg(Reg::a0(), 0x80);
+        m.set_reg(Reg::a1(), 9);
+        m.step().unwrap();
+        assert_eq!(5, m.get_reg(Reg::a4()));
+        assert_eq!(14, m.load32(0x80).unwrap());
+
+        m.pc = 0;
+        m.set_reg(Reg::a0(), 0x82);
+        assert!(m.step().is_err());
+    }
+
+    #[test]
+    fn test_compressed_fetch() {
+        // c.li a0,5 ; c.jal +4 ; (skipped) ; c.addi a0,1
+        let mut m = Machine::with_memory(8);
+        m.store32(0, 0x20114515).unwrap();
+        m.store32(4, 0x05050001).unwrap();
+        m.step().unwrap();
+        assert_eq!((5, 2), (m.get_reg(Reg::a0()), m.pc));
+        m.step().unwrap();
+        assert_eq!((4, 6), (m.get_reg(Reg::ra()), m.pc));
+        m.step().unwrap();
+        assert_eq!((6, 8), (m.get_reg(Reg::a0()), m.pc));
+    }
+
+    #[test]
+    fn test_csr_instructions() {
+        let mut m = machine_with(&[
+            0x34051073,  // csrw    mscratch,a0
+            0x340025f3,  // csrr    a1,mscratch
+            0x3402f673,  // csrrci  a2,mscratch,5
+            0xc0202773,  // rdinstret a4
+            0xf1401073,  // csrw    mhartid,zero
+        ]);
+        m.set_reg(Reg::a0(), 0xFF);
+        for _ in 0..4 {
+            m.step().unwrap();
+        }
+        assert_eq!(0xFF, m.get_reg(Reg::a1()));
+        assert_eq!(0xFF, m.get_reg(Reg::a2()));
+        assert_eq!(0xFA, m.csrs.read(csr::MSCRATCH).unwrap());
+        assert_eq!(3, m.get_reg(Reg::a4()));
+        assert!(m.step().is_err());
+    }
+
+    #[test]
+    fn test_csr_counter_write() {
+        let mut m = machine_with(&[
+            0xb0251073,  // csrw    minstret,a0
+            0xb02025f3,  // csrr    a1,minstret
+            0xb0051073,  // csrw    mcycle,a0
+            0xb0002673,  // csrr    a2,mcycle
+        ]);
+        m.set_reg(Reg::a0(), 100);
+        for _ in 0..4 {
+            m.step().unwrap();
+        }
+        // The write takes the place of the writing instruction's increment.
+        assert_eq!(100, m.get_reg(Reg::a1()));
+        assert_eq!(100, m.get_reg(Reg::a2()));
+        assert_eq!(103, m.csrs.read(csr::MINSTRET).unwrap());
+        assert_eq!(101, m.csrs.read(csr::MCYCLE).unwrap());
+    }
+
+    #[test]
+    fn test_trap_mode() {
+        let mut m = machine_with(&[
+            0x00000073,  // ecall
+            0xffffffff,  // (illegal)
+            0x00052503,  // lw      a0,0(a0)
+        ]);
+        m.trap_mode = true;
+        m.csrs.write(csr::MTVEC, 0x80).unwrap();
+        m.csrs.write(csr::MSTATUS, csr::MSTATUS_MIE).unwrap();
+        m.store32(0x80, 0x30200073).unwrap();  // mret
+
+        assert_eq!(StepOutcome::Trap(Cause::MachineEcall), m.step().unwrap());
+        assert_eq!(0x80, m.pc);
+        assert_eq!(0, m.csrs.read(csr::MEPC).unwrap());
+        assert_eq!(11, m.csrs.read(csr::MCAUSE).unwrap());
+        assert_eq!(csr::MSTATUS_MPIE | csr::MSTATUS_MPP,
+                   m.csrs.read(csr::MSTATUS).unwrap());
+
+        // The handler skips the faulting instruction before returning.
+        m.csrs.write(csr::MEPC, 4).unwrap();
+        assert_eq!(StepOutcome::Running, m.step().unwrap());
+        assert_eq!(4, m.pc);
+        assert_eq!(csr::MSTATUS_MIE | csr::MSTATUS_MPIE | csr::MSTATUS_MPP,
+                   m.csrs.read(csr::MSTATUS).unwrap());
+
+        assert_eq!(StepOutcome::Trap(Cause::IllegalInstruction), m.step().unwrap());
+        assert_eq!(0xffffffff, m.csrs.read(csr::MTVAL).unwrap());
+
+        m.pc = 8;
+        m.set_reg(Reg::a0(), 0x1000);
+        assert_eq!(StepOutcome::Trap(Cause::LoadAccessFault), m.step().unwrap());
+        assert_eq!(8, m.csrs.read(csr::MEPC).unwrap());
+        assert_eq!(0x1000, m.csrs.read(csr::MTVAL).unwrap());
+    }
 
-        Ok(outcome)
+    #[test]
+    fn test_system_bus() {
+        let mut bus = SystemBus::new();
+        bus.map_rom(0x1000, &[0x13, 0x05, 0x50, 0x00]).unwrap();  // li a0,5
+        bus.map_ram(0x8000_0000, 0x1000).unwrap();
+
+        let mut m = Machine::new(bus);
+        m.store32(0x8000_0000, 0x00a5a023).unwrap();  // sw a0,0(a1)
+        m.set_reg(Reg::a1(), 0x8000_0100);
+        m.pc = 0x1000;
+        m.step().unwrap();
+        m.pc = 0x8000_0000;
+        m.step().unwrap();
+        assert_eq!(5, m.load32(0x8000_0100).unwrap());
+
+        m.set_reg(Reg::a1(), 0x1000);
+        m.pc = 0x8000_0000;
+        assert!(m.step().is_err());
     }
 }
@@ -0,0 +1,304 @@
+//! Running a machine until something interesting happens.
+
+use decode;
+use decode::Instruction::*;
+use emu::{Machine, StepOutcome};
+use emu::bus::{Access, Bus};
+use Result;
+
+/// Stops when an access overlaps `len` bytes at `addr`, before the
+/// instruction making it runs.
+///
+/// An execute watchpoint fires when the instruction itself overlaps the
+/// range; read and write ones look at the data the instruction is about
+/// to load or store. AMOs count as both.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Watchpoint {
+    pub addr: u32,
+    pub len: u32,
+    pub read: bool,
+    pub write: bool,
+    pub exec: bool,
+}
+
+impl Watchpoint {
+    pub fn on_read(addr: u32, len: u32) -> Watchpoint {
+        Watchpoint { addr, len, read: true, write: false, exec: false }
+    }
+
+    pub fn on_write(addr: u32, len: u32) -> Watchpoint {
+        Watchpoint { addr, len, read: false, write: true, exec: false }
+    }
+
+    pub fn on_access(addr: u32, len: u32) -> Watchpoint {
+        Watchpoint { addr, len, read: true, write: true, exec: false }
+    }
+
+    pub fn on_exec(addr: u32, len: u32) -> Watchpoint {
+        Watchpoint { addr, len, read: false, write: false, exec: true }
+    }
+
+    fn overlaps(&self, addr: u32, len: u32) -> bool {
+        // Compare offsets from our start, so ranges can wrap around.
+        let offset = addr.wrapping_sub(self.addr);
+        offset < self.len || self.addr.wrapping_sub(addr) < len
+    }
+}
+
+/// The pc breakpoints and watchpoints for `Machine::run_until`.
+#[derive(Clone, Debug, Default)]
+pub struct Breakpoints {
+    pcs: Vec<u32>,
+    watchpoints: Vec<Watchpoint>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pcs.is_empty() && self.watchpoints.is_empty()
+    }
+
+    pub fn add_breakpoint(&mut self, pc: u32) {
+        if !self.pcs.contains(&pc) {
+            self.pcs.push(pc);
+        }
+    }
+
+    /// Returns whether there was a breakpoint at `pc`.
+    pub fn remove_breakpoint(&mut self, pc: u32) -> bool {
+        let len = self.pcs.len();
+        self.pcs.retain(|&p| p != pc);
+        self.pcs.len() != len
+    }
+
+    pub fn breakpoints(&self) -> &[u32] {
+        &self.pcs
+    }
+
+    pub fn add_watchpoint(&mut self, w: Watchpoint) {
+        self.watchpoints.push(w);
+    }
+
+    /// Remove one watchpoint equal to `w`, returning whether there was one.
+    pub fn remove_watchpoint(&mut self, w: &Watchpoint) -> bool {
+        match self.watchpoints.iter().position(|x| x == w) {
+            Some(i) => {
+                self.watchpoints.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+}
+
+/// Why `Machine::run` or `Machine::run_until` returned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The machine stopped by itself: `pc` is past an ECALL or EBREAK, or
+    /// at an instruction there wasn't enough fuel for.
+    Outcome(StepOutcome),
+
+    /// `pc` reached a breakpoint.
+    Breakpoint(u32),
+
+    /// The next instruction would make an access matching `watchpoint`.
+    /// `addr` is the start of the access, and `pc` still points at the
+    /// instruction.
+    Watchpoint {
+        watchpoint: Watchpoint,
+        addr: u32,
+        access: Access,
+    },
+
+    /// The instruction budget ran out.
+    BudgetExhausted,
+
+    /// `Machine::reverse_continue` undid every step it had recorded.
+    EndOfHistory,
+}
+
+impl<B: Bus> Machine<B> {
+    /// Run at most `budget` instructions, stopping early at ECALL or
+    /// EBREAK, or when the fuel runs out. Traps taken in trap mode don't
+    /// stop it.
+    pub fn run(&mut self, budget: u64) -> Result<StopReason> {
+        self.run_until(&Breakpoints::new(), budget)
+    }
+
+    /// Like `run`, but also stop at the breakpoints and watchpoints in
+    /// `stops`.
+    ///
+    /// These are checked before each instruction. Breakpoints and execute
+    /// watchpoints are skipped for the first one, so that a run can resume
+    /// from where the last one stopped. Read and write watchpoints are
+    /// not: as with hardware watchpoints, step past the access with `step`
+    /// before resuming. When a stop and the end of the budget coincide,
+    /// the stop is reported. Errors from `step` are returned as they are,
+    /// with `pc` at the faulting instruction.
+    pub fn run_until(&mut self, stops: &Breakpoints, budget: u64) -> Result<StopReason> {
+        self.run_counted(stops, budget, &mut 0)
+    }
+
+    // `run_until`, leaving in `count` how many instructions it tried.
+    pub(super) fn run_counted(&mut self, stops: &Breakpoints, budget: u64, count: &mut u64)
+        -> Result<StopReason>
+    {
+        *count = 0;
+        loop {
+            if !stops.is_empty() {
+                if let Some(stop) = self.check_stops(stops, *count == 0) {
+                    return Ok(stop);
+                }
+            }
+            if *count == budget {
+                return Ok(StopReason::BudgetExhausted);
+            }
+            #[cfg(all(feature = "jit", target_arch = "x86_64", unix))]
+            {
+                if stops.is_empty() {
+                    *count += self.jit_run(budget - *count);
+                    if *count == budget {
+                        continue;
+                    }
+                }
+            }
+            *count += 1;
+
+            match self.step()? {
+                StepOutcome::Running | StepOutcome::Trap(_) => (),
+                outcome => return Ok(StopReason::Outcome(outcome)),
+            }
+        }
+    }
+
+    pub(super) fn check_stops(&mut self, stops: &Breakpoints, resuming: bool) -> Option<StopReason> {
+        let pc = self.pc;
+        if !resuming && stops.pcs.contains(&pc) {
+            return Some(StopReason::Breakpoint(pc));
+        }
+        if stops.watchpoints.is_empty() {
+            return None;
+        }
+
+        let (bits, len) = self.fetch(pc).ok()?;
+        for w in &stops.watchpoints {
+            if !resuming && w.exec && w.overlaps(pc, len) {
+                return Some(StopReason::Watchpoint {
+                    watchpoint: *w,
+                    addr: pc,
+                    access: Access::Fetch,
+                });
+            }
+        }
+
+        let (addr, size, reads, writes) = self.data_access(bits)?;
+        for w in &stops.watchpoints {
+            if !w.overlaps(addr, size) {
+                continue;
+            }
+            let access = if w.write && writes {
+                Access::Store
+            } else if w.read && reads {
+                Access::Load
+            } else {
+                continue;
+            };
+            return Some(StopReason::Watchpoint { watchpoint: *w, addr, access });
+        }
+        None
+    }
+
+    // The data the instruction `bits` would touch if run now: its address
+    // and size, and whether it would be read and written.
+    fn data_access(&self, bits: u32) -> Option<(u32, u32, bool, bool)> {
+        let reg = |r| self.get_reg(r);
+        match decode::decode(bits).ok()? {
+            LB(ref op) | LBU(ref op) => Some((reg(op.rs1).wrapping_add(op.imm), 1, true, false)),
+            LH(ref op) | LHU(ref op) => Some((reg(op.rs1).wrapping_add(op.imm), 2, true, false)),
+            LW(ref op) => Some((reg(op.rs1).wrapping_add(op.imm), 4, true, false)),
+            SB(ref op) => Some((reg(op.rs1).wrapping_add(op.imm), 1, false, true)),
+            SH(ref op) => Some((reg(op.rs1).wrapping_add(op.imm), 2, false, true)),
+            SW(ref op) => Some((reg(op.rs1).wrapping_add(op.imm), 4, false, true)),
+            LR(ref op) => Some((reg(op.rs1), 4, true, false)),
+            SC(ref op) => Some((reg(op.rs1), 4, false, true)),
+            AMOSWAP(ref op) | AMOADD(ref op) | AMOXOR(ref op) | AMOAND(ref op)
+                | AMOOR(ref op) | AMOMIN(ref op) | AMOMAX(ref op) | AMOMINU(ref op)
+                | AMOMAXU(ref op) => Some((reg(op.rs1), 4, true, true)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Breakpoints, StopReason, Watchpoint};
+    use asm::assemble;
+    use decode::Reg;
+    use emu::{Machine, StepOutcome};
+    use emu::bus::Access;
+
+    fn machine() -> Machine {
+        let mut m = Machine::with_memory(256);
+        assemble("
+                    addi    a0, a0, 1
+                    addi    a0, a0, 1
+                    sw      a0, 0(a1)
+                    lw      a2, 0(a1)
+                    ecall
+        ", 0).unwrap().load(&mut m).unwrap();
+        m.set_reg(Reg::a1(), 0x82);
+        m
+    }
+
+    #[test]
+    fn test_budget_and_outcome() {
+        let mut m = machine();
+        assert_eq!(StopReason::BudgetExhausted, m.run(2).unwrap());
+        assert_eq!(8, m.pc);
+        assert_eq!(StopReason::Outcome(StepOutcome::Syscall), m.run(100).unwrap());
+        assert_eq!(20, m.pc);
+    }
+
+    #[test]
+    fn test_breakpoints_and_watchpoints() {
+        let mut m = machine();
+        let mut stops = Breakpoints::new();
+        stops.add_breakpoint(4);
+        stops.add_breakpoint(0);
+        let read = Watchpoint::on_read(0x84, 4);
+        stops.add_watchpoint(read);
+        stops.add_watchpoint(Watchpoint::on_write(0x80, 4));
+
+        // The breakpoint at the starting pc is skipped, and a breakpoint
+        // wins over the end of the budget.
+        assert_eq!(StopReason::Breakpoint(4), m.run_until(&stops, 1).unwrap());
+        assert!(stops.remove_breakpoint(4));
+
+        match m.run_until(&stops, 100).unwrap() {
+            StopReason::Watchpoint { watchpoint, addr: 0x82, access: Access::Store } => {
+                assert_eq!(Watchpoint::on_write(0x80, 4), watchpoint);
+            }
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(8, m.pc);
+        m.step().unwrap();
+
+        assert_eq!(StopReason::Watchpoint { watchpoint: read, addr: 0x82, access: Access::Load },
+                   m.run_until(&stops, 100).unwrap());
+        assert_eq!(12, m.pc);
+
+        assert!(stops.remove_watchpoint(&read));
+        stops.add_watchpoint(Watchpoint::on_exec(0x12, 2));
+        match m.run_until(&stops, 100).unwrap() {
+            StopReason::Watchpoint { addr: 16, access: Access::Fetch, .. } => (),
+            r => panic!("{:?}", r),
+        }
+    }
+}
@@ -0,0 +1,432 @@
+//! Several harts sharing one bus.
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+use emu::Machine;
+use emu::bus::{Bus, Perms};
+use emu::csr::CsrFile;
+use emu::run::{Breakpoints, StopReason};
+use Result;
+
+struct Shared<B> {
+    bus: B,
+
+    // The hart inside `begin_atomic`, if any.
+    owner: Option<u32>,
+
+    // The word each hart has reserved with LR, by hart ID.
+    reservations: Vec<Option<u32>>,
+}
+
+/// One hart's handle on a bus shared with others.
+///
+/// Every access locks the bus, so harts on host threads of their own see
+/// each access happen all at once. LR, SC and AMOs hold it for the whole
+/// instruction, and a store breaks the LR reservations other harts hold
+/// on the words it touches.
+///
+/// Harts with the decode cache on keep their own, so as on real
+/// hardware, such a hart only sees code written by another after
+/// executing FENCE.I.
+pub struct SharedBus<B> {
+    hart: u32,
+    shared: Arc<(Mutex<Shared<B>>, Condvar)>,
+}
+
+impl<B> Clone for SharedBus<B> {
+    fn clone(&self) -> SharedBus<B> {
+        SharedBus {
+            hart: self.hart,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<B: Bus> SharedBus<B> {
+    /// Share `bus`, returning the handle for hart 0.
+    pub fn new(bus: B) -> SharedBus<B> {
+        SharedBus {
+            hart: 0,
+            shared: Arc::new((Mutex::new(Shared {
+                bus,
+                owner: None,
+                reservations: vec![],
+            }), Condvar::new())),
+        }
+    }
+
+    /// A handle on the same bus for hart `hart`.
+    pub fn for_hart(&self, hart: u32) -> SharedBus<B> {
+        SharedBus {
+            hart,
+            shared: self.shared.clone(),
+        }
+    }
+
+    pub fn hart(&self) -> u32 {
+        self.hart
+    }
+
+    /// Call `f` with the bus itself, such as to look at a device.
+    pub fn with_bus<T, F: FnOnce(&mut B) -> T>(&self, f: F) -> T {
+        f(&mut self.lock().bus)
+    }
+
+    // Lock the bus once no other hart is inside an atomic section.
+    fn lock(&self) -> MutexGuard<'_, Shared<B>> {
+        let (ref mutex, ref cond) = *self.shared;
+        let mut shared = mutex.lock().unwrap_or_else(|e| e.into_inner());
+        while shared.owner.is_some() && shared.owner != Some(self.hart) {
+            shared = cond.wait(shared).unwrap_or_else(|e| e.into_inner());
+        }
+        shared
+    }
+
+    fn store<F>(&mut self, addr: u32, len: u32, f: F) -> Result<()>
+        where F: FnOnce(&mut B) -> Result<()>,
+    {
+        let mut guard = self.lock();
+        let shared = &mut *guard;
+        f(&mut shared.bus)?;
+
+        let first = addr & !3;
+        let last = addr.wrapping_add(len.max(1) - 1) & !3;
+        for r in &mut shared.reservations {
+            if r.is_some_and(|r| r.wrapping_sub(first) <= last.wrapping_sub(first)) {
+                *r = None;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<B: Bus> Bus for SharedBus<B> {
+    fn load8(&mut self, addr: u32) -> Result<u8> {
+        self.lock().bus.load8(addr)
+    }
+
+    fn load16(&mut self, addr: u32) -> Result<u16> {
+        self.lock().bus.load16(addr)
+    }
+
+    fn load32(&mut self, addr: u32) -> Result<u32> {
+        self.lock().bus.load32(addr)
+    }
+
+    fn store8(&mut self, addr: u32, val: u8) -> Result<()> {
+        self.store(addr, 1, |bus| bus.store8(addr, val))
+    }
+
+    fn store16(&mut self, addr: u32, val: u16) -> Result<()> {
+        self.store(addr, 2, |bus| bus.store16(addr, val))
+    }
+
+    fn store32(&mut self, addr: u32, val: u32) -> Result<()> {
+        self.store(addr, 4, |bus| bus.store32(addr, val))
+    }
+
+    fn fetch16(&mut self, addr: u32) -> Result<u16> {
+        self.lock().bus.fetch16(addr)
+    }
+
+    fn protect(&mut self, addr: u32, len: u32, perms: Perms) -> Result<()> {
+        self.lock().bus.protect(addr, len, perms)
+    }
+
+    fn discard(&mut self, addr: u32, len: u32) -> Result<()> {
+        self.store(addr, len, |bus| bus.discard(addr, len))
+    }
+
+    fn cacheable(&self, addr: u32) -> bool {
+        self.lock().bus.cacheable(addr)
+    }
+
+    fn begin_atomic(&mut self) {
+        self.lock().owner = Some(self.hart);
+    }
+
+    fn end_atomic(&mut self) {
+        let (ref mutex, ref cond) = *self.shared;
+        mutex.lock().unwrap_or_else(|e| e.into_inner()).owner = None;
+        cond.notify_all();
+    }
+
+    fn reserve(&mut self, addr: u32) {
+        let hart = self.hart as usize;
+        let mut shared = self.lock();
+        if shared.reservations.len() <= hart {
+            shared.reservations.resize(hart + 1, None);
+        }
+        shared.reservations[hart] = Some(addr);
+    }
+
+    fn reserved(&mut self, addr: u32) -> bool {
+        self.lock().reservations.get(self.hart as usize) == Some(&Some(addr))
+    }
+
+    /// The shared bus's own state. Reservations aren't saved.
+    fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+        self.lock().bus.save_state(out)
+    }
+
+    fn restore_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.lock().bus.restore_state(input)
+    }
+}
+
+const DEFAULT_QUANTUM: u64 = 100;
+
+/// A system of harts sharing one bus, each with its own pc, registers and
+/// CSRs, and with `mhartid` set to its index.
+///
+/// `run` interleaves the harts deterministically, in turn, so that a run
+/// can be repeated exactly. `run_threaded` runs each on a host thread of
+/// its own instead.
+pub struct Smp<B> {
+    harts: Vec<Machine<SharedBus<B>>>,
+    halted: Vec<bool>,
+    quantum: u64,
+
+    // The hart whose turn it is, and how much of its quantum it has used.
+    current: usize,
+    used: u64,
+}
+
+impl<B: Bus> Smp<B> {
+    /// `count` harts on `bus`, all starting at address 0. Panics if
+    /// `count` is 0.
+    pub fn new(bus: B, count: usize) -> Smp<B> {
+        assert!(count > 0, "an Smp needs at least one hart");
+        let bus = SharedBus::new(bus);
+        let harts = (0..count as u32).map(|i| {
+            let mut m = Machine::new(bus.for_hart(i));
+            m.csrs = CsrFile::new(i);
+            m
+        }).collect();
+
+        Smp {
+            harts,
+            halted: vec![false; count],
+            quantum: DEFAULT_QUANTUM,
+            current: 0,
+            used: 0,
+        }
+    }
+
+    pub fn harts(&self) -> &[Machine<SharedBus<B>>] {
+        &self.harts
+    }
+
+    pub fn harts_mut(&mut self) -> &mut [Machine<SharedBus<B>>] {
+        &mut self.harts
+    }
+
+    /// Call `f` with the bus the harts share.
+    pub fn with_bus<T, F: FnOnce(&mut B) -> T>(&self, f: F) -> T {
+        self.harts[0].memory.with_bus(f)
+    }
+
+    pub fn quantum(&self) -> u64 {
+        self.quantum
+    }
+
+    /// Give each hart up to `quantum` instructions per turn in `run`. The
+    /// default is 100; anything less than 1 counts as 1.
+    pub fn set_quantum(&mut self, quantum: u64) {
+        self.quantum = quantum.max(1);
+    }
+
+    /// Halt or resume `hart`. Halted harts are skipped, like ones parked
+    /// in WFI.
+    pub fn set_halted(&mut self, hart: usize, halted: bool) {
+        self.halted[hart] = halted;
+    }
+
+    pub fn is_halted(&self, hart: usize) -> bool {
+        self.halted[hart]
+    }
+
+    /// The hart whose turn it is in `run`. After an error, it is the one
+    /// that failed.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Run the harts that aren't halted in turn, each for up to `quantum`
+    /// instructions, until `budget` instructions have run in all or one
+    /// of the harts stops as `Machine::run` would. Returns the hart that
+    /// stopped and why, or `StopReason::BudgetExhausted` with the current
+    /// hart, including straight away if every hart is halted.
+    ///
+    /// A hart that stops keeps the rest of its turn, so the next call
+    /// picks up where this one left off.
+    pub fn run(&mut self, budget: u64) -> Result<(usize, StopReason)> {
+        let stops = Breakpoints::new();
+        let mut count = 0;
+        loop {
+            if count == budget || self.halted.iter().all(|&h| h) {
+                return Ok((self.current, StopReason::BudgetExhausted));
+            }
+            if self.halted[self.current] || self.used == self.quantum {
+                self.current = (self.current + 1) % self.harts.len();
+                self.used = 0;
+                continue;
+            }
+
+            let slice = (self.quantum - self.used).min(budget - count);
+            let mut ran = 0;
+            let res = self.harts[self.current].run_counted(&stops, slice, &mut ran);
+            self.used += ran;
+            count += ran;
+            match res? {
+                StopReason::BudgetExhausted => (),
+                reason => return Ok((self.current, reason)),
+            }
+        }
+    }
+
+    /// Run each hart that isn't halted on a host thread of its own, as
+    /// with `Machine::run`, and wait for them all to stop. Returns each
+    /// hart's index and how it stopped.
+    ///
+    /// How the harts interleave is up to the host, so unlike with `run`,
+    /// no two runs need be alike.
+    pub fn run_threaded(&mut self, budget: u64) -> Vec<(usize, Result<StopReason>)>
+        where B: Send,
+    {
+        let (harts, halted) = (&mut self.harts, &self.halted);
+        thread::scope(|s| {
+            let threads: Vec<_> = harts.iter_mut().enumerate()
+                .filter(|&(i, _)| !halted[i])
+                .map(|(i, m)| (i, s.spawn(move || m.run(budget))))
+                .collect();
+            threads.into_iter()
+                .map(|(i, t)| (i, t.join().unwrap()))
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Smp;
+    use asm::assemble;
+    use decode::Reg;
+    use emu::{csr, StepOutcome};
+    use emu::bus::{Bus, Ram};
+    use emu::run::StopReason;
+
+    const HARTS: usize = 4;
+    const ROUNDS: u32 = 50;
+
+    // Each hart adds one to the counter at 0x1004 `ROUNDS` times, taking
+    // the lock at 0x1000 with `acquire` around a plain load and store.
+    fn spinlock(acquire: &str) -> Smp<Ram> {
+        let program = assemble(&format!("
+                    li      a0, 0x1000
+                    li      a2, {}
+                    li      t0, 1
+            loop:
+            {}
+                    lw      t2, 4(a0)
+                    addi    t2, t2, 1
+                    sw      t2, 4(a0)
+                    amoswap.w zero, zero, (a0)
+                    addi    a2, a2, -1
+                    bnez    a2, loop
+                    csrr    a0, mhartid
+                    ecall
+        ", ROUNDS, acquire), 0).unwrap();
+
+        let mut smp = Smp::new(Ram::new(0x2000), HARTS);
+        program.load(&mut smp.harts_mut()[0]).unwrap();
+        smp
+    }
+
+    const AMOSWAP: &str = "
+            acquire: amoswap.w t1, t0, (a0)
+                    bnez    t1, acquire
+    ";
+
+    const LR_SC: &str = "
+            acquire: lr.w   t1, (a0)
+                    bnez    t1, acquire
+                    sc.w    t1, t0, (a0)
+                    bnez    t1, acquire
+    ";
+
+    fn counter(smp: &Smp<Ram>) -> u32 {
+        smp.with_bus(|bus| bus.load32(0x1004).unwrap())
+    }
+
+    #[test]
+    fn test_round_robin() {
+        for &acquire in &[AMOSWAP, LR_SC] {
+            let mut smp = spinlock(acquire);
+            smp.set_quantum(3);
+            let mut stops = vec![];
+            while stops.len() < HARTS {
+                let (hart, reason) = smp.run(1_000_000).unwrap();
+                assert_eq!(StopReason::Outcome(StepOutcome::Syscall), reason);
+                assert_eq!(hart as u32, smp.harts()[hart].get_reg(Reg::a0()));
+                smp.set_halted(hart, true);
+                stops.push(hart);
+            }
+            assert_eq!(HARTS as u32 * ROUNDS, counter(&smp));
+
+            // The same again, with the same result.
+            let mut again = spinlock(acquire);
+            again.set_quantum(3);
+            let mut repeat = vec![];
+            while repeat.len() < HARTS {
+                let (hart, _) = again.run(1_000_000).unwrap();
+                again.set_halted(hart, true);
+                repeat.push(hart);
+            }
+            assert_eq!(stops, repeat);
+            for i in 0..HARTS {
+                assert_eq!(smp.harts()[i].csrs.read(csr::MINSTRET).unwrap(),
+                           again.harts()[i].csrs.read(csr::MINSTRET).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_threads() {
+        for &acquire in &[AMOSWAP, LR_SC] {
+            let mut smp = spinlock(acquire);
+            let results = smp.run_threaded(10_000_000);
+            assert_eq!(HARTS, results.len());
+            for (_, res) in results {
+                assert_eq!(StopReason::Outcome(StepOutcome::Syscall), res.unwrap());
+            }
+            assert_eq!(HARTS as u32 * ROUNDS, counter(&smp));
+        }
+    }
+
+    #[test]
+    fn test_reservations() {
+        let program = assemble("
+                    lr.w    a1, (a0)
+                    sc.w    a2, a1, (a0)
+        ", 0).unwrap();
+        let mut smp = Smp::new(Ram::new(0x100), 2);
+        program.load(&mut smp.harts_mut()[0]).unwrap();
+        let harts = smp.harts_mut();
+        harts[0].set_reg(Reg::a0(), 0x80);
+        harts[1].set_reg(Reg::a0(), 0x80);
+
+        // Hart 1's store breaks hart 0's reservation, but not the one it
+        // takes afterwards.
+        harts[0].step().unwrap();
+        harts[1].store8(0x82, 1).unwrap();
+        harts[1].step().unwrap();
+        harts[0].step().unwrap();
+        assert_eq!(1, harts[0].get_reg(Reg::a2()));
+
+        harts[1].step().unwrap();
+        assert_eq!(0, harts[1].get_reg(Reg::a2()));
+    }
+}
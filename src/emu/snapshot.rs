@@ -0,0 +1,243 @@
+//! Saving a machine's state and restoring it later.
+//!
+//! A snapshot is, with every number little-endian:
+//!
+//! - the magic bytes `minrisc\0` and a `u32` format version;
+//! - the pc and x1 to x31 as `u32`s;
+//! - trap mode as a `u8`, and the LR reservation as a `u8` flag and a
+//!   `u32` address;
+//! - the CSRs: mstatus, mie, mtvec, mscratch, mepc, mcause and mtval as
+//!   `u32`s, mcycle and minstret as `u64`s, then mhartid;
+//! - the bus, as written by `Bus::save_state`.
+//!
+//! The bus layout itself, like which devices are mapped where, is not
+//! saved: a snapshot is restored into a machine built the same way as the
+//! one it was taken from. Host-side settings such as fuel, ECALL
+//! handlers, the trace and hooks aren't part of it either.
+
+use std::io::{self, Read, Write};
+
+use emu::Machine;
+use emu::bus::{Bus, Perms};
+
+const MAGIC: &[u8; 8] = b"minrisc\0";
+
+/// The version of the snapshot format written by `save_snapshot`. Any
+/// other is refused.
+pub const VERSION: u32 = 1;
+
+pub(super) fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub(super) fn write_u8(out: &mut dyn Write, val: u8) -> io::Result<()> {
+    out.write_all(&[val])
+}
+
+pub(super) fn write_u32(out: &mut dyn Write, val: u32) -> io::Result<()> {
+    out.write_all(&val.to_le_bytes())
+}
+
+pub(super) fn write_u64(out: &mut dyn Write, val: u64) -> io::Result<()> {
+    out.write_all(&val.to_le_bytes())
+}
+
+pub(super) fn read_u8(input: &mut dyn Read) -> io::Result<u8> {
+    let mut b = [0; 1];
+    input.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+pub(super) fn read_u32(input: &mut dyn Read) -> io::Result<u32> {
+    let mut b = [0; 4];
+    input.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+pub(super) fn read_u64(input: &mut dyn Read) -> io::Result<u64> {
+    let mut b = [0; 8];
+    input.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+pub(super) fn read_bool(input: &mut dyn Read) -> io::Result<bool> {
+    match read_u8(input)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(invalid("bad flag")),
+    }
+}
+
+// Permissions as bits: read 4, write 2, execute 1.
+pub(super) fn write_perms(out: &mut dyn Write, perms: Perms) -> io::Result<()> {
+    write_u8(out, (perms.read as u8) << 2 | (perms.write as u8) << 1 | perms.exec as u8)
+}
+
+pub(super) fn read_perms(input: &mut dyn Read) -> io::Result<Perms> {
+    let bits = read_u8(input)?;
+    if bits > 0b111 {
+        return Err(invalid("bad permissions"));
+    }
+    Ok(Perms { read: bits & 4 != 0, write: bits & 2 != 0, exec: bits & 1 != 0 })
+}
+
+impl<B: Bus> Machine<B> {
+    /// Write a snapshot of the machine to `out`.
+    pub fn save_snapshot<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let out: &mut dyn Write = &mut out;
+        out.write_all(MAGIC)?;
+        write_u32(out, VERSION)?;
+
+        write_u32(out, self.pc)?;
+        for &r in &self.iregs {
+            write_u32(out, r)?;
+        }
+        write_u8(out, self.trap_mode as u8)?;
+        write_u8(out, self.reservation.is_some() as u8)?;
+        write_u32(out, self.reservation.unwrap_or(0))?;
+
+        self.csrs.save_state(out)?;
+        self.memory.save_state(out)?;
+        out.flush()
+    }
+
+    /// Restore a snapshot written by `save_snapshot`, into a machine with
+    /// the same bus layout.
+    ///
+    /// Malformed snapshots, ones from another format version and ones
+    /// that don't fit the bus fail with `io::ErrorKind::InvalidData`.
+    /// Everything before the bus is checked before any of it is restored,
+    /// so a failure there leaves the machine as it was; one in the bus
+    /// state may leave the bus half restored.
+    ///
+    /// When recording, the undo log is emptied, since the steps in it led
+    /// up to the old state rather than the restored one.
+    pub fn restore_snapshot<R: Read>(&mut self, mut input: R) -> io::Result<()> {
+        let input: &mut dyn Read = &mut input;
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a snapshot"));
+        }
+        if read_u32(input)? != VERSION {
+            return Err(invalid("unsupported snapshot version"));
+        }
+
+        let pc = read_u32(input)?;
+        let mut iregs = [0; 31];
+        for r in &mut iregs {
+            *r = read_u32(input)?;
+        }
+        let trap_mode = read_bool(input)?;
+        let reserved = read_bool(input)?;
+        let addr = read_u32(input)?;
+        let mut csrs = self.csrs.clone();
+        csrs.restore_state(input)?;
+
+        self.history_clear();
+        self.pc = pc;
+        self.iregs = iregs;
+        self.trap_mode = trap_mode;
+        self.reservation = if reserved { Some(addr) } else { None };
+        self.csrs = csrs;
+        self.flush_code();
+        self.memory.restore_state(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use asm::assemble;
+    use decode::Reg;
+    use emu::Machine;
+    use emu::bus::SystemBus;
+    use emu::csr;
+    use emu::sparse::{SparseMemory, UnmappedPolicy};
+
+    #[test]
+    fn test_snapshot() {
+        let mut m = Machine::new(SparseMemory::new(UnmappedPolicy::Fault));
+        assemble("
+            loop:   addi    a0, a0, 1
+                    sw      a0, 0(a1)
+                    j       loop
+        ", 0).unwrap().load(&mut m).unwrap();
+        m.set_reg(Reg::a1(), 0x8000_0000);
+        m.csrs.write(csr::MSCRATCH, 0x1234).unwrap();
+        for _ in 0..4 {
+            m.step().unwrap();
+        }
+
+        let mut snap = vec![];
+        m.save_snapshot(&mut snap).unwrap();
+        // The header, the registers and CSRs, and two pages.
+        assert_eq!(12 + 4 * 32 + 6 + 48 + 10 + 2 * 4100, snap.len());
+
+        for _ in 0..6 {
+            m.step().unwrap();
+        }
+        m.store8(0x4000, 1).unwrap();
+        assert_eq!(4, m.get_reg(Reg::a0()));
+
+        m.restore_snapshot(&snap[..]).unwrap();
+        assert_eq!((4, 2), (m.pc, m.get_reg(Reg::a0())));
+        assert_eq!(1, m.load32(0x8000_0000).unwrap());
+        assert_eq!(0x1234, m.csrs.read(csr::MSCRATCH).unwrap());
+        assert_eq!(4, m.csrs.read(csr::MINSTRET).unwrap());
+        assert_eq!(2, m.memory.mapped_pages());
+
+        snap[8] = 2;
+        let err = m.restore_snapshot(&snap[..]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn test_snapshot_clears_history() {
+        let mut m = Machine::with_memory(64);
+        assemble("
+                    addi    a0, a0, 1
+                    addi    a0, a0, 1
+        ", 0).unwrap().load(&mut m).unwrap();
+        let mut snap = vec![];
+        m.save_snapshot(&mut snap).unwrap();
+
+        m.start_recording(16);
+        m.step().unwrap();
+        m.step().unwrap();
+
+        // Bad snapshots leave the machine and the undo log alone, here
+        // one that isn't and one with a reserved mtvec mode.
+        let mut bad = snap.clone();
+        bad[12 + 4 * 32 + 6 + 8] = 3;
+        for b in &[&b"garbage"[..], &bad[..]] {
+            assert!(m.restore_snapshot(*b).is_err());
+            assert_eq!((8, 2, 2), (m.pc, m.get_reg(Reg::a0()), m.history_len()));
+        }
+
+        m.restore_snapshot(&snap[..]).unwrap();
+        assert_eq!(0, m.history_len());
+        assert!(!m.step_back());
+        assert_eq!((0, 0), (m.pc, m.get_reg(Reg::a0())));
+    }
+
+    #[test]
+    fn test_system_bus_snapshot() {
+        let mut bus = SystemBus::new();
+        bus.map_rom(0, &[0x13, 0, 0, 0]).unwrap();
+        bus.map_ram(0x1000, 16).unwrap();
+        let mut m = Machine::new(bus);
+        m.store32(0x1004, 0xdeadbeef).unwrap();
+
+        let mut snap = vec![];
+        m.save_snapshot(&mut snap).unwrap();
+        m.store32(0x1004, 0).unwrap();
+        m.restore_snapshot(&snap[..]).unwrap();
+        assert_eq!(0xdeadbeef, m.load32(0x1004).unwrap());
+
+        let mut other = SystemBus::new();
+        other.map_ram(0x1000, 16).unwrap();
+        assert!(Machine::new(other).restore_snapshot(&snap[..]).is_err());
+    }
+}
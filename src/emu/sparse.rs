@@ -0,0 +1,436 @@
+//! Memory covering the whole 32-bit address space, allocated a page at a
+//! time.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Write};
+
+use emu::bus::{Access, Bus, Perms};
+use emu::snapshot::{self, read_perms, read_u32, read_u8, write_perms, write_u32, write_u8};
+use {Error, Result};
+
+pub const PAGE_SHIFT: u32 = 12;
+pub const PAGE_SIZE: u32 = 1 << PAGE_SHIFT;
+
+const PAGES: u32 = 1 << (32 - PAGE_SHIFT);
+
+type Page = Box<[u8; PAGE_SIZE as usize]>;
+
+/// What reading a page that was never written does.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UnmappedPolicy {
+    /// Fail with `Error::MemoryOutOfBounds`.
+    Fault,
+
+    /// Read zeroes, without allocating the page.
+    ReadZero,
+}
+
+/// Sparse, page-granular memory.
+///
+/// Every address is valid for stores: the first store to a page allocates
+/// it, zero-filled. Loads from pages that were never stored to, mapped
+/// with `map` or given permissions with `protect` follow the `unmapped`
+/// policy. Accesses may cross page boundaries and wrap around the top of
+/// the address space.
+///
+/// Each page has its own permissions, checked on every access through
+/// `Bus`; `read` and `write` bypass them. Pages start out with
+/// `default_perms`, which is `Perms::RWX`. Setting it to `Perms::RW`
+/// before loading an executable with `elf::load`, which protects each
+/// segment according to its flags, enforces W^X: code can't be written,
+/// and the stack and heap can't be executed.
+///
+/// Permissions are kept by range, apart from the pages, so protecting
+/// even the whole address space allocates nothing: pages it covers read
+/// as zero until they are first stored to.
+#[derive(Clone)]
+pub struct SparseMemory {
+    pages: HashMap<u32, Page>,
+
+    // Each entry covers the pages from its own up to the next one's.
+    // Those before the first, and those under `None`, were never
+    // protected, and have `default_perms`.
+    perms: BTreeMap<u32, Option<Perms>>,
+
+    pub unmapped: UnmappedPolicy,
+    pub default_perms: Perms,
+}
+
+impl SparseMemory {
+    pub fn new(unmapped: UnmappedPolicy) -> SparseMemory {
+        SparseMemory {
+            pages: HashMap::new(),
+            perms: BTreeMap::new(),
+            unmapped,
+            default_perms: Perms::RWX,
+        }
+    }
+
+    /// Allocate every page overlapping `len` bytes at `addr`, so that they
+    /// read as zero even under `UnmappedPolicy::Fault`.
+    pub fn map(&mut self, addr: u32, len: u32) {
+        SparseMemory::chunks(addr, len as usize, |page, _, _, _| {
+            self.page_mut(page);
+            Ok(())
+        }).unwrap();
+    }
+
+    /// Whether the page holding `addr` has been allocated or protected.
+    pub fn is_mapped(&self, addr: u32) -> bool {
+        let page = addr >> PAGE_SHIFT;
+        self.pages.contains_key(&page) || self.protected(page).is_some()
+    }
+
+    /// The permissions of the page holding `addr`.
+    pub fn perms(&self, addr: u32) -> Perms {
+        self.protected(addr >> PAGE_SHIFT).unwrap_or(self.default_perms)
+    }
+
+    fn protected(&self, page: u32) -> Option<Perms> {
+        protected(&self.perms, page)
+    }
+
+    // Give pages `first` up to `end` the permissions `perms`.
+    fn set_perms(&mut self, first: u32, end: u32, perms: Option<Perms>) {
+        let after = self.perms.range(..=end).next_back().and_then(|(_, &p)| p);
+        let covered: Vec<u32> = self.perms.range(first..=end).map(|(&k, _)| k).collect();
+        for k in covered {
+            self.perms.remove(&k);
+        }
+        self.perms.insert(first, perms);
+        if end < PAGES {
+            self.perms.insert(end, after);
+        }
+    }
+
+    // Fail unless every page touched by the access allows it.
+    fn check(&self, addr: u32, len: usize, access: Access) -> Result<()> {
+        SparseMemory::chunks(addr, len, |page, _, _, _| {
+            self.perms(page << PAGE_SHIFT).check(access)
+        })
+    }
+
+    /// The number of pages allocated so far.
+    pub fn mapped_pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    fn page_mut(&mut self, page: u32) -> &mut Page {
+        self.pages.entry(page).or_insert_with(|| Box::new([0; PAGE_SIZE as usize]))
+    }
+
+    // Walk `len` bytes at `addr` one page at a time, calling `f` with the
+    // page number, the offset into it, and the range of the buffer.
+    fn chunks<F>(addr: u32, len: usize, mut f: F) -> Result<()>
+        where F: FnMut(u32, usize, usize, usize) -> Result<()>,
+    {
+        let mut addr = addr;
+        let mut done = 0;
+        while done < len {
+            let off = (addr & (PAGE_SIZE - 1)) as usize;
+            let n = (PAGE_SIZE as usize - off).min(len - done);
+            f(addr >> PAGE_SHIFT, off, done, done + n)?;
+            done += n;
+            addr = addr.wrapping_add(n as u32);
+        }
+        Ok(())
+    }
+
+    /// Fill `buf` from memory starting at `addr`.
+    pub fn read(&self, addr: u32, buf: &mut [u8]) -> Result<()> {
+        let len = buf.len();
+        SparseMemory::chunks(addr, len, |page, off, start, end| {
+            let dst = &mut buf[start..end];
+            match self.pages.get(&page) {
+                Some(p) => dst.copy_from_slice(&p[off..off + dst.len()]),
+                None if self.unmapped == UnmappedPolicy::Fault
+                    && self.protected(page).is_none() => return Err(Error::MemoryOutOfBounds),
+                None => {
+                    for b in dst.iter_mut() {
+                        *b = 0;
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Copy `buf` into memory starting at `addr`, allocating pages as
+    /// needed.
+    pub fn write(&mut self, addr: u32, buf: &[u8]) {
+        SparseMemory::chunks(addr, buf.len(), |page, off, start, end| {
+            self.page_mut(page)[off..off + end - start].copy_from_slice(&buf[start..end]);
+            Ok(())
+        }).unwrap();
+    }
+}
+
+// The permissions `protect` gave `page`, if any.
+fn protected(perms: &BTreeMap<u32, Option<Perms>>, page: u32) -> Option<Perms> {
+    perms.range(..=page).next_back().and_then(|(_, &p)| p)
+}
+
+impl Bus for SparseMemory {
+    fn load8(&mut self, addr: u32) -> Result<u8> {
+        let mut b = [0; 1];
+        self.check(addr, 1, Access::Load)?;
+        self.read(addr, &mut b)?;
+        Ok(b[0])
+    }
+
+    fn load16(&mut self, addr: u32) -> Result<u16> {
+        let mut b = [0; 2];
+        self.check(addr, 2, Access::Load)?;
+        self.read(addr, &mut b)?;
+        Ok(u16::from_le_bytes(b))
+    }
+
+    fn load32(&mut self, addr: u32) -> Result<u32> {
+        let mut b = [0; 4];
+        self.check(addr, 4, Access::Load)?;
+        self.read(addr, &mut b)?;
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn store8(&mut self, addr: u32, val: u8) -> Result<()> {
+        self.check(addr, 1, Access::Store)?;
+        self.write(addr, &[val]);
+        Ok(())
+    }
+
+    fn store16(&mut self, addr: u32, val: u16) -> Result<()> {
+        self.check(addr, 2, Access::Store)?;
+        self.write(addr, &val.to_le_bytes());
+        Ok(())
+    }
+
+    fn store32(&mut self, addr: u32, val: u32) -> Result<()> {
+        self.check(addr, 4, Access::Store)?;
+        self.write(addr, &val.to_le_bytes());
+        Ok(())
+    }
+
+    fn fetch16(&mut self, addr: u32) -> Result<u16> {
+        let mut b = [0; 2];
+        self.check(addr, 2, Access::Fetch)?;
+        self.read(addr, &mut b)?;
+        Ok(u16::from_le_bytes(b))
+    }
+
+    /// Sets the permissions of every page overlapping the range, which
+    /// then reads as zero where nothing was stored, whatever the unmapped
+    /// policy.
+    fn protect(&mut self, addr: u32, len: u32, perms: Perms) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let first = addr >> PAGE_SHIFT;
+        let last = addr.wrapping_add(len - 1) >> PAGE_SHIFT;
+        if last < first {
+            self.set_perms(first, PAGES, Some(perms));
+            self.set_perms(0, last + 1, Some(perms));
+        } else {
+            self.set_perms(first, last + 1, Some(perms));
+        }
+        Ok(())
+    }
+
+    /// Frees the pages wholly inside the range, and zeroes the rest. Under
+    /// `UnmappedPolicy::Fault`, pages that were never protected are zeroed
+    /// too, since they would fault once freed.
+    fn discard(&mut self, addr: u32, len: u32) -> Result<()> {
+        let mut whole: Option<(u32, u32)> = None;
+        SparseMemory::chunks(addr, len as usize, |page, off, start, end| {
+            if end - start == PAGE_SIZE as usize {
+                whole = Some((whole.map_or(page, |w| w.0), page));
+            } else if let Some(p) = self.pages.get_mut(&page) {
+                for b in &mut p[off..off + end - start] {
+                    *b = 0;
+                }
+            }
+            Ok(())
+        })?;
+
+        // The range may wrap around the top of the address space.
+        if let Some((first, last)) = whole {
+            let span = last.wrapping_sub(first) & (PAGES - 1);
+            let fault = self.unmapped == UnmappedPolicy::Fault;
+            let perms = &self.perms;
+            self.pages.retain(|&n, p| {
+                if n.wrapping_sub(first) & (PAGES - 1) > span {
+                    true
+                } else if fault && protected(perms, n).is_none() {
+                    p.fill(0);
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn cacheable(&self, _addr: u32) -> bool {
+        true
+    }
+
+    /// The unmapped policy and default permissions; the number of ranges
+    /// given permissions by `protect`, then the first and last page of
+    /// each and its permissions; the number of pages, then each allocated
+    /// page in address order, its number followed by its contents.
+    fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_u8(out, match self.unmapped {
+            UnmappedPolicy::Fault => 0,
+            UnmappedPolicy::ReadZero => 1,
+        })?;
+        write_perms(out, self.default_perms)?;
+
+        let bounds: Vec<u32> = self.perms.keys().cloned().skip(1).chain(Some(PAGES)).collect();
+        let ranges: Vec<_> = self.perms.iter().zip(bounds)
+            .filter_map(|((&first, &p), end)| p.map(|p| (first, end - 1, p)))
+            .collect();
+        write_u32(out, ranges.len() as u32)?;
+        for (first, last, perms) in ranges {
+            write_u32(out, first)?;
+            write_u32(out, last)?;
+            write_perms(out, perms)?;
+        }
+
+        let mut pages: Vec<_> = self.pages.iter().collect();
+        pages.sort_by_key(|&(&n, _)| n);
+        write_u32(out, pages.len() as u32)?;
+        for (&n, page) in pages {
+            write_u32(out, n)?;
+            out.write_all(&page[..])?;
+        }
+        Ok(())
+    }
+
+    fn restore_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.unmapped = match read_u8(input)? {
+            0 => UnmappedPolicy::Fault,
+            1 => UnmappedPolicy::ReadZero,
+            _ => return Err(snapshot::invalid("bad unmapped policy")),
+        };
+        self.default_perms = read_perms(input)?;
+
+        self.perms.clear();
+        for _ in 0..read_u32(input)? {
+            let first = read_u32(input)?;
+            let last = read_u32(input)?;
+            if first > last || last >= PAGES {
+                return Err(snapshot::invalid("bad page range"));
+            }
+            let perms = read_perms(input)?;
+            self.set_perms(first, last + 1, Some(perms));
+        }
+
+        self.pages.clear();
+        for _ in 0..read_u32(input)? {
+            let n = read_u32(input)?;
+            if n >= PAGES {
+                return Err(snapshot::invalid("bad page number"));
+            }
+            let mut page = Box::new([0; PAGE_SIZE as usize]);
+            input.read_exact(&mut page[..])?;
+            self.pages.insert(n, page);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SparseMemory, UnmappedPolicy};
+    use emu::bus::{Access, Bus, Perms};
+    use Error;
+
+    #[test]
+    fn test_sparse() {
+        let mut mem = SparseMemory::new(UnmappedPolicy::Fault);
+        assert!(mem.load8(0xFFFF_F000).is_err());
+
+        mem.store32(0xFFFF_FFFC, 0x12345678).unwrap();
+        assert_eq!(0x12345678, mem.load32(0xFFFF_FFFC).unwrap());
+        assert_eq!(1, mem.mapped_pages());
+
+        // Straddling two pages, and wrapping around the address space.
+        mem.store32(0x1FFE, 0xdeadbeef).unwrap();
+        assert_eq!(0xdeadbeef, mem.load32(0x1FFE).unwrap());
+        mem.store16(0xFFFF_FFFF, 0xAABB).unwrap();
+        assert_eq!(0xAA, mem.load8(0).unwrap());
+        assert_eq!(4, mem.mapped_pages());
+
+        assert!(mem.load32(0x2FFE).is_err());
+        mem.unmapped = UnmappedPolicy::ReadZero;
+        assert_eq!(0, mem.load32(0x2FFE).unwrap());
+        assert_eq!(0, mem.load32(0x8000_0000).unwrap());
+        assert_eq!(4, mem.mapped_pages());
+    }
+
+    #[test]
+    fn test_perms() {
+        let mut mem = SparseMemory::new(UnmappedPolicy::Fault);
+        mem.default_perms = Perms::RW;
+        mem.store32(0x1000, 0x13).unwrap();
+        mem.protect(0x1000, 4, Perms::RX).unwrap();
+
+        assert_eq!(0x13, mem.fetch16(0x1000).unwrap());
+        match mem.store8(0x1FFF, 0) {
+            Err(Error::AccessFault(Access::Store)) => (),
+            r => panic!("{:?}", r),
+        }
+
+        // A fetch straddling an executable and a data page.
+        mem.store16(0x2000, 0).unwrap();
+        match mem.fetch16(0x1FFF) {
+            Err(Error::AccessFault(Access::Fetch)) => (),
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(0, mem.load16(0x1FFF).unwrap());
+    }
+
+    #[test]
+    fn test_protect_lazily() {
+        let mut mem = SparseMemory::new(UnmappedPolicy::Fault);
+        mem.store32(0x1000, 1).unwrap();
+        mem.store32(0x3000, 2).unwrap();
+        mem.protect(0, 0xFFFF_F000, Perms::NONE).unwrap();
+        mem.protect(0x2000, 0x2000, Perms::RW).unwrap();
+        assert_eq!(2, mem.mapped_pages());
+
+        // Protected pages read as zero, even if never stored to.
+        assert_eq!(Perms::NONE, mem.perms(0x1000));
+        assert_eq!(Perms::RWX, mem.perms(0xFFFF_F000));
+        assert_eq!(0, mem.load32(0x2000).unwrap());
+        assert_eq!(2, mem.load32(0x3000).unwrap());
+        assert!(mem.load32(0x4000).is_err());
+        assert!(mem.load32(0xFFFF_F000).is_err());
+
+        mem.discard(0x2000, 0x2000).unwrap();
+        assert_eq!(0, mem.load32(0x3000).unwrap());
+        assert_eq!(1, mem.mapped_pages());
+
+        let mut snap = vec![];
+        mem.save_state(&mut snap).unwrap();
+        let mut copy = SparseMemory::new(UnmappedPolicy::ReadZero);
+        copy.restore_state(&mut &snap[..]).unwrap();
+        for &addr in &[0, 0x1000, 0x2000, 0x3FFF, 0x4000, 0xFFFF_EFFF, 0xFFFF_F000] {
+            assert_eq!(mem.perms(addr), copy.perms(addr));
+        }
+        assert_eq!(1, copy.mapped_pages());
+    }
+
+    #[test]
+    fn test_discard_without_protect() {
+        let mut mem = SparseMemory::new(UnmappedPolicy::Fault);
+        mem.store32(0x5000, 1).unwrap();
+        mem.store32(0x6000, 2).unwrap();
+        mem.discard(0x5000, 0x2000).unwrap();
+
+        // Stored-to pages still read, as zero.
+        assert_eq!(0, mem.load32(0x5000).unwrap());
+        assert_eq!(0, mem.load32(0x6FFC).unwrap());
+        assert!(mem.load32(0x7000).is_err());
+    }
+}
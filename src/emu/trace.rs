@@ -0,0 +1,221 @@
+//! Logging retired instructions in the format of Spike's `--log-commits`,
+//! so that traces can be diffed against it.
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use decode;
+use disasm::{self, Syntax};
+use emu::Machine;
+
+/// Where and how `Machine::step` logs the instructions it retires.
+///
+/// Each one gets a line like Spike's:
+///
+/// ```text
+/// core   0: 3 0x00000008 (0x00a5a023) mem 0x00000080 0x00000002
+/// ```
+///
+/// with the pc and bits, then the integer registers written, the
+/// addresses loaded and the addresses and values stored. The machine
+/// always runs in M-mode, hence the 3. Instructions that trap don't
+/// retire, so they aren't logged. An ECALL run by a handler from
+/// `Machine::register_ecall` is logged with the handler's register
+/// writes and memory accesses. Clones of a machine share its trace.
+#[derive(Clone)]
+pub struct Trace {
+    out: Arc<Mutex<dyn Write + Send>>,
+
+    /// Also print each instruction disassembled, on a line of its own
+    /// before its commit line, as `spike -l --log-commits` does. The
+    /// disassembly follows objdump rather than Spike, so these lines
+    /// won't diff cleanly.
+    pub disassemble: bool,
+
+    // What the current instruction did so far.
+    bits: u32,
+    len: u32,
+    regs: Vec<(u8, u32)>,
+    loads: Vec<u32>,
+    stores: Vec<(u32, u32, u32)>,
+}
+
+impl Trace {
+    pub fn new<W: Write + Send + 'static>(out: W) -> Trace {
+        Trace {
+            out: Arc::new(Mutex::new(out)),
+            disassemble: false,
+            bits: 0,
+            len: 4,
+            regs: vec![],
+            loads: vec![],
+            stores: vec![],
+        }
+    }
+
+    fn line(&self, pc: u32) -> String {
+        let bits = match self.len {
+            2 => format!("0x{:04x}", self.bits),
+            _ => format!("0x{:08x}", self.bits),
+        };
+        let mut s = String::new();
+        if self.disassemble {
+            let text = match decode::decode(self.bits) {
+                Ok(inst) => disasm::disassemble(&inst, Some(pc), Syntax::default()),
+                Err(_) => "unknown".to_owned(),
+            };
+            s.push_str(&format!("core   0: 0x{:08x} (0x{:08x}) {}\n", pc, self.bits, text));
+        }
+
+        s.push_str(&format!("core   0: 3 0x{:08x} ({})", pc, bits));
+        for &(rd, val) in &self.regs {
+            s.push_str(&format!(" x{:<2} 0x{:08x}", rd, val));
+        }
+        for &addr in &self.loads {
+            s.push_str(&format!(" mem 0x{:08x}", addr));
+        }
+        for &(addr, val, size) in &self.stores {
+            s.push_str(&format!(" mem 0x{:08x} 0x{:0width$x}", addr, val, width = 2 * size as usize));
+        }
+        s.push('\n');
+        s
+    }
+}
+
+impl<B> Machine<B> {
+    /// Start logging retired instructions, or stop with `None`. Returns
+    /// the previous trace, if any.
+    ///
+    /// If writing the trace fails, tracing stops.
+    pub fn set_trace(&mut self, trace: Option<Trace>) -> Option<Trace> {
+        ::std::mem::replace(&mut self.trace, trace)
+    }
+
+    pub fn trace_mut(&mut self) -> Option<&mut Trace> {
+        self.trace.as_mut()
+    }
+
+    // The recording below happens whenever the trace is on, but anything
+    // done outside of `step` is forgotten when the next one starts.
+
+    pub(super) fn trace_begin(&mut self, bits: u32, len: u32) {
+        if let Some(ref mut t) = self.trace {
+            t.bits = bits;
+            t.len = len;
+            t.regs.clear();
+            t.loads.clear();
+            t.stores.clear();
+        }
+    }
+
+    pub(super) fn trace_reg(&mut self, rd: u8, val: u32) {
+        if let Some(ref mut t) = self.trace {
+            match t.regs.iter_mut().find(|r| r.0 == rd) {
+                Some(r) => r.1 = val,
+                None => t.regs.push((rd, val)),
+            }
+        }
+    }
+
+    pub(super) fn trace_load(&mut self, addr: u32) {
+        if let Some(ref mut t) = self.trace {
+            t.loads.push(addr);
+        }
+    }
+
+    pub(super) fn trace_store(&mut self, addr: u32, val: u32, size: u32) {
+        if let Some(ref mut t) = self.trace {
+            t.stores.push((addr, val, size));
+        }
+    }
+
+    // Log the instruction at `pc`, which just retired.
+    pub(super) fn trace_commit(&mut self, pc: u32) {
+        let failed = match self.trace {
+            Some(ref t) => {
+                let line = t.line(pc);
+                let mut out = t.out.lock().unwrap_or_else(|e| e.into_inner());
+                out.write_all(line.as_bytes()).is_err()
+            }
+            None => false,
+        };
+        if failed {
+            self.trace = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    use super::Trace;
+    use asm::assemble;
+    use decode::Reg;
+    use emu::Machine;
+
+    #[derive(Clone)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut m = Machine::with_memory(256);
+        assemble("
+                    addi    a0, a0, 1
+                    sw      a0, 0(a1)
+                    lw      a2, 0(a1)
+                    amoadd.w a3, s0, (a1)
+                    .half   0x4501      # c.li a0, 0
+        ", 0).unwrap().load(&mut m).unwrap();
+        m.set_reg(Reg::a1(), 0x80);
+        m.set_reg(Reg::s0(), 1);
+
+        let buf = SharedBuf(Arc::new(Mutex::new(vec![])));
+        m.set_trace(Some(Trace::new(buf.clone())));
+        for _ in 0..4 {
+            m.step().unwrap();
+        }
+        m.trace_mut().unwrap().disassemble = true;
+        m.step().unwrap();
+        assert!(m.set_trace(None).is_some());
+
+        assert_eq!(
+            "core   0: 3 0x00000000 (0x00150513) x10 0x00000001\n\
+             core   0: 3 0x00000004 (0x00a5a023) mem 0x00000080 0x00000001\n\
+             core   0: 3 0x00000008 (0x0005a603) x12 0x00000001 mem 0x00000080\n\
+             core   0: 3 0x0000000c (0x0085a6af) x13 0x00000001 mem 0x00000080 \
+                 mem 0x00000080 0x00000002\n\
+             core   0: 0x00000010 (0x00004501) li a0,0\n\
+             core   0: 3 0x00000010 (0x4501) x10 0x00000000\n",
+            String::from_utf8(buf.0.lock().unwrap().clone()).unwrap());
+    }
+
+    #[test]
+    fn test_trace_ecall_handler() {
+        let mut m = Machine::with_memory(64);
+        assemble("
+                    ecall
+        ", 0).unwrap().load(&mut m).unwrap();
+        m.register_ecall(0, |call| call.arg(0) as u64 + 1);
+
+        let buf = SharedBuf(Arc::new(Mutex::new(vec![])));
+        m.set_trace(Some(Trace::new(buf.clone())));
+        m.step().unwrap();
+
+        assert_eq!(
+            "core   0: 3 0x00000000 (0x00000073) x10 0x00000001 x11 0x00000000\n",
+            String::from_utf8(buf.0.lock().unwrap().clone()).unwrap());
+    }
+}
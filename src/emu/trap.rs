@@ -0,0 +1,66 @@
+//! Synchronous exceptions, as seen by guest trap handlers.
+
+use Error;
+
+/// Exception codes, as written to `mcause`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Cause {
+    InstructionAddressMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadAddressMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreAddressMisaligned = 6,
+    StoreAccessFault = 7,
+    MachineEcall = 11,
+}
+
+/// An exception raised by one instruction.
+///
+/// `error` is what `Machine::step` returns when traps are disabled.
+#[derive(Clone, Debug)]
+pub struct Exception {
+    pub cause: Cause,
+    pub tval: u32,
+    pub error: Error,
+}
+
+impl Exception {
+    pub fn new(cause: Cause, tval: u32, error: Error) -> Exception {
+        Exception {
+            cause,
+            tval,
+            error,
+        }
+    }
+
+    pub fn fetch(error: Error, addr: u32) -> Exception {
+        let cause = match error {
+            Error::MisalignedAccess => Cause::InstructionAddressMisaligned,
+            _ => Cause::InstructionAccessFault,
+        };
+        Exception::new(cause, addr, error)
+    }
+
+    pub fn load(error: Error, addr: u32) -> Exception {
+        let cause = match error {
+            Error::MisalignedAccess => Cause::LoadAddressMisaligned,
+            _ => Cause::LoadAccessFault,
+        };
+        Exception::new(cause, addr, error)
+    }
+
+    /// Stores and AMOs.
+    pub fn store(error: Error, addr: u32) -> Exception {
+        let cause = match error {
+            Error::MisalignedAccess => Cause::StoreAddressMisaligned,
+            _ => Cause::StoreAccessFault,
+        };
+        Exception::new(cause, addr, error)
+    }
+
+    pub fn illegal(error: Error, bits: u32) -> Exception {
+        Exception::new(Cause::IllegalInstruction, bits, error)
+    }
+}
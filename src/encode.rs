@@ -0,0 +1,202 @@
+//! Turning instructions back into machine code.
+
+use decode::Instruction;
+use decode::Instruction::*;
+use decode::formats::{self, Opcode};
+use decode::Funct;
+use {Error, Result};
+
+macro_rules! encode_as {
+    ($format:ident, $opcode:ident, $funct:expr, $operands:expr) => {
+        formats::$format(&formats::Instruction {
+            opcode: Opcode::$opcode,
+            funct: $funct,
+            operands: $operands.clone(),
+        })
+    };
+}
+
+// Shift amounts share the I-type immediate with the upper funct bits, as
+// in the result of `decode`.
+fn check_shift(imm: u32, funct7: Funct) -> Result<()> {
+    if imm & !0b11111 != (funct7 as u32) << 5 {
+        return Err(Error::ImmediateOutOfRange);
+    }
+    Ok(())
+}
+
+/// Encode an instruction as a 32-bit word.
+///
+/// This is the inverse of `decode::decode` for 32-bit instructions.
+/// Compressed instructions decode to their 32-bit equivalents, so they
+/// come back uncompressed. Immediates that don't fit their field give
+/// `Error::ImmediateOutOfRange`, and odd branch or jump offsets or a LUI
+/// immediate with low bits set give `Error::MisalignedImmediate`.
+pub fn encode(inst: &Instruction) -> Result<u32> {
+    match *inst {
+        ADDI(ref op)  => encode_as!(encode_i, OpImm, 0b_000, op),
+        SLTI(ref op)  => encode_as!(encode_i, OpImm, 0b_010, op),
+        SLTIU(ref op) => encode_as!(encode_i, OpImm, 0b_011, op),
+        XORI(ref op)  => encode_as!(encode_i, OpImm, 0b_100, op),
+        ORI(ref op)   => encode_as!(encode_i, OpImm, 0b_110, op),
+        ANDI(ref op)  => encode_as!(encode_i, OpImm, 0b_111, op),
+
+        SLLI(ref op) => {
+            check_shift(op.imm, 0b0000000)?;
+            encode_as!(encode_i, OpImm, 0b_001, op)
+        }
+
+        SRLI(ref op) => {
+            check_shift(op.imm, 0b0000000)?;
+            encode_as!(encode_i, OpImm, 0b_101, op)
+        }
+
+        SRAI(ref op) => {
+            check_shift(op.imm, 0b0100000)?;
+            encode_as!(encode_i, OpImm, 0b_101, op)
+        }
+
+        LUI(ref op)   => encode_as!(encode_u, Lui, 0, op),
+        AUIPC(ref op) => encode_as!(encode_u, Auipc, 0, op),
+
+        ADD(ref op)  => encode_as!(encode_r, Op, 0b_000, op),
+        SLL(ref op)  => encode_as!(encode_r, Op, 0b_001, op),
+        SLT(ref op)  => encode_as!(encode_r, Op, 0b_010, op),
+        SLTU(ref op) => encode_as!(encode_r, Op, 0b_011, op),
+        XOR(ref op)  => encode_as!(encode_r, Op, 0b_100, op),
+        SRL(ref op)  => encode_as!(encode_r, Op, 0b_101, op),
+        OR(ref op)   => encode_as!(encode_r, Op, 0b_110, op),
+        AND(ref op)  => encode_as!(encode_r, Op, 0b_111, op),
+        SUB(ref op)  => encode_as!(encode_r, Op, 0b_0100000_000, op),
+        SRA(ref op)  => encode_as!(encode_r, Op, 0b_0100000_101, op),
+
+        MUL(ref op)    => encode_as!(encode_r, Op, 0b_0000001_000, op),
+        MULH(ref op)   => encode_as!(encode_r, Op, 0b_0000001_001, op),
+        MULHSU(ref op) => encode_as!(encode_r, Op, 0b_0000001_010, op),
+        MULHU(ref op)  => encode_as!(encode_r, Op, 0b_0000001_011, op),
+        DIV(ref op)    => encode_as!(encode_r, Op, 0b_0000001_100, op),
+        DIVU(ref op)   => encode_as!(encode_r, Op, 0b_0000001_101, op),
+        REM(ref op)    => encode_as!(encode_r, Op, 0b_0000001_110, op),
+        REMU(ref op)   => encode_as!(encode_r, Op, 0b_0000001_111, op),
+
+        JAL(ref op)  => encode_as!(encode_j, Jal, 0, op),
+        JALR(ref op) => encode_as!(encode_i, Jalr, 0, op),
+
+        BEQ(ref op)  => encode_as!(encode_b, Branch, 0b_000, op),
+        BNE(ref op)  => encode_as!(encode_b, Branch, 0b_001, op),
+        BLT(ref op)  => encode_as!(encode_b, Branch, 0b_100, op),
+        BGE(ref op)  => encode_as!(encode_b, Branch, 0b_101, op),
+        BLTU(ref op) => encode_as!(encode_b, Branch, 0b_110, op),
+        BGEU(ref op) => encode_as!(encode_b, Branch, 0b_111, op),
+
+        LB(ref op)  => encode_as!(encode_i, Load, 0b_000, op),
+        LH(ref op)  => encode_as!(encode_i, Load, 0b_001, op),
+        LW(ref op)  => encode_as!(encode_i, Load, 0b_010, op),
+        LBU(ref op) => encode_as!(encode_i, Load, 0b_100, op),
+        LHU(ref op) => encode_as!(encode_i, Load, 0b_101, op),
+        SB(ref op)  => encode_as!(encode_s, Store, 0b_000, op),
+        SH(ref op)  => encode_as!(encode_s, Store, 0b_001, op),
+        SW(ref op)  => encode_as!(encode_s, Store, 0b_010, op),
+
+        LR(ref op) => {
+            if op.rs2.num() != 0 {
+                return Err(Error::BadRegister);
+            }
+            encode_as!(encode_a, Amo, 0b_00010_010, op)
+        }
+
+        SC(ref op)      => encode_as!(encode_a, Amo, 0b_00011_010, op),
+        AMOSWAP(ref op) => encode_as!(encode_a, Amo, 0b_00001_010, op),
+        AMOADD(ref op)  => encode_as!(encode_a, Amo, 0b_00000_010, op),
+        AMOXOR(ref op)  => encode_as!(encode_a, Amo, 0b_00100_010, op),
+        AMOAND(ref op)  => encode_as!(encode_a, Amo, 0b_01100_010, op),
+        AMOOR(ref op)   => encode_as!(encode_a, Amo, 0b_01000_010, op),
+        AMOMIN(ref op)  => encode_as!(encode_a, Amo, 0b_10000_010, op),
+        AMOMAX(ref op)  => encode_as!(encode_a, Amo, 0b_10100_010, op),
+        AMOMINU(ref op) => encode_as!(encode_a, Amo, 0b_11000_010, op),
+        AMOMAXU(ref op) => encode_as!(encode_a, Amo, 0b_11100_010, op),
+
+        CSRRW(ref op)  => encode_as!(encode_csr, System, 0b_001, op),
+        CSRRS(ref op)  => encode_as!(encode_csr, System, 0b_010, op),
+        CSRRC(ref op)  => encode_as!(encode_csr, System, 0b_011, op),
+        CSRRWI(ref op) => encode_as!(encode_csri, System, 0b_101, op),
+        CSRRSI(ref op) => encode_as!(encode_csri, System, 0b_110, op),
+        CSRRCI(ref op) => encode_as!(encode_csri, System, 0b_111, op),
+
+        FENCE(ref op) => {
+            if op.pred > 0b1111 || op.succ > 0b1111 {
+                return Err(Error::ImmediateOutOfRange);
+            }
+            Ok((op.pred as u32) << 24 | (op.succ as u32) << 20 | Opcode::MiscMem as u32)
+        }
+        FENCEI => Ok(0x0000100f),
+
+        ECALL  => Ok(0x00000073),
+        EBREAK => Ok(0x00100073),
+        MRET   => Ok(0x30200073),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::encode;
+    use decode::{decode, Reg, Instruction};
+    use decode::formats::{IOperands, BOperands, UOperands};
+    use Error;
+
+    #[test]
+    fn test_round_trip() {
+        let words = [
+            0xfff50793,  // addi    a5,a0,-1
+            0x4025d513,  // srai    a0,a1,0x2
+            0x00e68533,  // add     a0,a3,a4
+            0x40b50533,  // sub     a0,a0,a1
+            0x02c5c533,  // div     a0,a1,a2
+            0xfe0798e3,  // bnez    a5,-16
+            0x00812623,  // sw      s0,12(sp)
+            0xff410083,  // lb      ra,-12(sp)
+            0x123452b7,  // lui     t0,0x12345
+            0x7f8000ef,  // jal     2040
+            0x800000ef,  // jal     -1048576
+            0x000080e7,  // jalr    ra
+            0x1605262f,  // lr.w.aqrl a2,(a0)
+            0x08b5272f,  // amoswap.w a4,a1,(a0)
+            0x34051073,  // csrw    mscratch,a0
+            0xf1402573,  // csrr    a0,mhartid
+            0x3002f073,  // csrci   mstatus,5
+            0x0310000f,  // fence   rw,w
+            0x0000100f,  // fence.i
+            0x00100073,  // ebreak
+            0x30200073,  // mret
+        ];
+
+        for &w in &words {
+            assert_eq!(w, encode(&decode(w).unwrap()).unwrap(), "{:08x}", w);
+        }
+    }
+
+    #[test]
+    fn test_bad_immediates() {
+        let a0 = Reg::a0();
+        match encode(&Instruction::ADDI(IOperands { rd: a0, rs1: a0, imm: 2048 })) {
+            Err(Error::ImmediateOutOfRange) => (),
+            r => panic!("{:?}", r),
+        }
+        match encode(&Instruction::SLLI(IOperands { rd: a0, rs1: a0, imm: 32 })) {
+            Err(Error::ImmediateOutOfRange) => (),
+            r => panic!("{:?}", r),
+        }
+        match encode(&Instruction::BEQ(BOperands { rs1: a0, rs2: a0, imm: 6 | 1 })) {
+            Err(Error::MisalignedImmediate) => (),
+            r => panic!("{:?}", r),
+        }
+        match encode(&Instruction::BEQ(BOperands { rs1: a0, rs2: a0, imm: 4096 })) {
+            Err(Error::ImmediateOutOfRange) => (),
+            r => panic!("{:?}", r),
+        }
+        match encode(&Instruction::LUI(UOperands { rd: a0, imm: 0x1234 })) {
+            Err(Error::MisalignedImmediate) => (),
+            r => panic!("{:?}", r),
+        }
+    }
+}
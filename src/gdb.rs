@@ -0,0 +1,696 @@
+//! A GDB remote serial protocol stub, so that `gdb-multiarch` or
+//! `riscv32-unknown-elf-gdb` can debug a `Machine` with `target remote`.
+//!
+//! Breakpoints are kept by the stub and checked before each instruction,
+//! rather than patched into guest memory, so they work in ROM and in
+//! write-protected text. EBREAK instructions in the guest stop with
+//! SIGTRAP too. Watchpoints fire before the instruction that would touch
+//! the watched memory, which is what GDB expects on RISC-V.
+//!
+//! If the machine is recording, with `Machine::start_recording`, GDB's
+//! reverse-stepi and reverse-continue work too, back to the start of the
+//! recorded history.
+
+use std::io::{self, Read, Write};
+use std::str;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+
+use decode::Reg;
+use emu::{Machine, StepOutcome};
+use emu::bus::Bus;
+use emu::run::{Breakpoints, StopReason, Watchpoint};
+use Error;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 7;
+const SIGSEGV: u8 = 11;
+
+// Register 32 is the pc, as in GDB's riscv:rv32 numbering.
+const PC_REGNUM: usize = 32;
+
+// In bytes of packet, so reads are limited to half this.
+const PACKET_SIZE: usize = 0x4000;
+
+// How many instructions to run between checks for a ^C.
+const INTERRUPT_CHECK_INTERVAL: u64 = 4096;
+
+/// A connection to the debugger.
+pub trait Connection: Read + Write {
+    /// Without blocking, read a byte if the debugger has sent one, and
+    /// report whether it was an interrupt (^C). While the machine runs,
+    /// that is all the debugger may send.
+    fn poll_interrupt(&mut self) -> io::Result<bool>;
+}
+
+// For sockets that can be switched to non-blocking mode.
+fn poll_nonblocking<S: Read>(stream: &mut S, set_nonblocking: &dyn Fn(&S, bool) -> io::Result<()>)
+    -> io::Result<bool>
+{
+    set_nonblocking(stream, true)?;
+    let mut b = [0; 1];
+    let res = match stream.read(&mut b) {
+        Ok(n) => Ok(n == 1 && b[0] == 0x03),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    };
+    set_nonblocking(stream, false)?;
+    res
+}
+
+impl Connection for TcpStream {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        poll_nonblocking(self, &TcpStream::set_nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        poll_nonblocking(self, &UnixStream::set_nonblocking)
+    }
+}
+
+/// Wait for GDB to connect on a TCP port.
+pub fn accept_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
+    let (stream, _) = TcpListener::bind(addr)?.accept()?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// Wait for GDB to connect on a Unix socket, which is created at `path`.
+#[cfg(unix)]
+pub fn accept_unix<P: AsRef<Path>>(path: P) -> io::Result<UnixStream> {
+    let (stream, _) = UnixListener::bind(path)?.accept()?;
+    Ok(stream)
+}
+
+fn signal_for(e: &Error) -> u8 {
+    match *e {
+        Error::BadOpcode | Error::BadFunct | Error::BadRegister
+            | Error::BadCsr | Error::ReadOnlyCsr => SIGILL,
+        Error::MisalignedAccess => SIGBUS,
+        _ => SIGSEGV,
+    }
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">",
+        "<target version=\"1.0\">",
+        "<architecture>riscv:rv32</architecture>",
+        "<feature name=\"org.gnu.gdb.riscv.cpu\">"));
+
+    for i in 0..32 {
+        let ty = match i {
+            1 => "code_ptr",
+            2..=4 => "data_ptr",
+            _ => "int",
+        };
+        xml.push_str(&format!("<reg name=\"{}\" bitsize=\"32\" type=\"{}\" regnum=\"{}\"/>",
+                              Reg::new(i).unwrap().abi_name(), ty, i));
+    }
+    xml.push_str(&format!("<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{}\"/>",
+                          PC_REGNUM));
+    xml.push_str("</feature></target>");
+    xml
+}
+
+fn hex_u32(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+// Registers go over the wire as little-endian hex.
+fn reg_hex(val: u32) -> String {
+    val.to_le_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_reg_hex(s: &str) -> Option<u32> {
+    if s.len() != 8 {
+        return None;
+    }
+    hex_u32(s).map(u32::swap_bytes)
+}
+
+fn parse_hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i+2)?, 16).ok()).collect()
+}
+
+// "addr,len" as in the m and M packets.
+fn parse_range(s: &str) -> Option<(u32, u32)> {
+    let mut parts = s.splitn(2, ',');
+    Some((hex_u32(parts.next()?)?, hex_u32(parts.next()?)?))
+}
+
+// Characters that can't appear raw in a packet body.
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    for &b in data {
+        match b {
+            b'#' | b'$' | b'}' | b'*' => out.extend_from_slice(&[b'}', b ^ 0x20]),
+            _ => out.push(b),
+        }
+    }
+    out
+}
+
+// The reply for a stop other than an exit, an error or an interrupt.
+fn stop_reply(stop: &StopReason) -> String {
+    match *stop {
+        StopReason::Breakpoint(_) => "T05swbreak:;".to_owned(),
+        StopReason::Watchpoint { watchpoint: w, addr, .. } => {
+            let name = match (w.read, w.write) {
+                (true, true) => "awatch",
+                (true, false) => "rwatch",
+                _ => "watch",
+            };
+            format!("T05{}:{:x};", name, addr.max(w.addr))
+        }
+        StopReason::EndOfHistory => "T05replaylog:begin;".to_owned(),
+        _ => format!("S{:02x}", SIGTRAP),
+    }
+}
+
+// What a command asks the stub to do next.
+enum Action {
+    Reply(Vec<u8>),
+    Resume { step: bool },
+    Detach,
+    Kill,
+}
+
+fn reply(s: &str) -> Action {
+    Action::Reply(s.as_bytes().to_vec())
+}
+
+type SyscallHandler<B> = Box<dyn FnMut(&mut Machine<B>) -> Option<i32>>;
+
+/// The debugger's view of a machine: its breakpoints, watchpoints and
+/// connection state.
+pub struct GdbStub<B> {
+    stops: Breakpoints,
+    no_ack: bool,
+    on_syscall: Option<SyscallHandler<B>>,
+}
+
+impl<B: Bus> Default for GdbStub<B> {
+    fn default() -> GdbStub<B> {
+        GdbStub::new()
+    }
+}
+
+impl<B: Bus> GdbStub<B> {
+    pub fn new() -> GdbStub<B> {
+        GdbStub {
+            stops: Breakpoints::new(),
+            no_ack: false,
+            on_syscall: None,
+        }
+    }
+
+    /// Handle ECALLs with `f` instead of stopping at them. If it returns
+    /// an exit status, the debugger is told the program exited. This is
+    /// where `linux::Linux::syscall` plugs in.
+    pub fn on_syscall<F>(&mut self, f: F)
+        where F: FnMut(&mut Machine<B>) -> Option<i32> + 'static,
+    {
+        self.on_syscall = Some(Box::new(f));
+    }
+
+    /// Serve one debugger session, until it detaches, kills the program,
+    /// the program exits, or the connection closes.
+    pub fn serve<C: Connection>(&mut self, machine: &mut Machine<B>, conn: &mut C)
+        -> io::Result<()>
+    {
+        self.no_ack = false;
+        loop {
+            let packet = match self.read_packet(conn)? {
+                Some(p) => p,
+                None => return Ok(()),
+            };
+
+            match self.command(machine, &packet) {
+                Action::Reply(r) => self.send(conn, &r)?,
+                Action::Detach => {
+                    self.send(conn, b"OK")?;
+                    return Ok(());
+                }
+                Action::Kill => return Ok(()),
+                Action::Resume { step } => {
+                    let stop = self.resume(machine, conn, step)?;
+                    self.send(conn, stop.as_bytes())?;
+                    if stop.starts_with('W') {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    // Returns None at end of file.
+    fn read_packet<C: Connection>(&mut self, conn: &mut C) -> io::Result<Option<String>> {
+        let mut byte = [0; 1];
+        loop {
+            // Skip acks and stray interrupts until the start of a packet.
+            loop {
+                if conn.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+
+            let mut body = vec![];
+            loop {
+                if conn.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                body.push(byte[0]);
+            }
+
+            let mut sum = [0; 2];
+            conn.read_exact(&mut sum)?;
+            let expected = str::from_utf8(&sum).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+            let actual = body.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+
+            if self.no_ack || expected == Some(actual) {
+                if !self.no_ack {
+                    conn.write_all(b"+")?;
+                }
+                return Ok(Some(String::from_utf8_lossy(&body).into_owned()));
+            }
+            conn.write_all(b"-")?;
+        }
+    }
+
+    fn send<C: Connection>(&mut self, conn: &mut C, body: &[u8]) -> io::Result<()> {
+        let sum = body.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(body);
+        packet.extend_from_slice(format!("#{:02x}", sum).as_bytes());
+
+        loop {
+            conn.write_all(&packet)?;
+            conn.flush()?;
+            if self.no_ack {
+                return Ok(());
+            }
+
+            let mut ack = [0; 1];
+            loop {
+                if conn.read(&mut ack)? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                match ack[0] {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    fn command(&mut self, m: &mut Machine<B>, packet: &str) -> Action {
+        let (cmd, args) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+        match cmd {
+            "?" => reply("S05"),
+
+            "g" => {
+                let mut s = String::new();
+                for i in 0..32 {
+                    s.push_str(&reg_hex(m.get_reg(Reg::new(i).unwrap())));
+                }
+                s.push_str(&reg_hex(m.pc));
+                reply(&s)
+            }
+
+            "G" => {
+                if args.len() != 8 * (PC_REGNUM + 1) {
+                    return reply("E01");
+                }
+                let vals: Option<Vec<u32>> = (0..PC_REGNUM + 1)
+                    .map(|i| args.get(8*i..8*i + 8).and_then(parse_reg_hex))
+                    .collect();
+                match vals {
+                    Some(vals) => {
+                        for (i, &v) in vals[..32].iter().enumerate() {
+                            m.set_reg(Reg::new(i as u32).unwrap(), v);
+                        }
+                        m.pc = vals[PC_REGNUM];
+                        reply("OK")
+                    }
+                    None => reply("E01"),
+                }
+            }
+
+            "p" => match hex_u32(args) {
+                Some(n) if n < 32 => reply(&reg_hex(m.get_reg(Reg::new(n).unwrap()))),
+                Some(n) if n as usize == PC_REGNUM => reply(&reg_hex(m.pc)),
+                _ => reply("E01"),
+            },
+
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let n = parts.next().and_then(hex_u32);
+                let val = parts.next().and_then(parse_reg_hex);
+                match (n, val) {
+                    (Some(n), Some(v)) if n < 32 => m.set_reg(Reg::new(n).unwrap(), v),
+                    (Some(n), Some(v)) if n as usize == PC_REGNUM => m.pc = v,
+                    _ => return reply("E01"),
+                }
+                reply("OK")
+            }
+
+            "m" => {
+                let (addr, len) = match parse_range(args) {
+                    Some(r) => r,
+                    None => return reply("E01"),
+                };
+                let mut buf = vec![0; (len as usize).min(PACKET_SIZE / 2)];
+                match m.read_bytes(addr, &mut buf) {
+                    Ok(()) => reply(&buf.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+                    Err(_) => reply("E14"),
+                }
+            }
+
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let range = parts.next().and_then(parse_range);
+                let data = parts.next().and_then(parse_hex_bytes);
+                match (range, data) {
+                    (Some((addr, len)), Some(ref data)) if data.len() == len as usize => {
+                        match m.write_bytes(addr, data) {
+                            Ok(()) => reply("OK"),
+                            Err(_) => reply("E14"),
+                        }
+                    }
+                    _ => reply("E01"),
+                }
+            }
+
+            "s" | "c" => {
+                if let Some(addr) = hex_u32(args) {
+                    m.pc = addr;
+                }
+                Action::Resume { step: cmd == "s" }
+            }
+
+            "Z" | "z" => self.breakpoint(cmd == "Z", args),
+
+            // Reverse execution, if the machine is recording.
+            "b" => match args {
+                "s" if m.step_back() => reply(&format!("S{:02x}", SIGTRAP)),
+                "s" => reply(&stop_reply(&StopReason::EndOfHistory)),
+                "c" => reply(&stop_reply(&m.reverse_continue(&self.stops))),
+                _ => reply(""),
+            },
+
+            "H" => reply("OK"),
+            "D" => Action::Detach,
+            "k" => Action::Kill,
+            "q" | "Q" | "v" => self.query(packet),
+            _ => reply(""),
+        }
+    }
+
+    // Z and z packets: "type,addr,kind".
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Action {
+        let mut parts = args.split(',');
+        let kind = parts.next();
+        let addr = parts.next().and_then(hex_u32);
+        let len = parts.next().and_then(hex_u32);
+        let (addr, len) = match (addr, len) {
+            (Some(a), Some(l)) => (a, l),
+            _ => return reply("E01"),
+        };
+
+        let wp = match kind {
+            Some("0") | Some("1") => {
+                if insert {
+                    self.stops.add_breakpoint(addr);
+                } else {
+                    self.stops.remove_breakpoint(addr);
+                }
+                return reply("OK");
+            }
+            Some("2") => Watchpoint::on_write(addr, len),
+            Some("3") => Watchpoint::on_read(addr, len),
+            Some("4") => Watchpoint::on_access(addr, len),
+            _ => return reply(""),
+        };
+
+        if insert {
+            self.stops.add_watchpoint(wp);
+        } else {
+            self.stops.remove_watchpoint(&wp);
+        }
+        reply("OK")
+    }
+
+    fn query(&mut self, packet: &str) -> Action {
+        if packet.starts_with("qSupported") {
+            return reply(&format!("PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+;\
+                                   QStartNoAckMode+;ReverseStep+;ReverseContinue+", PACKET_SIZE));
+        }
+        if packet == "QStartNoAckMode" {
+            // GDB may still ack the OK, but stray acks are skipped when
+            // reading packets.
+            self.no_ack = true;
+            return reply("OK");
+        }
+        if let Some(rest) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (off, len) = match parse_range(rest) {
+                Some(r) => r,
+                None => return reply("E01"),
+            };
+            let xml = target_xml().into_bytes();
+            let start = (off as usize).min(xml.len());
+            let end = start.saturating_add(len as usize).min(xml.len());
+            let mut r = vec![if end == xml.len() { b'l' } else { b'm' }];
+            r.extend(escape(&xml[start..end]));
+            return Action::Reply(r);
+        }
+
+        match packet {
+            "qAttached" => reply("1"),
+            "qC" => reply("QC1"),
+            "qfThreadInfo" => reply("m1"),
+            "qsThreadInfo" => reply("l"),
+            _ => reply(""),
+        }
+    }
+
+    // Run until something stops the machine, and return the stop reply.
+    fn resume<C: Connection>(&mut self, m: &mut Machine<B>, conn: &mut C, step: bool)
+        -> io::Result<String>
+    {
+        loop {
+            let res = if step {
+                // A step can only be stopped by a watchpoint on its own
+                // access, not by what it lands on.
+                match m.run_until(&self.stops, 0) {
+                    Ok(StopReason::BudgetExhausted) => m.run(1),
+                    res => res,
+                }
+            } else {
+                m.run_until(&self.stops, INTERRUPT_CHECK_INTERVAL)
+            };
+
+            let stop = match res {
+                Ok(stop) => stop,
+                Err(e) => return Ok(format!("S{:02x}", signal_for(&e))),
+            };
+            match stop {
+                StopReason::Outcome(StepOutcome::Syscall) if self.on_syscall.is_some() => {
+                    let f = self.on_syscall.as_mut().unwrap();
+                    if let Some(status) = f(m) {
+                        return Ok(format!("W{:02x}", status as u8));
+                    }
+                }
+                StopReason::BudgetExhausted => {
+                    if !step && conn.poll_interrupt()? {
+                        return Ok(format!("S{:02x}", SIGINT));
+                    }
+                }
+                stop => return Ok(stop_reply(&stop)),
+            }
+
+            if step {
+                return Ok(format!("S{:02x}", SIGTRAP));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::{self, Read, Write};
+    use std::str;
+
+    use super::{Connection, GdbStub};
+    use asm::assemble;
+    use decode::Reg;
+    use emu::Machine;
+
+    // Scripted input; everything written is kept.
+    struct Mock {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Mock {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.input.len());
+            for b in buf[..n].iter_mut() {
+                *b = self.input.pop_front().unwrap();
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for Mock {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Mock {
+        fn poll_interrupt(&mut self) -> io::Result<bool> {
+            Ok(false)
+        }
+    }
+
+    // Run a session without acks, returning the body of each reply.
+    fn session(m: &mut Machine, commands: &[&str]) -> Vec<String> {
+        let mut input = String::from("$QStartNoAckMode#b0+");
+        for c in commands {
+            let sum = c.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+            input.push_str(&format!("${}#{:02x}", c, sum));
+        }
+
+        let mut conn = Mock { input: input.into_bytes().into(), output: vec![] };
+        GdbStub::new().serve(m, &mut conn).unwrap();
+
+        let out = String::from_utf8(conn.output).unwrap();
+        out.split('$').skip(2).map(|p| p.split('#').next().unwrap().to_owned()).collect()
+    }
+
+    #[test]
+    fn test_session() {
+        let mut m = Machine::with_memory(256);
+        assemble("
+                    addi    a0, a0, 1
+                    addi    a0, a0, 1
+                    sw      a0, 0(a1)
+                    ebreak
+        ", 0).unwrap().load(&mut m).unwrap();
+        m.set_reg(Reg::a1(), 0x80);
+
+        let replies = session(&mut m, &[
+            "?",
+            "Z0,8,4",
+            "c",
+            "p20",
+            "pa",
+            "Z2,80,4",
+            "c",
+            "z2,80,4",
+            "s",
+            "m80,4",
+            "M80,2:beef",
+            "c",
+            "P20=00000000",
+            "D",
+        ]);
+
+        assert_eq!(vec![
+            "S05",
+            "OK",
+            "T05swbreak:;",
+            "08000000",
+            "02000000",
+            "OK",
+            "T05watch:80;",
+            "OK",
+            "S05",
+            "02000000",
+            "OK",
+            "S05",
+            "OK",
+            "OK",
+        ], replies);
+        assert_eq!(0, m.pc);
+        assert_eq!(0xefbe, m.load16(0x80).unwrap());
+    }
+
+    #[test]
+    fn test_reverse() {
+        let mut m = Machine::with_memory(64);
+        for i in 0..4 {
+            m.store32(4 * i, 0x00150513).unwrap();  // addi    a0,a0,1
+        }
+        m.start_recording(16);
+
+        let replies = session(&mut m, &[
+            "Z0,c,4",
+            "c",
+            "bs",
+            "pa",
+            "z0,c,4",
+            "Z0,0,4",
+            "bc",
+            "bc",
+            "D",
+        ]);
+        assert_eq!(vec![
+            "OK",
+            "T05swbreak:;",
+            "S05",
+            "02000000",
+            "OK",
+            "OK",
+            "T05swbreak:;",
+            "T05replaylog:begin;",
+            "OK",
+        ], replies);
+        assert_eq!(0, m.pc);
+    }
+
+    #[test]
+    fn test_registers_and_xml() {
+        let mut m = Machine::with_memory(16);
+        m.set_reg(Reg::ra(), 0x12345678);
+        // The right length in bytes, but with a character straddling the
+        // first register.
+        let bad = format!("G0000000\u{fffd}{}", "0".repeat(33 * 8 - 10));
+        let replies = session(&mut m, &[
+            "g",
+            "qXfer:features:read:target.xml:0,40",
+            &bad,
+        ]);
+        assert_eq!(&replies[0][8..16], "78563412");
+        assert_eq!(33 * 8, replies[0].len());
+        assert_eq!("m<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><t",
+                   replies[1]);
+        assert_eq!("E01", replies[2]);
+    }
+}
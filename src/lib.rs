@@ -1,11 +1,20 @@
 #![deny(warnings)]
 
+// Binary literals are grouped by instruction field, not by nibble.
+#![allow(clippy::unusual_byte_groupings)]
+
 #[macro_use]
 extern crate enum_primitive;
 extern crate num;
 
 pub mod decode;
+pub mod encode;
+pub mod disasm;
+pub mod asm;
 pub mod emu;
+pub mod elf;
+pub mod linux;
+pub mod gdb;
 
 #[derive(Clone, Debug)]
 pub enum Error {
@@ -13,6 +22,19 @@ pub enum Error {
     BadFunct,
     BadRegister,
     MemoryOutOfBounds,
+    MisalignedAccess,
+    OverlappingRegion,
+    BadCsr,
+    ReadOnlyCsr,
+    BadElf,
+    ImmediateOutOfRange,
+    MisalignedImmediate,
+
+    /// The memory refused an access for lack of permission.
+    AccessFault(emu::bus::Access),
+
+    /// Assembler error, with the line number and a description.
+    Assembly(usize, String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,1032 @@
+//! A Linux user-mode runtime, for running statically linked programs built
+//! for the rv32 Linux ABI, such as with musl.
+//!
+//! Programs linked against newlib's bare-metal libgloss won't run
+//! unmodified. Its syscalls share Linux's numbers, but not all their
+//! arguments: it makes 62 as lseek(fd, offset, whence), where rv32 Linux
+//! has llseek with a result pointer, and it calls fstat (80) and
+//! gettimeofday (169), which rv32 Linux lacks and which fail here with
+//! ENOSYS.
+//!
+//! After `Machine::step` returns `StepOutcome::Syscall`, pass the machine
+//! to `Linux::syscall`. The syscall number is in a7 and the arguments in
+//! a0-a5; the result, or a negated errno, goes back in a0. Files are
+//! served from a host directory that the guest sees as `/`.
+//!
+//! Before starting the guest, `Linux::init_stack` lays out its arguments
+//! and environment the way the kernel would.
+
+use std::ffi::OsString;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use decode::Reg;
+use elf::Image;
+use emu::Machine;
+use emu::bus::{Bus, Perms};
+use emu::sparse::PAGE_SIZE;
+use {Error, Result};
+
+// Syscall numbers, from the generic table that RV32 uses.
+const SYS_GETCWD: u32 = 17;
+const SYS_IOCTL: u32 = 29;
+const SYS_MKDIRAT: u32 = 34;
+const SYS_UNLINKAT: u32 = 35;
+const SYS_OPENAT: u32 = 56;
+const SYS_CLOSE: u32 = 57;
+const SYS_LLSEEK: u32 = 62;
+const SYS_READ: u32 = 63;
+const SYS_WRITE: u32 = 64;
+const SYS_READV: u32 = 65;
+const SYS_WRITEV: u32 = 66;
+const SYS_EXIT: u32 = 93;
+const SYS_EXIT_GROUP: u32 = 94;
+const SYS_SET_TID_ADDRESS: u32 = 96;
+const SYS_RT_SIGACTION: u32 = 134;
+const SYS_RT_SIGPROCMASK: u32 = 135;
+const SYS_GETPID: u32 = 172;
+const SYS_GETUID: u32 = 174;
+const SYS_GETEUID: u32 = 175;
+const SYS_GETGID: u32 = 176;
+const SYS_GETEGID: u32 = 177;
+const SYS_GETTID: u32 = 178;
+const SYS_BRK: u32 = 214;
+const SYS_MUNMAP: u32 = 215;
+const SYS_MMAP: u32 = 222;
+const SYS_MPROTECT: u32 = 226;
+const SYS_STATX: u32 = 291;
+const SYS_CLOCK_GETTIME64: u32 = 403;
+
+const EPERM: u32 = 1;
+const ENOENT: u32 = 2;
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const ENOMEM: u32 = 12;
+const EACCES: u32 = 13;
+const EFAULT: u32 = 14;
+const EEXIST: u32 = 17;
+const ENOTDIR: u32 = 20;
+const EINVAL: u32 = 22;
+const ENOTTY: u32 = 25;
+const ESPIPE: u32 = 29;
+const ERANGE: u32 = 34;
+const ENAMETOOLONG: u32 = 36;
+const ENOSYS: u32 = 38;
+const ELOOP: u32 = 40;
+
+const AT_FDCWD: u32 = -100i32 as u32;
+const AT_SYMLINK_NOFOLLOW: u32 = 0x100;
+const AT_REMOVEDIR: u32 = 0x200;
+const AT_EMPTY_PATH: u32 = 0x1000;
+
+const O_ACCMODE: u32 = 0o3;
+const O_RDONLY: u32 = 0o0;
+const O_WRONLY: u32 = 0o1;
+const O_RDWR: u32 = 0o2;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+const O_DIRECTORY: u32 = 0o200000;
+
+const PROT_READ: u32 = 1;
+const PROT_WRITE: u32 = 2;
+const PROT_EXEC: u32 = 4;
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+// Everything in STATX_BASIC_STATS but STATX_INO.
+const STATX_FILLED: u32 = 0x6ff;
+
+const CLOCK_REALTIME: u32 = 0;
+const CLOCK_MONOTONIC: u32 = 1;
+
+const PATH_MAX: usize = 4096;
+const MAX_SYMLINKS: u32 = 40;
+
+// Auxiliary vector keys.
+const AT_NULL: u32 = 0;
+const AT_PHDR: u32 = 3;
+const AT_PHENT: u32 = 4;
+const AT_PHNUM: u32 = 5;
+const AT_PAGESZ: u32 = 6;
+const AT_ENTRY: u32 = 9;
+const AT_UID: u32 = 11;
+const AT_EUID: u32 = 12;
+const AT_GID: u32 = 13;
+const AT_EGID: u32 = 14;
+const AT_SECURE: u32 = 23;
+const AT_RANDOM: u32 = 25;
+const AT_EXECFN: u32 = 31;
+
+/// How much of the stack `Linux::init_stack` maps read-write.
+pub const STACK_SIZE: u32 = 1 << 20;
+
+// Longer reads and writes are cut short, as the kernel is allowed to do.
+const MAX_IO: u32 = 1 << 20;
+
+// On failure, the errno to negate into a0.
+type SysResult<T> = ::std::result::Result<T, u32>;
+
+enum Fd {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+fn errno(e: &io::Error) -> u32 {
+    match e.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        io::ErrorKind::InvalidInput => EINVAL,
+        _ => EIO,
+    }
+}
+
+fn page_align(addr: u32) -> Option<u32> {
+    addr.checked_add(PAGE_SIZE - 1).map(|a| a & !(PAGE_SIZE - 1))
+}
+
+fn prot_perms(prot: u32) -> Perms {
+    Perms {
+        read: prot & PROT_READ != 0,
+        write: prot & PROT_WRITE != 0,
+        exec: prot & PROT_EXEC != 0,
+    }
+}
+
+// `struct statx`, which rv32 C libraries use for the whole stat family:
+// the older stat syscalls are only there on 64-bit kernels.
+fn statx_bytes(mode: u32, meta: Option<&Metadata>) -> [u8; 256] {
+    let size = meta.map_or(0, |m| m.len());
+    let mtime = meta.and_then(|m| m.modified().ok())
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or((0, 0), |d| (d.as_secs(), d.subsec_nanos()));
+
+    let mut bytes = [0; 256];
+    let mut put = |off: usize, val: &[u8]| bytes[off..off + val.len()].copy_from_slice(val);
+    put(0, &STATX_FILLED.to_le_bytes());
+    put(4, &4096u32.to_le_bytes());             // stx_blksize
+    put(16, &1u32.to_le_bytes());               // stx_nlink
+    put(28, &(mode as u16).to_le_bytes());
+    put(40, &size.to_le_bytes());
+    put(48, &size.div_ceil(512).to_le_bytes()); // stx_blocks
+
+    // The access, change and modification times all give mtime; there's
+    // no birth time.
+    for &off in &[64, 96, 112] {
+        put(off, &mtime.0.to_le_bytes());
+        put(off + 8, &mtime.1.to_le_bytes());
+    }
+    bytes
+}
+
+// The names and `..`s in `path`, last first.
+fn walk_order(path: &Path) -> Vec<OsString> {
+    path.components().rev().filter_map(|c| match c {
+        Component::Normal(name) => Some(name.to_owned()),
+        Component::ParentDir => Some("..".into()),
+        _ => None,
+    }).collect()
+}
+
+fn file_mode(meta: &Metadata) -> u32 {
+    if meta.is_dir() {
+        S_IFDIR | 0o755
+    } else if meta.permissions().readonly() {
+        S_IFREG | 0o444
+    } else {
+        S_IFREG | 0o644
+    }
+}
+
+/// The state of the emulated process: open files, the heap and the
+/// mmap area.
+pub struct Linux {
+    root: PathBuf,
+    files: Vec<Option<Fd>>,
+    brk_start: u32,
+    brk: u32,
+    mmap_next: u32,
+    started: Instant,
+
+    /// The 16 bytes `AT_RANDOM` points at, which seed the C library's
+    /// stack protector and pointer mangling. They are zero unless set, so
+    /// that runs are reproducible.
+    pub random: [u8; 16],
+
+    pub stdin: Box<dyn Read + Send>,
+    pub stdout: Box<dyn Write + Send>,
+    pub stderr: Box<dyn Write + Send>,
+}
+
+impl Linux {
+    /// A process whose `/` is the host directory `root`.
+    ///
+    /// Paths can't climb out of `root` with `..`. Symlinks are followed by
+    /// the runtime itself, and those pointing outside `root` are refused,
+    /// even if nothing exists there yet. File descriptors 0-2 are the host's
+    /// standard streams, which can be replaced through the public fields.
+    pub fn new<P: AsRef<Path>>(root: P) -> Linux {
+        let root = root.as_ref();
+        Linux {
+            root: root.canonicalize().unwrap_or_else(|_| root.to_path_buf()),
+            files: vec![Some(Fd::Stdin), Some(Fd::Stdout), Some(Fd::Stderr)],
+            brk_start: 0,
+            brk: 0,
+            mmap_next: 0x4000_0000,
+            started: Instant::now(),
+            random: [0; 16],
+            stdin: Box::new(io::stdin()),
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+        }
+    }
+
+    /// Start the heap at the first page boundary at or after `end`,
+    /// normally `elf::Image::end`. Until this is called `brk` always
+    /// fails, which musl copes with.
+    pub fn set_brk(&mut self, end: u32) {
+        self.brk_start = page_align(end).unwrap_or(end);
+        self.brk = self.brk_start;
+    }
+
+    /// Where `mmap` places mappings that don't ask for a fixed address.
+    /// They are handed out upwards from here, and never reused.
+    pub fn set_mmap_base(&mut self, addr: u32) {
+        self.mmap_next = addr;
+    }
+
+    /// Build the initial stack of a new process below `top`, and point sp
+    /// at it.
+    ///
+    /// From sp upwards this holds argc, the `argv` and `envp` pointer
+    /// arrays, and the auxiliary vector, which describes `image` and the
+    /// page size and points at `random`. The strings go above those. The
+    /// `STACK_SIZE` bytes below `top` are mapped read-write, if the bus
+    /// supports permissions.
+    pub fn init_stack<B: Bus>(&self, machine: &mut Machine<B>, top: u32, image: &Image,
+                              args: &[&str], env: &[&str]) -> Result<()>
+    {
+        fn push<B: Bus>(m: &mut Machine<B>, sp: &mut u32, data: &[u8]) -> Result<u32> {
+            *sp = sp.checked_sub(data.len() as u32).ok_or(Error::MemoryOutOfBounds)?;
+            m.write_bytes(*sp, data)?;
+            Ok(*sp)
+        }
+
+        let m = machine;
+        m.protect(top.saturating_sub(STACK_SIZE), top.min(STACK_SIZE), Perms::RW)?;
+
+        let mut sp = top;
+        let random = push(m, &mut sp, &self.random)?;
+        let mut strings = |list: &[&str], sp: &mut u32| -> Result<Vec<u32>> {
+            let mut ptrs = vec![];
+            for s in list {
+                push(m, sp, &[0])?;
+                ptrs.push(push(m, sp, s.as_bytes())?);
+            }
+            ptrs.push(0);
+            Ok(ptrs)
+        };
+        let envp = strings(env, &mut sp)?;
+        let argv = strings(args, &mut sp)?;
+        let execfn = argv[0];
+
+        let auxv = [
+            (AT_PHDR, image.phdr),
+            (AT_PHENT, 32),
+            (AT_PHNUM, image.phnum),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, image.entry),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_SECURE, 0),
+            (AT_RANDOM, random),
+            (AT_EXECFN, execfn),
+            (AT_NULL, 0),
+        ];
+
+        let mut words = vec![args.len() as u32];
+        words.extend(argv);
+        words.extend(envp);
+        for &(key, val) in &auxv {
+            words.push(key);
+            words.push(val);
+        }
+
+        // The ABI wants sp 16-byte aligned.
+        sp = sp.checked_sub(4 * words.len() as u32).ok_or(Error::MemoryOutOfBounds)? & !15;
+        for (i, &w) in words.iter().enumerate() {
+            m.store32(sp + 4 * i as u32, w)?;
+        }
+
+        m.set_reg(Reg::sp(), sp);
+        Ok(())
+    }
+
+    /// Handle the ECALL that `machine` just stopped at.
+    ///
+    /// Returns the exit status if the guest called `exit` or `exit_group`.
+    /// Otherwise a0 holds the result, and the guest can carry on.
+    /// Unknown syscalls fail with `ENOSYS`.
+    pub fn syscall<B: Bus>(&mut self, machine: &mut Machine<B>) -> Option<i32> {
+        let mut a = [0; 6];
+        for (i, arg) in a.iter_mut().enumerate() {
+            *arg = machine.get_reg(Reg::new(10 + i as u32).unwrap());
+        }
+
+        let m = machine;
+        let res = match m.get_reg(Reg::a7()) {
+            SYS_EXIT | SYS_EXIT_GROUP => return Some(a[0] as i32),
+
+            SYS_READ => self.read(m, a[0], a[1], a[2]),
+            SYS_WRITE => self.write(m, a[0], a[1], a[2]),
+            SYS_READV => self.iov(m, a[0], a[1], a[2], Linux::read),
+            SYS_WRITEV => self.iov(m, a[0], a[1], a[2], Linux::write),
+            SYS_OPENAT => self.openat(m, a[0], a[1], a[2]),
+            SYS_CLOSE => self.close(a[0]),
+            SYS_LLSEEK => self.llseek(m, a[0], a[1], a[2], a[3], a[4]),
+            SYS_STATX => self.statx(m, a[0], a[1], a[2], a[4]),
+            SYS_MKDIRAT => self.mkdirat(m, a[0], a[1]),
+            SYS_UNLINKAT => self.unlinkat(m, a[0], a[1], a[2]),
+            SYS_GETCWD => Linux::getcwd(m, a[0], a[1]),
+            SYS_IOCTL => Err(ENOTTY),
+
+            SYS_BRK => Ok(self.brk(m, a[0])),
+            SYS_MMAP => self.mmap(m, a[0], a[1], a[2], a[3], a[4], a[5]),
+            SYS_MPROTECT => m.protect(a[0], a[1], prot_perms(a[2]))
+                .map(|_| 0).map_err(|_| ENOMEM),
+
+            // Unmapped memory is never reused, but it does stop being
+            // accessible, to catch use after free.
+            SYS_MUNMAP => {
+                let _ = m.protect(a[0], a[1], Perms::NONE);
+                Ok(0)
+            }
+
+            SYS_CLOCK_GETTIME64 => self.clock_gettime(m, a[0], a[1]),
+
+            // There is only one thread, and nothing sends it signals.
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => Ok(1),
+            SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => Ok(0),
+            SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK => Ok(0),
+
+            _ => Err(ENOSYS),
+        };
+
+        let ret = match res {
+            Ok(val) => val,
+            Err(e) => e.wrapping_neg(),
+        };
+        m.set_reg(Reg::a0(), ret);
+        None
+    }
+
+    fn fd(&mut self, fd: u32) -> SysResult<&mut Fd> {
+        match self.files.get_mut(fd as usize) {
+            Some(&mut Some(ref mut f)) => Ok(f),
+            _ => Err(EBADF),
+        }
+    }
+
+    fn alloc_fd(&mut self, fd: Fd) -> u32 {
+        match self.files.iter().position(|f| f.is_none()) {
+            Some(i) => {
+                self.files[i] = Some(fd);
+                i as u32
+            }
+            None => {
+                self.files.push(Some(fd));
+                self.files.len() as u32 - 1
+            }
+        }
+    }
+
+    fn read<B: Bus>(&mut self, m: &mut Machine<B>, fd: u32, buf: u32, len: u32) -> SysResult<u32> {
+        let mut data = vec![0; len.min(MAX_IO) as usize];
+        let n = match *self.fd(fd)? {
+            Fd::Stdin => self.stdin.read(&mut data),
+            Fd::File(ref mut f) => f.read(&mut data),
+            Fd::Stdout | Fd::Stderr => return Err(EBADF),
+        }.map_err(|e| errno(&e))?;
+
+        m.write_bytes(buf, &data[..n]).map_err(|_| EFAULT)?;
+        Ok(n as u32)
+    }
+
+    fn write<B: Bus>(&mut self, m: &mut Machine<B>, fd: u32, buf: u32, len: u32) -> SysResult<u32> {
+        let mut data = vec![0; len.min(MAX_IO) as usize];
+        m.read_bytes(buf, &mut data).map_err(|_| EFAULT)?;
+
+        let n = match *self.fd(fd)? {
+            Fd::Stdout => self.stdout.write(&data),
+            Fd::Stderr => self.stderr.write(&data),
+            Fd::File(ref mut f) => f.write(&data),
+            Fd::Stdin => return Err(EBADF),
+        }.map_err(|e| errno(&e))?;
+        Ok(n as u32)
+    }
+
+    // readv and writev, one buffer at a time until one comes up short.
+    fn iov<B: Bus, F>(&mut self, m: &mut Machine<B>, fd: u32, iov: u32, count: u32, f: F)
+        -> SysResult<u32>
+        where F: Fn(&mut Linux, &mut Machine<B>, u32, u32, u32) -> SysResult<u32>,
+    {
+        if count > 1024 {
+            return Err(EINVAL);
+        }
+
+        let mut total = 0;
+        for i in 0..count {
+            let entry = iov.wrapping_add(8 * i);
+            let base = m.load32(entry).map_err(|_| EFAULT)?;
+            let len = m.load32(entry.wrapping_add(4)).map_err(|_| EFAULT)?;
+            let n = f(self, m, fd, base, len)?;
+            total += n;
+            if n < len {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    // Read a path from the guest and map it into the sandbox. A symlink
+    // at the end is followed if `follow`.
+    fn path<B: Bus>(&self, m: &mut Machine<B>, dirfd: u32, addr: u32, follow: bool)
+        -> SysResult<PathBuf>
+    {
+        let path = Linux::read_path(m, addr)?;
+        self.resolve(dirfd, &path, follow)
+    }
+
+    fn read_path<B: Bus>(m: &mut Machine<B>, addr: u32) -> SysResult<String> {
+        let mut bytes = vec![];
+        loop {
+            let b = m.load8(addr.wrapping_add(bytes.len() as u32)).map_err(|_| EFAULT)?;
+            if b == 0 {
+                break;
+            }
+            if bytes.len() == PATH_MAX {
+                return Err(ENAMETOOLONG);
+            }
+            bytes.push(b);
+        }
+        String::from_utf8(bytes).map_err(|_| EINVAL)
+    }
+
+    // Directory file descriptors other than AT_FDCWD aren't supported; the
+    // current directory is always `/`.
+    //
+    // Symlinks are resolved here a component at a time, rather than by the
+    // host, so that none can lead outside the root: not even a dangling
+    // one, through which O_CREAT would create a file.
+    fn resolve(&self, dirfd: u32, path: &str, follow: bool) -> SysResult<PathBuf> {
+        if !path.starts_with('/') && dirfd != AT_FDCWD {
+            return Err(EBADF);
+        }
+
+        // What is left to walk, last component first.
+        let mut rest = walk_order(Path::new(path));
+        let mut links = 0;
+        let mut full = self.root.clone();
+        while let Some(name) = rest.pop() {
+            if name == ".." {
+                if full != self.root {
+                    full.pop();
+                }
+                continue;
+            }
+
+            let next = full.join(&name);
+            let is_link = fs::symlink_metadata(&next).is_ok_and(|m| m.file_type().is_symlink());
+            if !is_link || (rest.is_empty() && !follow) {
+                full = next;
+                continue;
+            }
+
+            links += 1;
+            if links > MAX_SYMLINKS {
+                return Err(ELOOP);
+            }
+            let target = fs::read_link(&next).map_err(|e| errno(&e))?;
+            if target.is_absolute() {
+                full = self.root.clone();
+                rest.extend(walk_order(target.strip_prefix(&self.root).map_err(|_| EACCES)?));
+            } else {
+                rest.extend(walk_order(&target));
+            }
+        }
+        Ok(full)
+    }
+
+    fn openat<B: Bus>(&mut self, m: &mut Machine<B>, dirfd: u32, path: u32, flags: u32)
+        -> SysResult<u32>
+    {
+        let path = self.path(m, dirfd, path, true)?;
+
+        let mut opts = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_RDONLY => opts.read(true),
+            O_WRONLY => opts.write(true),
+            O_RDWR => opts.read(true).write(true),
+            _ => return Err(EINVAL),
+        };
+        opts.append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0);
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 {
+                opts.create_new(true);
+            } else {
+                opts.create(true);
+            }
+        }
+
+        if flags & O_DIRECTORY != 0 && !path.is_dir() {
+            return Err(if path.exists() { ENOTDIR } else { ENOENT });
+        }
+
+        let file = opts.open(&path).map_err(|e| errno(&e))?;
+        Ok(self.alloc_fd(Fd::File(file)))
+    }
+
+    fn close(&mut self, fd: u32) -> SysResult<u32> {
+        self.fd(fd)?;
+        self.files[fd as usize] = None;
+        Ok(0)
+    }
+
+    fn llseek<B: Bus>(&mut self, m: &mut Machine<B>, fd: u32, hi: u32, lo: u32,
+                      result: u32, whence: u32) -> SysResult<u32>
+    {
+        let offset = ((hi as u64) << 32 | lo as u64) as i64;
+        let pos = match whence {
+            0 if offset >= 0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(EINVAL),
+        };
+
+        let new = match *self.fd(fd)? {
+            Fd::File(ref mut f) => f.seek(pos).map_err(|e| errno(&e))?,
+            _ => return Err(ESPIPE),
+        };
+        m.write_bytes(result, &new.to_le_bytes()).map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+
+    // The mask of wanted fields is ignored: they are all filled in, as
+    // far as they can be.
+    fn statx<B: Bus>(&mut self, m: &mut Machine<B>, dirfd: u32, path: u32, flags: u32,
+                     buf: u32) -> SysResult<u32>
+    {
+        let path = Linux::read_path(m, path)?;
+        let stat = if path.is_empty() && flags & AT_EMPTY_PATH != 0 && dirfd != AT_FDCWD {
+            match *self.fd(dirfd)? {
+                Fd::File(ref f) => {
+                    let meta = f.metadata().map_err(|e| errno(&e))?;
+                    statx_bytes(file_mode(&meta), Some(&meta))
+                }
+                _ => statx_bytes(S_IFCHR | 0o620, None),
+            }
+        } else {
+            if path.is_empty() && flags & AT_EMPTY_PATH == 0 {
+                return Err(ENOENT);
+            }
+            let path = self.resolve(dirfd, &path, flags & AT_SYMLINK_NOFOLLOW == 0)?;
+            let meta = if flags & AT_SYMLINK_NOFOLLOW != 0 {
+                fs::symlink_metadata(&path)
+            } else {
+                fs::metadata(&path)
+            }.map_err(|e| errno(&e))?;
+            statx_bytes(file_mode(&meta), Some(&meta))
+        };
+
+        m.write_bytes(buf, &stat).map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+
+    fn mkdirat<B: Bus>(&mut self, m: &mut Machine<B>, dirfd: u32, path: u32) -> SysResult<u32> {
+        let path = self.path(m, dirfd, path, false)?;
+        fs::create_dir(path).map_err(|e| errno(&e))?;
+        Ok(0)
+    }
+
+    fn unlinkat<B: Bus>(&mut self, m: &mut Machine<B>, dirfd: u32, path: u32, flags: u32)
+        -> SysResult<u32>
+    {
+        let path = self.path(m, dirfd, path, false)?;
+        if path == self.root {
+            return Err(EPERM);
+        }
+        if flags & AT_REMOVEDIR != 0 {
+            fs::remove_dir(path)
+        } else {
+            fs::remove_file(path)
+        }.map_err(|e| errno(&e))?;
+        Ok(0)
+    }
+
+    fn getcwd<B: Bus>(m: &mut Machine<B>, buf: u32, size: u32) -> SysResult<u32> {
+        if size < 2 {
+            return Err(ERANGE);
+        }
+        m.write_bytes(buf, b"/\0").map_err(|_| EFAULT)?;
+        Ok(2)
+    }
+
+    // Returns the new break, or the old one on failure.
+    fn brk<B: Bus>(&mut self, m: &mut Machine<B>, addr: u32) -> u32 {
+        if self.brk_start == 0 || addr < self.brk_start || addr > self.mmap_next {
+            return self.brk;
+        }
+        if addr > self.brk && m.protect(self.brk, addr - self.brk, Perms::RW).is_err() {
+            return self.brk;
+        }
+        self.brk = addr;
+        self.brk
+    }
+
+    // This is mmap2 really: the offset is in pages. File mappings are
+    // private copies.
+    #[allow(clippy::too_many_arguments)]
+    fn mmap<B: Bus>(&mut self, m: &mut Machine<B>, addr: u32, len: u32, prot: u32,
+                    flags: u32, fd: u32, pgoff: u32) -> SysResult<u32>
+    {
+        let len = match page_align(len) {
+            Some(len) if len > 0 => len,
+            _ => return Err(EINVAL),
+        };
+
+        let start = if flags & MAP_FIXED != 0 {
+            if addr & (PAGE_SIZE - 1) != 0 {
+                return Err(EINVAL);
+            }
+            addr
+        } else {
+            let start = self.mmap_next;
+            self.mmap_next = start.checked_add(len).ok_or(ENOMEM)?;
+            start
+        };
+
+        m.protect(start, len, Perms::RW).map_err(|_| ENOMEM)?;
+        if flags & MAP_FIXED != 0 {
+            m.discard(start, len).map_err(|_| ENOMEM)?;
+        }
+
+        if flags & MAP_ANONYMOUS == 0 {
+            let mut data = vec![];
+            match *self.fd(fd)? {
+                Fd::File(ref mut f) => {
+                    f.seek(SeekFrom::Start(pgoff as u64 * PAGE_SIZE as u64))
+                        .and_then(|_| f.take(len as u64).read_to_end(&mut data))
+                        .map_err(|e| errno(&e))?;
+                }
+                _ => return Err(EACCES),
+            }
+            m.write_bytes(start, &data).map_err(|_| ENOMEM)?;
+        }
+
+        m.protect(start, len, prot_perms(prot)).map_err(|_| ENOMEM)?;
+        Ok(start)
+    }
+
+    fn clock_gettime<B: Bus>(&self, m: &mut Machine<B>, clock: u32, tp: u32) -> SysResult<u32> {
+        let t = match clock {
+            CLOCK_REALTIME => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+            CLOCK_MONOTONIC => self.started.elapsed(),
+            _ => return Err(EINVAL),
+        };
+
+        let mut buf = [0; 16];
+        buf[..8].copy_from_slice(&t.as_secs().to_le_bytes());
+        buf[8..].copy_from_slice(&(t.subsec_nanos() as u64).to_le_bytes());
+        m.write_bytes(tp, &buf).map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::{self, Write};
+    use std::process;
+    use std::sync::{Arc, Mutex};
+
+    use super::Linux;
+    use asm::assemble;
+    use decode::Reg;
+    use elf::Image;
+    use emu::{Machine, StepOutcome};
+    use emu::bus::Bus;
+    use emu::sparse::{SparseMemory, UnmappedPolicy};
+
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run<B: Bus>(linux: &mut Linux, m: &mut Machine<B>) -> i32 {
+        loop {
+            if m.step().unwrap() == StepOutcome::Syscall {
+                if let Some(status) = linux.syscall(m) {
+                    return status;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_files_and_exit() {
+        let root = env::temp_dir().join(format!("minrisc-linux-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+
+        let program = assemble("
+                    li      a0, 1           # write(1, msg, 3)
+                    la      a1, msg
+                    li      a2, 3
+                    li      a7, 64
+                    ecall
+
+                    li      a0, -100        # openat(AT_FDCWD, path, O_WRONLY|O_CREAT)
+                    la      a1, path
+                    li      a2, 0x41
+                    li      a7, 56
+                    ecall
+                    mv      s0, a0
+
+                    la      a1, msg         # write(fd, msg, 3)
+                    li      a2, 3
+                    li      a7, 64
+                    ecall
+
+                    mv      a0, s0          # close(fd)
+                    li      a7, 57
+                    ecall
+
+                    li      a0, 0           # brk(0)
+                    li      a7, 214
+                    ecall
+                    mv      s1, a0
+
+                    li      a7, 12345       # ENOSYS
+                    ecall
+                    mv      s2, a0
+
+                    li      a0, 3           # exit(3)
+                    li      a7, 93
+                    ecall
+
+            msg:    .ascii  \"hi\\n\"
+            path:   .asciz  \"../../out.txt\"
+        ", 0).unwrap();
+
+        let out = Arc::new(Mutex::new(vec![]));
+        let mut linux = Linux::new(&root);
+        linux.stdout = Box::new(SharedBuf(out.clone()));
+        linux.set_brk(program.bytes.len() as u32);
+
+        let mut m = Machine::with_memory(0x2000);
+        program.load(&mut m).unwrap();
+
+        assert_eq!(3, run(&mut linux, &mut m));
+        assert_eq!(b"hi\n", &out.lock().unwrap()[..]);
+        assert_eq!(b"hi\n", &fs::read(root.join("out.txt")).unwrap()[..]);
+        assert_eq!(0x1000, m.get_reg(Reg::s1()));
+        assert_eq!(-38i32 as u32, m.get_reg(Reg::s2()));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = env::temp_dir().join(format!("minrisc-links-{}", process::id()));
+        let (root, outside) = (dir.join("root"), dir.join("outside"));
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(root.join("f.txt"), b"").unwrap();
+        symlink(outside.join("new.txt"), root.join("evil")).unwrap();
+        symlink("../outside/new.txt", root.join("climb")).unwrap();
+        symlink("f.txt", root.join("good")).unwrap();
+
+        let program = assemble("
+                    li      a0, -100        # openat(AT_FDCWD, \"/evil\", O_WRONLY|O_CREAT)
+                    la      a1, evil
+                    li      a2, 0x41
+                    li      a7, 56
+                    ecall
+                    mv      s0, a0
+
+                    li      a0, -100        # the same through \"/climb\"
+                    la      a1, climb
+                    li      a7, 56
+                    ecall
+                    mv      s1, a0
+
+                    li      a0, -100        # openat(AT_FDCWD, \"/good\", O_RDONLY)
+                    la      a1, good
+                    li      a2, 0
+                    li      a7, 56
+                    ecall
+                    mv      s2, a0
+
+                    li      a0, -100        # unlinkat(AT_FDCWD, \"/good\", 0)
+                    la      a1, good
+                    li      a7, 35
+                    ecall
+                    mv      s3, a0
+
+                    li      a0, 0
+                    li      a7, 93
+                    ecall
+
+            evil:   .asciz  \"/evil\"
+            climb:  .asciz  \"/climb\"
+            good:   .asciz  \"/good\"
+        ", 0).unwrap();
+
+        let mut m = Machine::with_memory(0x1000);
+        program.load(&mut m).unwrap();
+        assert_eq!(0, run(&mut Linux::new(&root), &mut m));
+
+        assert_eq!(-13i32 as u32, m.get_reg(Reg::s0()));
+
+        // "..", even in a symlink, stops at the root, where there is no
+        // "outside" directory.
+        assert_eq!(-2i32 as u32, m.get_reg(Reg::s1()));
+        assert_eq!(3, m.get_reg(Reg::s2()));
+        assert_eq!(0, m.get_reg(Reg::s3()));
+        assert!(!outside.join("new.txt").exists());
+        assert!(root.join("f.txt").exists() && !root.join("good").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // The calls rv32 musl and glibc make for stat and fstat, which have no
+    // syscalls of their own there.
+    #[test]
+    fn test_statx() {
+        let root = env::temp_dir().join(format!("minrisc-statx-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("f.txt"), b"hello").unwrap();
+
+        let program = assemble("
+                    li      a0, -100        # musl stat(path): statx(AT_FDCWD, path, 0,
+                    la      a1, path        #   STATX_BASIC_STATS, buf)
+                    li      a2, 0
+                    li      a3, 0x7ff
+                    la      a4, buf
+                    li      a7, 291
+                    ecall
+                    mv      s0, a0
+                    la      t0, buf
+                    lhu     s1, 28(t0)
+                    lw      s2, 40(t0)
+
+                    li      a0, -100        # openat(AT_FDCWD, path, O_RDONLY)
+                    la      a1, path
+                    li      a2, 0
+                    li      a7, 56
+                    ecall
+
+                    la      a1, empty       # glibc fstat(fd): statx(fd, \"\",
+                    li      a2, 0x1800      #   AT_EMPTY_PATH|AT_NO_AUTOMOUNT, ...)
+                    li      a3, 0x7ff
+                    la      a4, buf
+                    li      a7, 291
+                    ecall
+                    la      t0, buf
+                    lw      s3, 40(t0)
+
+                    li      a0, 1           # musl fstat(1): statx(1, \"\", AT_EMPTY_PATH, ...)
+                    la      a1, empty
+                    li      a2, 0x1000
+                    li      a3, 0x7ff
+                    la      a4, buf
+                    li      a7, 291
+                    ecall
+                    la      t0, buf
+                    lhu     s4, 28(t0)
+
+                    li      a0, -100        # stat(\"\") fails without AT_EMPTY_PATH
+                    la      a1, empty
+                    li      a2, 0
+                    la      a4, buf
+                    li      a7, 291
+                    ecall
+                    mv      s5, a0
+
+                    li      a0, 0
+                    li      a7, 93
+                    ecall
+
+            path:   .asciz  \"/f.txt\"
+            empty:  .byte   0
+                    .align  2
+            buf:    .zero   256
+        ", 0).unwrap();
+
+        let mut m = Machine::with_memory(0x1000);
+        program.load(&mut m).unwrap();
+        assert_eq!(0, run(&mut Linux::new(&root), &mut m));
+        assert_eq!((0, 0o100644, 5), (m.get_reg(Reg::s0()), m.get_reg(Reg::s1()),
+                                      m.get_reg(Reg::s2())));
+        assert_eq!(5, m.get_reg(Reg::s3()));
+        assert_eq!(0o020620, m.get_reg(Reg::s4()));
+        assert_eq!(-2i32 as u32, m.get_reg(Reg::s5()));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_huge_mappings() {
+        let program = assemble("
+                    li      a0, 0x10000000  # munmap(0x10000000, 0xe0000000)
+                    li      a1, 0xe0000000
+                    li      a7, 215
+                    ecall
+
+                    li      a0, 0x10000000  # mmap(0x10000000, 0x80000000, RW, FIXED|ANON)
+                    li      a1, 0x80000000
+                    li      a2, 3
+                    li      a3, 0x30
+                    li      a4, -1
+                    li      a5, 0
+                    li      a7, 222
+                    ecall
+
+                    li      t0, 0x10000000
+                    sw      t0, 0(t0)
+                    lw      a0, 0x7f0(a0)
+                    li      a7, 93
+                    ecall
+        ", 0).unwrap();
+
+        let mut m = Machine::new(SparseMemory::new(UnmappedPolicy::Fault));
+        program.load(&mut m).unwrap();
+        assert_eq!(0, run(&mut Linux::new("."), &mut m));
+        assert_eq!(2, m.memory.mapped_pages());
+    }
+
+    #[test]
+    fn test_init_stack() {
+        let image = Image { entry: 0x100, end: 0x200, phdr: 0x34, phnum: 2, symbols: vec![] };
+        let mut linux = Linux::new(".");
+        linux.random = [7; 16];
+
+        let mut m = Machine::with_memory(0x2000);
+        linux.init_stack(&mut m, 0x2000, &image, &["prog", "-v"], &["HOME=/"]).unwrap();
+
+        let sp = m.get_reg(Reg::sp());
+        assert_eq!(0, sp & 15);
+        assert_eq!(2, m.load32(sp).unwrap());
+
+        let string = |m: &mut Machine, addr: u32| {
+            let mut buf = [0; 6];
+            m.read_bytes(addr, &mut buf).unwrap();
+            buf
+        };
+        let argv1 = m.load32(sp + 8).unwrap();
+        let env0 = m.load32(sp + 16).unwrap();
+        assert_eq!(b"-v\0", &string(&mut m, argv1)[..3]);
+        assert_eq!(b"HOME=/", &string(&mut m, env0));
+        assert_eq!((0, 0), (m.load32(sp + 12).unwrap(), m.load32(sp + 20).unwrap()));
+
+        let mut auxv = vec![];
+        let mut p = sp + 24;
+        while m.load32(p).unwrap() != 0 {
+            auxv.push((m.load32(p).unwrap(), m.load32(p + 4).unwrap()));
+            p += 8;
+        }
+        assert!(auxv.contains(&(6, 4096)));
+        assert!(auxv.contains(&(9, 0x100)));
+        assert!(auxv.contains(&(3, 0x34)));
+
+        let random = auxv.iter().find(|a| a.0 == 25).unwrap().1;
+        let mut bytes = [0; 16];
+        m.read_bytes(random, &mut bytes).unwrap();
+        assert_eq!([7; 16], bytes);
+        assert_eq!(0x2000 - 16, random);
+    }
+}